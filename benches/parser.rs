@@ -0,0 +1,104 @@
+//! Measures how fast `Parser` reads a large pipeline of MSET commands.
+//!
+//! The crate is a binary, so the parser and the frame modules are compiled in
+//! from the source tree. The pipeline is handed out in 16KB reads, as a socket
+//! would.
+//! ```sh
+//! cargo bench --bench parser -- [commands] [pairs] [value size]
+//! ```
+
+#[allow(dead_code)]
+#[path = "../src/bytestring.rs"]
+mod bytestring;
+#[allow(dead_code)]
+#[path = "../src/frame/mod.rs"]
+mod frame;
+#[allow(dead_code)]
+#[path = "../src/parser.rs"]
+mod parser;
+
+use bytes::BytesMut;
+use parser::Parser;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const READ_SIZE: usize = 16 * 1024;
+const ROUNDS: usize = 5;
+
+/// Hands out its data in reads of at most `READ_SIZE` bytes
+struct Chunked {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for Chunked {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let pos = self.pos;
+        let n = (self.data.len() - pos).min(buf.remaining()).min(READ_SIZE);
+        buf.put_slice(&self.data[pos..pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1).filter(|v| v != "--bench");
+    let mut arg = |default: usize| {
+        args.next()
+            .map(|v| v.parse().expect("arguments are numbers"))
+            .unwrap_or(default)
+    };
+    let commands = arg(100_000);
+    let pairs = arg(10);
+    let value_size = arg(64);
+
+    let value = vec![b'x'; value_size];
+    let mut pipeline = Vec::new();
+    for i in 0..commands {
+        pipeline.extend_from_slice(format!("*{}\r\n$4\r\nMSET\r\n", 1 + 2 * pairs).as_bytes());
+        for j in 0..pairs {
+            let key = format!("key:{i}:{j}");
+            pipeline.extend_from_slice(format!("${}\r\n{key}\r\n", key.len()).as_bytes());
+            pipeline.extend_from_slice(format!("${value_size}\r\n").as_bytes());
+            pipeline.extend_from_slice(&value);
+            pipeline.extend_from_slice(b"\r\n");
+        }
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let input = Chunked {
+            data: pipeline.clone(),
+            pos: 0,
+        };
+        let mut parser = Parser::new(Box::new(input), BytesMut::with_capacity(4 * 1024));
+        let start = Instant::now();
+        let frames = runtime.block_on(async {
+            let mut frames = 0;
+            while let Some(frame) = parser.read_frame().await.unwrap() {
+                assert!(frame.is_array());
+                frames += 1;
+            }
+            frames
+        });
+        best = best.min(start.elapsed());
+        assert_eq!(frames, commands);
+    }
+
+    let mb = pipeline.len() as f64 / (1024.0 * 1024.0);
+    println!(
+        "{commands} MSET of {pairs} pairs ({mb:.1} MB) parsed in {best:?}, {:.0} MB/s",
+        mb / best.as_secs_f64()
+    );
+}
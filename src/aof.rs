@@ -0,0 +1,124 @@
+use crate::frame::{Frame, encode::AsBytes};
+use bytes::BytesMut;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// How often the append only file is flushed to the disk
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+pub enum FsyncPolicy {
+    /// fsync after every write, slow but the safest
+    Always,
+    /// fsync once every second, at most one second of data can be lost
+    #[default]
+    EverySec,
+    /// never fsync, leave it to the operating system
+    No,
+}
+
+/// The append only file, every write command that was executed
+/// successfully is appended to it in the same format it was received in.
+pub struct Aof {
+    file: File,
+    path: PathBuf,
+    policy: FsyncPolicy,
+    /// database the last appended command was meant for
+    selected_db: Option<usize>,
+    /// commands received while a rewrite is in progress,
+    /// they are appended to the rewritten file when it is done
+    rewrite_buffer: Option<BytesMut>,
+    fsync_pending: bool,
+}
+
+impl Aof {
+    pub fn open(path: PathBuf, policy: FsyncPolicy) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Aof {
+            file,
+            path,
+            policy,
+            selected_db: None,
+            rewrite_buffer: None,
+            fsync_pending: false,
+        })
+    }
+
+    /// Appends a command executed against the database `db`, a SELECT
+    /// is written first whenever the database differs from the last command
+    pub fn feed(&mut self, db: usize, command: &Frame) -> std::io::Result<()> {
+        let mut b = BytesMut::new();
+        if self.selected_db != Some(db) {
+            let select: Frame = vec!["SELECT".to_string(), db.to_string()].into();
+            select.encode_bytes(&mut b);
+            self.selected_db = Some(db);
+        }
+        command.encode_bytes(&mut b);
+
+        if let Some(buffer) = self.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(&b);
+        }
+        self.file.write_all(&b)?;
+        match self.policy {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::EverySec => self.fsync_pending = true,
+            FsyncPolicy::No => {}
+        }
+        Ok(())
+    }
+
+    /// Flushes the writes since the last call, used by the everysec policy
+    pub fn fsync(&mut self) -> std::io::Result<()> {
+        if self.fsync_pending {
+            self.file.sync_data()?;
+            self.fsync_pending = false;
+        }
+        Ok(())
+    }
+
+    /// Starts buffering the incoming commands so they are not lost
+    /// while the rewritten file is being built from a snapshot
+    pub fn start_rewrite(&mut self) {
+        // the buffer has to be self contained so the next command selects its db
+        self.selected_db = None;
+        self.rewrite_buffer = Some(BytesMut::new());
+    }
+
+    /// Appends the buffered commands to the rewritten file at `temp`
+    /// and atomically replaces the current append only file with it
+    pub fn finish_rewrite(&mut self, temp: &Path) -> std::io::Result<()> {
+        let buffer = self.rewrite_buffer.take().unwrap_or_default();
+        let mut file = OpenOptions::new().append(true).open(temp)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        std::fs::rename(temp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.fsync_pending = false;
+        Ok(())
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buffer = None;
+    }
+}
+
+impl FsyncPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+}
+
+impl Display for FsyncPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::EverySec => write!(f, "everysec"),
+            FsyncPolicy::No => write!(f, "no"),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::frame::Frame;
+use bytes::Bytes;
+use std::borrow::Borrow;
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::Deref;
+use std::str::FromStr;
+
+/// A binary safe string: command arguments, keys and every string held by a value.
+/// Redis strings are arbitrary bytes, the arguments that are numbers or options are
+/// still read as text with `parse` and `to_lowercase`.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteString(Bytes);
+
+impl ByteString {
+    /// Parses the string as a number or any other type read from text, failing
+    /// when it isn't valid UTF-8
+    pub fn parse<F>(&self) -> Result<F, crate::Error>
+    where
+        F: FromStr,
+        F::Err: std::error::Error + Send + Sync + 'static,
+    {
+        Ok(std::str::from_utf8(&self.0)?.parse()?)
+    }
+
+    pub fn eq_ignore_ascii_case(&self, other: &str) -> bool {
+        self.0.eq_ignore_ascii_case(other.as_bytes())
+    }
+
+    pub fn to_lowercase(&self) -> String {
+        String::from_utf8_lossy(&self.0).to_lowercase()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Deref for ByteString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for ByteString {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Display for ByteString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&String::from_utf8_lossy(&self.0), f)
+    }
+}
+
+impl Debug for ByteString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
+
+impl PartialEq<str> for ByteString {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for ByteString {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl From<Bytes> for ByteString {
+    fn from(v: Bytes) -> Self {
+        ByteString(v)
+    }
+}
+
+impl From<Vec<u8>> for ByteString {
+    fn from(v: Vec<u8>) -> Self {
+        ByteString(v.into())
+    }
+}
+
+impl From<&[u8]> for ByteString {
+    fn from(v: &[u8]) -> Self {
+        ByteString(Bytes::copy_from_slice(v))
+    }
+}
+
+impl From<String> for ByteString {
+    fn from(v: String) -> Self {
+        ByteString(v.into())
+    }
+}
+
+impl From<&str> for ByteString {
+    fn from(v: &str) -> Self {
+        ByteString(Bytes::copy_from_slice(v.as_bytes()))
+    }
+}
+
+impl From<ByteString> for Bytes {
+    fn from(v: ByteString) -> Self {
+        v.0
+    }
+}
+
+impl From<ByteString> for Frame {
+    fn from(v: ByteString) -> Self {
+        Frame::BulkString(v.0)
+    }
+}
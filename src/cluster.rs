@@ -0,0 +1,1102 @@
+use crate::Error;
+use crate::frame::Frame;
+use crate::frame::encode::AsBytes;
+use crate::link::Link;
+use crate::parser::Parser;
+use crate::slave;
+use crate::store::Store;
+use bytes::BytesMut;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+/// number of hash slots the keys are spread over
+pub const SLOTS: usize = 16384;
+/// the cluster bus of a node listens on its port plus this
+pub const BUS_PORT_OFFSET: u16 = 10000;
+const PERIOD: Duration = Duration::from_secs(1);
+
+pub type Addr = (String, u16);
+
+/// State of a node running in cluster mode: the nodes it knows about and which
+/// of them serves every hash slot
+pub struct Cluster {
+    pub myself: String,
+    pub current_epoch: u64,
+    /// the last epoch we voted in for the failover of a master
+    pub last_vote_epoch: u64,
+    pub nodes: HashMap<String, Node>,
+    /// the id of the node serving every slot
+    pub slots: Vec<Option<String>>,
+    /// slots we serve that are being moved to another node, and the node
+    pub migrating: HashMap<u16, String>,
+    /// slots served by another node that are being moved to us, and the node
+    pub importing: HashMap<u16, String>,
+    /// a node that does not answer for this long is considered failing
+    pub node_timeout: Duration,
+    /// where the configuration is saved, and what was last written to it
+    config_file: PathBuf,
+    saved: String,
+    /// bus addresses of the nodes we are meeting
+    handshakes: HashSet<Addr>,
+    /// when we can next ask for votes to replace our failed master
+    election: Option<Instant>,
+}
+
+/// A node of the cluster as seen by us
+pub struct Node {
+    pub id: String,
+    pub ip: String,
+    pub port: u16,
+    pub cport: u16,
+    /// the id of its master when the node is a replica
+    pub master: Option<String>,
+    pub config_epoch: u64,
+    /// unix time in milliseconds of the last ping we sent it and of its last pong
+    pub ping_sent: u128,
+    pub pong_received: u128,
+    pub connected: bool,
+    /// it did not answer us for longer than the node timeout
+    pub pfail: bool,
+    /// unix time in milliseconds when a majority of the masters agreed it is failing
+    pub fail: Option<u128>,
+    /// the masters that see it as failing, and when they last said so
+    fail_reports: HashMap<String, u128>,
+}
+
+/// A message of the cluster bus, the header of the sender followed by what
+/// it knows about the other nodes
+///
+/// `<type> <id> <port> <cport> <master> <config-epoch> <current-epoch> <slots>
+/// [<id> <ip> <port> <cport> <flags> ...]`
+struct Message {
+    kind: String,
+    id: String,
+    port: u16,
+    cport: u16,
+    master: Option<String>,
+    config_epoch: u64,
+    current_epoch: u64,
+    slots: Vec<(u16, u16)>,
+    gossip: Vec<Gossip>,
+}
+
+/// What the sender of a message knows about another node
+struct Gossip {
+    id: String,
+    addr: Addr,
+    /// `fail?` or `fail` when the sender sees it failing, `-` otherwise
+    flags: String,
+}
+
+/// A replica asking the masters to vote for it in an election
+struct AuthRequest {
+    epoch: u64,
+    msg: Vec<String>,
+    /// the masters to ask, and the number of votes needed to win
+    voters: Vec<(String, Addr)>,
+    needed: usize,
+}
+
+impl Node {
+    fn new(id: String, ip: String, port: u16, cport: u16) -> Self {
+        Node {
+            id,
+            ip,
+            port,
+            cport,
+            master: None,
+            config_epoch: 0,
+            ping_sent: 0,
+            // a node has the whole timeout to answer us for the first time
+            pong_received: unix_time_ms(),
+            connected: false,
+            pfail: false,
+            fail: None,
+            fail_reports: HashMap::new(),
+        }
+    }
+
+    /// `fail` or `fail?` when we see the node failing
+    pub fn fail_flag(&self) -> Option<&'static str> {
+        if self.fail.is_some() {
+            Some("fail")
+        } else if self.pfail {
+            Some("fail?")
+        } else {
+            None
+        }
+    }
+}
+
+impl Cluster {
+    pub fn new(
+        ip: String,
+        port: u16,
+        cport: u16,
+        node_timeout: Duration,
+        config_file: PathBuf,
+    ) -> Self {
+        let myself = hex::encode(rand::random::<[u8; 20]>());
+        let mut node = Node::new(myself.clone(), ip, port, cport);
+        node.pong_received = 0;
+        node.connected = true;
+        Cluster {
+            nodes: HashMap::from([(myself.clone(), node)]),
+            myself,
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            slots: vec![None; SLOTS],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+            node_timeout,
+            config_file,
+            saved: String::new(),
+            handshakes: HashSet::new(),
+            election: None,
+        }
+    }
+
+    /// Loads the configuration saved in `config_file`, or starts a new cluster
+    /// made of this node alone when there is none yet
+    pub fn open(
+        ip: String,
+        port: u16,
+        cport: u16,
+        node_timeout: Duration,
+        config_file: PathBuf,
+    ) -> Result<Self, Error> {
+        let mut cluster = Cluster::new(ip, port, cport, node_timeout, config_file);
+        let Ok(config) = std::fs::read_to_string(&cluster.config_file) else {
+            cluster.save();
+            return Ok(cluster);
+        };
+        cluster.load(&config).ok_or_else(|| {
+            format!(
+                "Unrecoverable error: corrupted cluster config file \"{}\"",
+                cluster.config_file.display()
+            )
+        })?;
+        cluster.saved = config;
+        Ok(cluster)
+    }
+
+    /// Parses the lines written by `config`, our own address is the one we
+    /// were started with
+    fn load(&mut self, config: &str) -> Option<()> {
+        let mine = self.nodes.remove(&self.myself)?;
+        let mut myself = None;
+        for line in config.lines().filter(|v| !v.trim().is_empty()) {
+            let parts: Vec<_> = line.split_whitespace().collect();
+            if let ["vars", vars @ ..] = parts.as_slice() {
+                for pair in vars.chunks(2) {
+                    match pair {
+                        ["currentEpoch", v] => self.current_epoch = v.parse().ok()?,
+                        ["lastVoteEpoch", v] => self.last_vote_epoch = v.parse().ok()?,
+                        _ => return None,
+                    }
+                }
+                continue;
+            }
+            let [id, addr, flags, master, _, _, epoch, _, slots @ ..] = parts.as_slice() else {
+                return None;
+            };
+            let (ip, ports) = addr.rsplit_once(':')?;
+            let (port, cport) = ports.split_once('@')?;
+            let mut node = Node::new(
+                id.to_string(),
+                ip.to_string(),
+                port.parse().ok()?,
+                cport.parse().ok()?,
+            );
+            if flags.split(',').any(|v| v == "myself") {
+                myself = Some(id.to_string());
+                node.ip = mine.ip.clone();
+                node.port = mine.port;
+                node.cport = mine.cport;
+                node.pong_received = 0;
+                node.connected = true;
+            }
+            node.master = (*master != "-").then(|| master.to_string());
+            node.config_epoch = epoch.parse().ok()?;
+            for slot in slots.iter() {
+                if let Some(state) = slot.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                    if let Some((slot, id)) = state.split_once("->-") {
+                        self.migrating.insert(slot.parse().ok()?, id.to_string());
+                    } else {
+                        let (slot, id) = state.split_once("-<-")?;
+                        self.importing.insert(slot.parse().ok()?, id.to_string());
+                    }
+                    continue;
+                }
+                let (start, end) = slot.split_once('-').unwrap_or((slot, slot));
+                let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+                if start > end || end >= SLOTS {
+                    return None;
+                }
+                for slot in start..=end {
+                    self.slots[slot] = Some(id.to_string());
+                }
+            }
+            self.nodes.insert(id.to_string(), node);
+        }
+        self.myself = myself?;
+        Some(())
+    }
+
+    /// Writes the configuration to `config_file` when it changed since the last time
+    pub fn save(&mut self) {
+        let config = self.config();
+        if config == self.saved {
+            return;
+        }
+        let tmp = self.config_file.with_extension("tmp");
+        let res =
+            std::fs::write(&tmp, &config).and_then(|_| std::fs::rename(&tmp, &self.config_file));
+        match res {
+            Ok(_) => self.saved = config,
+            Err(e) => eprintln!(
+                "Could not save the cluster config file {}: {e}",
+                self.config_file.display()
+            ),
+        }
+    }
+
+    /// The nodes as described by CLUSTER NODES without their ping times, followed
+    /// by the epochs
+    fn config(&self) -> String {
+        let mut res = String::new();
+        for node in self.nodes.values() {
+            res.push_str(&self.describe(node, false));
+            res.push('\n');
+        }
+        res.push_str(&format!(
+            "vars currentEpoch {} lastVoteEpoch {}\n",
+            self.current_epoch, self.last_vote_epoch
+        ));
+        res
+    }
+
+    /// A line of CLUSTER NODES: `<id> <ip:port@cport> <flags> <master> <ping-sent>
+    /// <pong-recv> <config-epoch> <link-state> <slot> <slot> ... <slot>`
+    pub fn describe(&self, node: &Node, times: bool) -> String {
+        let mut flags = vec![];
+        if node.id == self.myself {
+            flags.push("myself");
+        }
+        flags.push(if node.master.is_some() {
+            "slave"
+        } else {
+            "master"
+        });
+        flags.extend(node.fail_flag());
+        let link = if node.connected {
+            "connected"
+        } else {
+            "disconnected"
+        };
+        let (ping, pong) = if times {
+            (node.ping_sent, node.pong_received)
+        } else {
+            (0, 0)
+        };
+        let mut line = format!(
+            "{} {}:{}@{} {} {} {ping} {pong} {} {link}",
+            node.id,
+            node.ip,
+            node.port,
+            node.cport,
+            flags.join(","),
+            node.master.as_deref().unwrap_or("-"),
+            node.config_epoch
+        );
+        for (start, end) in self.slot_ranges(&node.id) {
+            if start == end {
+                line.push_str(&format!(" {start}"));
+            } else {
+                line.push_str(&format!(" {start}-{end}"));
+            }
+        }
+        if node.id == self.myself {
+            for (slot, id) in &self.migrating {
+                line.push_str(&format!(" [{slot}->-{id}]"));
+            }
+            for (slot, id) in &self.importing {
+                line.push_str(&format!(" [{slot}-<-{id}]"));
+            }
+        }
+        line
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    /// The node serving the slot, if any
+    pub fn owner(&self, slot: u16) -> Option<&Node> {
+        self.slots[slot as usize]
+            .as_ref()
+            .and_then(|v| self.nodes.get(v))
+    }
+
+    /// The replicas of the node
+    pub fn replicas(&self, id: &str) -> impl Iterator<Item = &Node> {
+        self.nodes
+            .values()
+            .filter(move |v| v.master.as_deref() == Some(id))
+    }
+
+    /// Takes a new config epoch without asking the other nodes, so that the slots
+    /// we took over win against the claims of their previous owner
+    pub fn bump_epoch(&mut self) {
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        if let Some(myself) = self.nodes.get_mut(&self.myself) {
+            myself.config_epoch = epoch;
+        }
+    }
+
+    /// The slots served by the node as ranges of consecutive slots
+    pub fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = vec![];
+        let owned = (0..SLOTS as u16).filter(|&v| self.slots[v as usize].as_deref() == Some(id));
+        for slot in owned {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    /// The masters serving at least one slot, the ones whose votes count
+    fn voting_masters(&self) -> Vec<&Node> {
+        let mut masters = HashSet::new();
+        masters.extend(self.slots.iter().flatten());
+        self.nodes
+            .values()
+            .filter(|v| v.master.is_none() && masters.contains(&v.id))
+            .collect()
+    }
+
+    /// Our header and the nodes we know about, as sent over the bus
+    fn message(&self, kind: &str) -> Vec<String> {
+        let myself = self.myself();
+        let slots: Vec<_> = self
+            .slot_ranges(&self.myself)
+            .into_iter()
+            .map(|(start, end)| format!("{start}-{end}"))
+            .collect();
+        let mut res = vec![
+            kind.to_string(),
+            myself.id.clone(),
+            myself.port.to_string(),
+            myself.cport.to_string(),
+            myself.master.clone().unwrap_or("-".into()),
+            myself.config_epoch.to_string(),
+            self.current_epoch.to_string(),
+            slots.join(","),
+        ];
+        for node in self.nodes.values().filter(|v| v.id != self.myself) {
+            res.extend([
+                node.id.clone(),
+                node.ip.clone(),
+                node.port.to_string(),
+                node.cport.to_string(),
+                node.fail_flag().unwrap_or("-").into(),
+            ]);
+        }
+        res
+    }
+
+    /// Updates what we know from a message sent by the node at `ip`, returns the
+    /// bus addresses of the nodes it knows about that we have to meet
+    fn process(&mut self, msg: Message, ip: &str) -> Vec<Addr> {
+        if msg.id == self.myself {
+            return vec![];
+        }
+        // only MEET and its reply introduce a node, the others must first be met
+        if matches!(msg.kind.as_str(), "PING" | "AUTH-REQUEST") && !self.nodes.contains_key(&msg.id)
+        {
+            return vec![];
+        }
+        let node = self.nodes.entry(msg.id.clone()).or_insert_with(|| {
+            println!("Node {} ({ip}:{}) added to the cluster", msg.id, msg.port);
+            Node::new(msg.id.clone(), String::new(), 0, 0)
+        });
+        node.ip = ip.to_string();
+        node.port = msg.port;
+        node.cport = msg.cport;
+        node.master = msg.master.clone();
+        node.config_epoch = msg.config_epoch;
+        if msg.kind != "PING" && msg.kind != "MEET" {
+            node.pong_received = unix_time_ms();
+            node.connected = true;
+        }
+        self.current_epoch = self.current_epoch.max(msg.current_epoch);
+
+        // two masters must never claim slots with the same epoch, the one with
+        // the lower id moves on to a new one
+        let myself = self.myself();
+        if msg.master.is_none()
+            && myself.master.is_none()
+            && msg.config_epoch == myself.config_epoch
+            && msg.id > self.myself
+        {
+            self.bump_epoch();
+            println!(
+                "WARNING: configEpoch collision with node {}. configEpoch set to {}",
+                msg.id, self.current_epoch
+            );
+        }
+
+        // a slot changes hands when it is claimed with a newer configuration
+        let mut lost = HashSet::new();
+        let claimed = msg.slots.iter().flat_map(|&(start, end)| start..=end);
+        for slot in claimed.filter(|_| msg.master.is_none()) {
+            let owner = self.slots[slot as usize].clone();
+            let newer = owner
+                .as_ref()
+                .and_then(|v| self.nodes.get(v))
+                .is_none_or(|v| v.config_epoch < msg.config_epoch);
+            if owner.as_ref() != Some(&msg.id) && newer {
+                lost.extend(owner);
+                self.slots[slot as usize] = Some(msg.id.clone());
+                self.migrating.remove(&slot);
+            }
+        }
+        // a master that lost all of its slots, and its replicas, replicate the new owner
+        let myself = self.myself();
+        let master = myself.master.clone().unwrap_or(myself.id.clone());
+        if lost.contains(&master) && self.slot_ranges(&master).is_empty() {
+            if let Some(myself) = self.nodes.get_mut(&self.myself) {
+                myself.master = Some(msg.id.clone());
+            }
+            self.election = None;
+        }
+
+        let mut meet = vec![];
+        let reporter = msg.master.is_none() && !msg.slots.is_empty();
+        for gossip in msg.gossip {
+            if gossip.id == self.myself {
+                continue;
+            }
+            let Some(node) = self.nodes.get_mut(&gossip.id) else {
+                if self.handshakes.insert(gossip.addr.clone()) {
+                    meet.push(gossip.addr);
+                }
+                continue;
+            };
+            let failing = gossip.flags != "-";
+            // the reports of the masters turn our own suspicion into a failure
+            if reporter && failing {
+                node.fail_reports.insert(msg.id.clone(), unix_time_ms());
+            } else {
+                node.fail_reports.remove(&msg.id);
+            }
+            // a failure agreed on by the masters is taken as is, unless we can reach the node
+            if gossip.flags == "fail" && node.fail.is_none() && (node.pfail || !node.connected) {
+                println!("FAIL message received from {} about {}", msg.id, node.id);
+                node.fail = Some(unix_time_ms());
+            }
+        }
+        meet
+    }
+
+    /// Flags the nodes that did not answer for the node timeout, and the ones a
+    /// majority of the masters agree about as failing
+    fn update_failures(&mut self) {
+        let now = unix_time_ms();
+        let timeout = self.node_timeout.as_millis();
+        let quorum = self.voting_masters().len() / 2 + 1;
+        let myself = self.myself();
+        let voter = myself.master.is_none() && !self.slot_ranges(&self.myself).is_empty();
+        let voters: HashSet<String> = self.voting_masters().iter().map(|v| v.id.clone()).collect();
+        let mut owners = HashSet::new();
+        owners.extend(self.slots.iter().flatten().cloned());
+        for node in self.nodes.values_mut().filter(|v| v.id != self.myself) {
+            node.pfail = now.saturating_sub(node.pong_received) > timeout;
+            node.fail_reports
+                .retain(|id, time| voters.contains(id) && now.saturating_sub(*time) <= timeout * 2);
+            match node.fail {
+                None if node.pfail => {
+                    let reports = node.fail_reports.len() + voter as usize;
+                    if reports >= quorum {
+                        println!("Marking node {} as failing (quorum reached).", node.id);
+                        node.fail = Some(now);
+                    }
+                }
+                // a master that still serves slots is failed over, or given the time to be
+                Some(time)
+                    if !node.pfail
+                        && (node.master.is_some()
+                            || !owners.contains(&node.id)
+                            || now.saturating_sub(time) > timeout * 2) =>
+                {
+                    println!("Clear FAIL state for node {}: is reachable again.", node.id);
+                    node.fail = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The master we replicate when it is failed and still serves slots
+    fn failed_master(&self) -> Option<String> {
+        let master = self.myself().master.clone()?;
+        let failed = self.nodes.get(&master)?.fail.is_some();
+        (failed && self.slots.iter().any(|v| v.as_ref() == Some(&master))).then_some(master)
+    }
+
+    /// Starts a new election when our master failed, after a random delay so that
+    /// its replicas don't all ask at once
+    fn start_election(&mut self) -> Option<AuthRequest> {
+        let Some(master) = self.failed_master() else {
+            self.election = None;
+            return None;
+        };
+        let now = Instant::now();
+        let delay = Duration::from_millis(500 + rand::random_range(0..500));
+        let start = *self.election.get_or_insert(now + delay);
+        if now < start {
+            return None;
+        }
+        // the next attempt, in case nobody gets a majority of the votes
+        self.election = Some(now + (self.node_timeout * 2).max(Duration::from_secs(2)));
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        println!("Starting a failover election for epoch {epoch}.");
+        let masters = self.voting_masters();
+        let needed = masters.len() / 2 + 1;
+        let voters = masters
+            .into_iter()
+            .filter(|v| v.id != master)
+            .map(|v| (v.id.clone(), (v.ip.clone(), v.cport)))
+            .collect();
+        Some(AuthRequest {
+            epoch,
+            msg: self.message("AUTH-REQUEST"),
+            voters,
+            needed,
+        })
+    }
+
+    /// Takes over the slots of our failed master with the epoch we were elected in
+    fn win_election(&mut self, epoch: u64) -> bool {
+        let Some(master) = self.failed_master() else {
+            return false;
+        };
+        for owner in self
+            .slots
+            .iter_mut()
+            .filter(|v| v.as_ref() == Some(&master))
+        {
+            *owner = Some(self.myself.clone());
+        }
+        if let Some(myself) = self.nodes.get_mut(&self.myself) {
+            myself.master = None;
+            myself.config_epoch = epoch;
+        }
+        self.current_epoch = self.current_epoch.max(epoch);
+        self.election = None;
+        println!("Failover election won for epoch {epoch}, I'm the new master.");
+        true
+    }
+
+    /// Votes for the replica `id` to replace its failed master in `epoch`, a
+    /// master votes once per epoch
+    fn vote(&mut self, id: &str, epoch: u64) -> bool {
+        let myself = self.myself();
+        if myself.master.is_some() || self.slot_ranges(&self.myself).is_empty() {
+            return false;
+        }
+        if epoch < self.current_epoch || epoch <= self.last_vote_epoch {
+            return false;
+        }
+        let master = self.nodes.get(id).and_then(|v| v.master.as_ref());
+        if master
+            .and_then(|v| self.nodes.get(v))
+            .is_none_or(|v| v.fail.is_none())
+        {
+            return false;
+        }
+        self.last_vote_epoch = epoch;
+        self.save();
+        println!("Failover auth granted to {id} for epoch {epoch}");
+        true
+    }
+}
+
+impl Message {
+    fn parse(frame: Frame) -> Option<Message> {
+        let args: Vec<String> = frame
+            .array()?
+            .into_iter()
+            .map(|v| v.string())
+            .collect::<Option<_>>()?;
+        let mut slots = vec![];
+        for range in args.get(7)?.split(',').filter(|v| !v.is_empty()) {
+            let (start, end) = range.split_once('-')?;
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            if start > end || end as usize >= SLOTS {
+                return None;
+            }
+            slots.push((start, end));
+        }
+        let gossip = args[8..]
+            .chunks(5)
+            .map(|v| match v {
+                [id, ip, _, cport, flags] => Some(Gossip {
+                    id: id.clone(),
+                    addr: (ip.clone(), cport.parse().ok()?),
+                    flags: flags.clone(),
+                }),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        Some(Message {
+            kind: args[0].to_uppercase(),
+            id: args[1].clone(),
+            port: args[2].parse().ok()?,
+            cport: args[3].parse().ok()?,
+            master: (args[4] != "-").then(|| args[4].clone()),
+            config_epoch: args[5].parse().ok()?,
+            current_epoch: args[6].parse().ok()?,
+            slots,
+            gossip,
+        })
+    }
+}
+
+/// The hash slot of a key, only the part between the first `{` and the following
+/// `}` is hashed when it is not empty so that related keys end up in the same slot
+pub fn key_slot(mut key: &[u8]) -> u16 {
+    if let Some(start) = key.iter().position(|&v| v == b'{')
+        && let Some(len) = key[start + 1..].iter().position(|&v| v == b'}')
+        && len > 0
+    {
+        key = &key[start + 1..start + 1 + len];
+    }
+    crc16(key) % SLOTS as u16
+}
+
+/// CRC16 with the XMODEM polynomial, as used by redis cluster
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn unix_time_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis())
+        .unwrap_or_default()
+}
+
+/// Listens on the cluster bus and starts pinging the other nodes
+pub async fn start(store: Arc<Mutex<Store>>) -> Result<(), Error> {
+    let (ip, cport) = match store.lock().await.cluster.as_ref() {
+        Some(cluster) => (cluster.myself().ip.clone(), cluster.myself().cport),
+        None => return Ok(()),
+    };
+    let listener = TcpListener::bind((ip.as_str(), cport)).await?;
+    let bus = store.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(bus.clone(), stream));
+        }
+    });
+    tokio::spawn(cron(store));
+    Ok(())
+}
+
+/// Starts replicating from our master, as set in the configuration we loaded
+pub async fn replicate(store: &Arc<Mutex<Store>>) {
+    let mut guard = store.lock().await;
+    let Some(cluster) = guard.cluster.as_ref() else {
+        return;
+    };
+    let master = cluster.myself().master.as_ref();
+    if let Some(master) = master.and_then(|v| cluster.nodes.get(v)) {
+        let (host, port) = (master.ip.clone(), master.port);
+        guard.demote(host.clone(), port);
+        guard.master_link = Some(slave::start(store.clone(), host, port));
+    }
+}
+
+/// Starts a handshake with the node listening on the bus address
+pub fn meet(store: Arc<Mutex<Store>>, addr: Addr) {
+    tokio::spawn(async move {
+        let reply = match Link::connect(&addr).await {
+            Ok(mut link) => {
+                let Some(msg) = with_cluster(&store, |v| v.message("MEET")).await else {
+                    return;
+                };
+                link.call(&msg).await.ok().and_then(Message::parse)
+            }
+            Err(_) => None,
+        };
+        with_cluster(&store, |cluster| cluster.handshakes.remove(&addr)).await;
+        if let Some(reply) = reply {
+            receive(&store, reply, &addr.0).await;
+        }
+    });
+}
+
+async fn with_cluster<T>(store: &Mutex<Store>, f: impl FnOnce(&mut Cluster) -> T) -> Option<T> {
+    store.lock().await.cluster.as_mut().map(f)
+}
+
+/// Learns from a message sent by the node at `ip` and returns our reply, a vote
+/// when it asks for one. Meets the nodes it knows about, and starts replicating
+/// from our new master when we have to follow another node.
+async fn receive(store: &Arc<Mutex<Store>>, msg: Message, ip: &str) -> Option<Vec<String>> {
+    let mut guard = store.lock().await;
+    let cluster = guard.cluster.as_mut()?;
+    let before = cluster.myself().master.clone();
+    let request = (msg.kind == "AUTH-REQUEST").then(|| (msg.id.clone(), msg.current_epoch));
+    let meet = cluster.process(msg, ip);
+    let reply = match request {
+        Some((id, epoch)) if cluster.vote(&id, epoch) => cluster.message("AUTH-ACK"),
+        _ => cluster.message("PONG"),
+    };
+    let master = cluster.myself().master.clone();
+    let addr = master
+        .filter(|v| before.as_ref() != Some(v))
+        .and_then(|v| cluster.nodes.get(&v))
+        .map(|v| (v.ip.clone(), v.port));
+    cluster.save();
+    if let Some((host, port)) = addr {
+        println!(
+            "Configuration change detected. Reconfiguring myself as a replica of {host}:{port}"
+        );
+        guard.demote(host.clone(), port);
+        guard.master_link = Some(slave::start(store.clone(), host, port));
+    }
+    drop(guard);
+    for addr in meet {
+        self::meet(store.clone(), addr);
+    }
+    Some(reply)
+}
+
+/// Answers the messages sent by another node over the bus
+async fn serve(store: Arc<Mutex<Store>>, stream: TcpStream) {
+    let ip = match stream.peer_addr() {
+        Ok(v) => v.ip().to_string(),
+        Err(_) => return,
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut parser = Parser::new(Box::new(reader), BytesMut::new());
+    while let Ok(Some(frame)) = parser.read_frame().await {
+        let Some(msg) = Message::parse(frame) else {
+            break;
+        };
+        let Some(reply) = receive(&store, msg, &ip).await else {
+            break;
+        };
+        let frame: Frame = reply.into();
+        let mut b = BytesMut::new();
+        frame.encode_bytes(&mut b);
+        if writer.write_all(&b).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends a message to the node over the link we keep with it, the link is
+/// dropped when the node does not answer
+async fn call(
+    links: &mut HashMap<String, Link>,
+    id: &str,
+    addr: &Addr,
+    msg: &[String],
+) -> Option<Message> {
+    if !links.contains_key(id)
+        && let Ok(link) = Link::connect(addr).await
+    {
+        links.insert(id.to_string(), link);
+    }
+    let reply = match links.get_mut(id) {
+        Some(link) => link.call(msg).await.ok().and_then(Message::parse),
+        None => None,
+    };
+    if reply.is_none() {
+        links.remove(id);
+    }
+    reply
+}
+
+/// Pings every other node once per `PERIOD` and learns from their pongs, then
+/// looks for failing nodes and replaces our master when it is one of them
+async fn cron(store: Arc<Mutex<Store>>) {
+    let mut links: HashMap<String, Link> = HashMap::new();
+    let mut interval = tokio::time::interval(PERIOD);
+    loop {
+        interval.tick().await;
+        let Some(nodes) = with_cluster(&store, |cluster| {
+            let others = cluster.nodes.values().filter(|v| v.id != cluster.myself);
+            others
+                .map(|v| (v.id.clone(), (v.ip.clone(), v.cport)))
+                .collect::<Vec<_>>()
+        })
+        .await
+        else {
+            return;
+        };
+        for (id, addr) in nodes {
+            let Some(msg) = with_cluster(&store, |cluster| {
+                if let Some(node) = cluster.nodes.get_mut(&id) {
+                    node.ping_sent = unix_time_ms();
+                }
+                cluster.message("PING")
+            })
+            .await
+            else {
+                return;
+            };
+            match call(&mut links, &id, &addr, &msg).await {
+                Some(reply) => {
+                    receive(&store, reply, &addr.0).await;
+                }
+                None => {
+                    with_cluster(&store, |cluster| {
+                        if let Some(node) = cluster.nodes.get_mut(&id) {
+                            node.connected = false;
+                        }
+                    })
+                    .await;
+                }
+            }
+        }
+        with_cluster(&store, |cluster| {
+            cluster.update_failures();
+            cluster.save();
+        })
+        .await;
+        failover(&store, &mut links).await;
+    }
+}
+
+/// Asks the masters to elect us as the replacement of our failed master, the
+/// first replica to get the votes of a majority of them takes over its slots
+async fn failover(store: &Arc<Mutex<Store>>, links: &mut HashMap<String, Link>) {
+    let Some(AuthRequest {
+        epoch,
+        msg,
+        voters,
+        needed,
+    }) = with_cluster(store, |cluster| cluster.start_election())
+        .await
+        .flatten()
+    else {
+        return;
+    };
+    let mut votes = 0;
+    for (id, addr) in voters {
+        let Some(reply) = call(links, &id, &addr, &msg).await else {
+            continue;
+        };
+        if reply.kind == "AUTH-ACK" {
+            votes += 1;
+        }
+        receive(store, reply, &addr.0).await;
+    }
+    if votes < needed {
+        println!(
+            "Failover election for epoch {epoch} lost with {votes} of the {needed} votes needed."
+        );
+        return;
+    }
+    let mut guard = store.lock().await;
+    let Some(cluster) = guard.cluster.as_mut() else {
+        return;
+    };
+    if cluster.win_election(epoch) {
+        cluster.save();
+        guard.promote();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_check_value() {
+        // the test vector of redis' crc16.c
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn slots_of_keys() {
+        // the slots given by CLUSTER KEYSLOT of redis
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b""), 0);
+        for key in ["a", "somekey", "\u{ff}\u{ff}", "123456789"] {
+            assert!((key_slot(key.as_bytes()) as usize) < SLOTS);
+        }
+        assert_eq!(key_slot(b"123456789"), 0x31c3 % SLOTS as u16);
+    }
+
+    #[test]
+    fn hashtags() {
+        assert_eq!(key_slot(b"{user}a"), key_slot(b"{user}b"));
+        assert_eq!(key_slot(b"{user}a"), key_slot(b"user"));
+        assert_eq!(key_slot(b"x{user}y{other}"), key_slot(b"user"));
+        // an empty or unclosed tag hashes the whole key
+        assert_eq!(key_slot(b"{}x"), crc16(b"{}x") % SLOTS as u16);
+        assert_eq!(key_slot(b"{x"), crc16(b"{x") % SLOTS as u16);
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS as u16);
+        // the tag ends at the first `}` after the first `{`
+        assert_eq!(key_slot(b"foo{{bar}}"), key_slot(b"{bar"));
+    }
+
+    fn single_node(port: u16) -> Cluster {
+        Cluster::new(
+            "127.0.0.1".into(),
+            port,
+            port + BUS_PORT_OFFSET,
+            Duration::from_secs(15),
+            PathBuf::from("nodes.conf"),
+        )
+    }
+
+    fn add_node(cluster: &mut Cluster, id: &str, port: u16, config_epoch: u64) {
+        let mut node = Node::new(id.into(), "127.0.0.1".into(), port, port + BUS_PORT_OFFSET);
+        node.config_epoch = config_epoch;
+        cluster.nodes.insert(id.into(), node);
+    }
+
+    fn sorted_lines(config: &str) -> Vec<&str> {
+        let mut lines: Vec<_> = config.lines().collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn config_round_trip() {
+        let mut cluster = single_node(7000);
+        let myself = cluster.myself.clone();
+        let other = "b".repeat(40);
+        let replica = "c".repeat(40);
+        add_node(&mut cluster, &other, 7001, 2);
+        add_node(&mut cluster, &replica, 7002, 0);
+        cluster.nodes.get_mut(&replica).unwrap().master = Some(other.clone());
+        cluster.nodes.get_mut(&myself).unwrap().config_epoch = 3;
+        cluster.current_epoch = 3;
+        cluster.last_vote_epoch = 2;
+        for slot in (0..=99).chain([200]) {
+            cluster.slots[slot] = Some(myself.clone());
+        }
+        for slot in 100..=199 {
+            cluster.slots[slot] = Some(other.clone());
+        }
+        cluster.migrating.insert(5, other.clone());
+        cluster.importing.insert(150, other.clone());
+
+        let config = cluster.config();
+        let mut loaded = single_node(7000);
+        loaded.load(&config).unwrap();
+        assert_eq!(loaded.myself, myself);
+        assert_eq!(loaded.current_epoch, 3);
+        assert_eq!(loaded.last_vote_epoch, 2);
+        assert_eq!(loaded.slot_ranges(&myself), [(0, 99), (200, 200)]);
+        assert_eq!(loaded.slot_ranges(&other), [(100, 199)]);
+        assert_eq!(loaded.migrating, HashMap::from([(5, other.clone())]));
+        assert_eq!(loaded.importing, HashMap::from([(150, other.clone())]));
+        assert_eq!(loaded.nodes[&other].config_epoch, 2);
+        assert_eq!(loaded.nodes[&replica].master.as_ref(), Some(&other));
+        assert_eq!(sorted_lines(&loaded.config()), sorted_lines(&config));
+    }
+
+    #[test]
+    fn config_keeps_the_address_we_were_started_with() {
+        let config = single_node(7000).config();
+        let mut loaded = single_node(7005);
+        loaded.load(&config).unwrap();
+        assert_eq!(loaded.myself().port, 7005);
+        assert_eq!(loaded.myself().cport, 17005);
+    }
+
+    #[test]
+    fn corrupted_config() {
+        let line = |slots: &str| {
+            format!(
+                "{} 127.0.0.1:7000@17000 myself,master - 0 0 0 connected {slots}",
+                "a".repeat(40)
+            )
+        };
+        assert!(single_node(7000).load(&line("0-100")).is_some());
+        assert!(single_node(7000).load(&line("100-0")).is_none());
+        assert!(single_node(7000).load(&line("16384")).is_none());
+        assert!(single_node(7000).load(&line("[5->-")).is_none());
+        // none of the nodes is us
+        let other = line("").replace("myself,", "");
+        assert!(single_node(7000).load(&other).is_none());
+        assert!(single_node(7000).load("vars currentEpoch x").is_none());
+    }
+
+    fn pong(id: &str, config_epoch: u64, slots: Vec<(u16, u16)>) -> Message {
+        Message {
+            kind: "PONG".into(),
+            id: id.into(),
+            port: 7001,
+            cport: 17001,
+            master: None,
+            config_epoch,
+            current_epoch: config_epoch,
+            slots,
+            gossip: vec![],
+        }
+    }
+
+    #[test]
+    fn newer_config_epoch_wins_slots() {
+        let mut cluster = single_node(7000);
+        let (b, c, d) = ("b".repeat(40), "c".repeat(40), "d".repeat(40));
+        cluster.process(pong(&b, 1, vec![(10, 20)]), "127.0.0.1");
+        assert_eq!(cluster.slot_ranges(&b), [(10, 20)]);
+
+        cluster.process(pong(&c, 2, vec![(15, 25)]), "127.0.0.1");
+        assert_eq!(cluster.slot_ranges(&b), [(10, 14)]);
+        assert_eq!(cluster.slot_ranges(&c), [(15, 25)]);
+        assert_eq!(cluster.current_epoch, 2);
+
+        // an older configuration only gets the slots nobody serves
+        cluster.process(pong(&d, 1, vec![(20, 30)]), "127.0.0.1");
+        assert_eq!(cluster.slot_ranges(&c), [(15, 25)]);
+        assert_eq!(cluster.slot_ranges(&d), [(26, 30)]);
+    }
+
+    #[test]
+    fn master_losing_its_slots_replicates_the_new_owner() {
+        let mut cluster = single_node(7000);
+        let myself = cluster.myself.clone();
+        cluster.bump_epoch();
+        cluster.slots[0] = Some(myself.clone());
+        cluster.migrating.insert(0, "x".into());
+
+        let b = "b".repeat(40);
+        cluster.process(pong(&b, 2, vec![(0, 0)]), "127.0.0.1");
+        assert_eq!(cluster.slot_ranges(&b), [(0, 0)]);
+        assert!(cluster.migrating.is_empty());
+        assert_eq!(cluster.myself().master.as_ref(), Some(&b));
+    }
+}
@@ -19,7 +19,7 @@ impl Debug for Frame {
             Attributes(a) => write!(f, "{a:?}"),
             Set(s) => write!(f, "{s:?}"),
             Push(p) => write!(f, "{p:?}"),
-            RDB(v) => write!(f, "RDB FILE({} bytes)", v.len()),
+            Rdb(v) => write!(f, "RDB FILE({} bytes)", v.len()),
             None(_) => write!(f, "None"),
         }
     }
@@ -1,12 +1,35 @@
 use super::{Error, Frame, TypedNone};
-use bytes::{Buf, Bytes};
-use std::collections::{HashMap, HashSet};
+use bytes::{Buf, Bytes, BytesMut};
 use std::io::Cursor;
 use std::num::{ParseFloatError, TryFromIntError};
 use std::string::FromUtf8Error;
 
+/// Bulk strings at least this long are slices of the buffer being parsed, shorter
+/// ones are copied so that a stored key or value doesn't keep the whole read
+/// buffer alive. Redis makes the same choice for arguments above 32KB.
+const BIG_BULK_SIZE: usize = 32 * 1024;
+
 impl Frame {
-    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+    /// Checks that a complete frame is at the start of `src` and moves past it. Only
+    /// the framing is validated, the contents are left to `parse`.
+    pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+        match get_u8(src)? {
+            b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => {
+                get_line(src)?;
+            }
+            b'$' | b'!' | b'=' => {
+                get_bulk_slice(src)?;
+            }
+            b'*' | b'~' | b'>' => check_aggregate(src, 1)?,
+            b'%' | b'|' => check_aggregate(src, 2)?,
+            v => return Err(unexpected_type(v)),
+        }
+        Ok(())
+    }
+
+    /// Parses a frame from the start of `src`, large bulk strings share the memory
+    /// of the buffer.
+    pub fn parse(src: &mut Cursor<&Bytes>) -> Result<Frame, Error> {
         let err = || -> Error { "protocol error; invalid format".into() };
         match get_u8(src)? {
             b'+' => {
@@ -27,30 +50,21 @@ impl Frame {
                     None => Ok(Frame::None(TypedNone::String)),
                 }
             }
-            b'*' => {
-                if b'-' == peek_u8(src)? {
-                    let line = get_line(src)?;
-                    if line != b"-1" {
-                        return Err("protocol error; invalid frame format".into());
-                    }
-                    Ok(Frame::None(TypedNone::Array))
-                } else {
-                    let len = get_decimal(src)?.try_into()?;
-                    let mut out = Vec::with_capacity(len);
-                    for _ in 0..len {
-                        out.push(Frame::parse(src)?);
-                    }
-                    Ok(Frame::Array(out))
-                }
-            }
+            b'*' => match get_aggregate(src, 1)? {
+                Some(v) => Ok(Frame::Array(v)),
+                None => Ok(Frame::None(TypedNone::Array)),
+            },
             b'_' => {
-                skip(src, 2)?;
+                if !get_line(src)?.is_empty() {
+                    return Err(err());
+                }
                 Ok(Frame::None(TypedNone::Nil))
             }
-            b'#' => {
-                let line = get_line(src)?;
-                Ok(Frame::Boolean(line[0] == b't'))
-            }
+            b'#' => match get_line(src)? {
+                b"t" => Ok(Frame::Boolean(true)),
+                b"f" => Ok(Frame::Boolean(false)),
+                _ => Err(err()),
+            },
             b',' => {
                 let line = get_line(src)?.to_vec();
                 let res: f64 = String::from_utf8(line)?
@@ -59,94 +73,204 @@ impl Frame {
                 Ok(Frame::Double(res))
             }
             b'(' => {
-                let line = get_line(src)?.to_vec();
-                let string = String::from_utf8(line)?;
-                Ok(Frame::BigNumber(string))
+                let line = get_line(src)?;
+                let digits = line.strip_prefix(b"-").unwrap_or(line);
+                if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                    return Err(err());
+                }
+                Ok(Frame::BigNumber(String::from_utf8(line.to_vec())?))
             }
             b'!' => {
                 let bulk_data = get_bulk(src)?.ok_or(err())?;
                 Ok(Frame::BulkError(bulk_data))
             }
             b'=' => {
-                unimplemented!()
+                // The first three bytes are the format of the text, followed by a colon
+                let bulk_data = get_bulk(src)?.ok_or(err())?;
+                if bulk_data.len() < 4 || bulk_data[3] != b':' {
+                    return Err(err());
+                }
+                let format = String::from_utf8(bulk_data[..3].to_vec())?;
+                let text = String::from_utf8(bulk_data[4..].to_vec())?;
+                Ok(Frame::VerbatimString((format, text)))
             }
             b'%' => {
-                let count = get_decimal(src)? as usize;
-                let mut res = HashMap::new();
-                for _ in 0..count {
-                    let key = Frame::parse(src)?.string().ok_or(err())?;
-                    let value = Frame::parse(src)?;
-                    res.insert(key, value);
-                }
-                Ok(Frame::Map(res))
+                let items = get_aggregate(src, 2)?.ok_or(err())?;
+                Ok(Frame::Map(pairs(items)))
             }
             b'|' => {
-                let count = get_decimal(src)? as usize;
-                let mut res = HashMap::new();
-                for _ in 0..count {
-                    let key = Frame::parse(src)?.string().ok_or(err())?;
-                    let value = Frame::parse(src)?;
-                    res.insert(key, value);
-                }
-                Ok(Frame::Attributes(res))
-            }
-            b'~' => {
-                let count = get_decimal(src)? as usize;
-                let mut res = HashSet::new();
-                for _ in 0..count {
-                    let key = Frame::parse(src)?.string().ok_or(err())?;
-                    res.insert(key);
-                }
-                Ok(Frame::Set(res))
-            }
-            b'>' => {
-                if b'-' == peek_u8(src)? {
-                    let line = get_line(src)?;
-                    if line != b"-1" {
-                        return Err("protocol error; invalid frame format".into());
-                    }
-                    Ok(Frame::None(TypedNone::Array))
-                } else {
-                    let len = get_decimal(src)?.try_into()?;
-                    let mut out = Vec::with_capacity(len);
-                    for _ in 0..len {
-                        out.push(Frame::parse(src)?);
-                    }
-                    Ok(Frame::Push(out))
-                }
-            }
-            v => {
-                println!("got {v} as starting byte how can i?");
-                unimplemented!()
+                let items = get_aggregate(src, 2)?.ok_or(err())?;
+                Ok(Frame::Attributes(pairs(items)))
             }
+            b'~' => Ok(Frame::Set(get_aggregate(src, 1)?.ok_or(err())?)),
+            b'>' => match get_aggregate(src, 1)? {
+                Some(v) => Ok(Frame::Push(v)),
+                None => Ok(Frame::None(TypedNone::Array)),
+            },
+            v => Err(unexpected_type(v)),
         }
     }
 }
 
-fn get_bulk(src: &mut Cursor<&[u8]>) -> Result<Option<Bytes>, Error> {
-    if b'-' == peek_u8(src)? {
-        let line = get_line(src)?;
+fn unexpected_type(v: u8) -> Error {
+    format!(
+        "protocol error; unexpected type byte '{}'",
+        v.escape_ascii()
+    )
+    .into()
+}
 
-        if line != b"-1" {
-            return Err("protocol error; invalid frame format".into());
+/// Moves past the elements of an aggregate type, see `get_aggregate`
+fn check_aggregate(src: &mut Cursor<&[u8]>, width: usize) -> Result<(), Error> {
+    let err = || -> Error { "protocol error; invalid frame format".into() };
+    match peek_u8(src)? {
+        b'-' => {
+            get_line(src)?;
+        }
+        b'?' => {
+            get_line(src)?;
+            while peek_u8(src)? != b'.' {
+                Frame::check(src)?;
+            }
+            get_line(src)?;
+        }
+        _ => {
+            let len: usize = get_decimal(src)?.try_into()?;
+            for _ in 0..len.checked_mul(width).ok_or_else(err)? {
+                Frame::check(src)?;
+            }
         }
-        Ok(None)
-    } else {
-        // Read the bulk string
-        let len = get_decimal(src)?.try_into()?;
-        let n = len + 2;
+    }
+    Ok(())
+}
 
-        if src.remaining() < n {
-            return Err(Error::Incomplete);
+/// Reads the elements of an aggregate type, `width` frames per counted element. The
+/// count is either given up front, `-1` for a RESP2 null, or `?` for a streamed
+/// aggregate whose elements are followed by a `.` terminator.
+fn get_aggregate(src: &mut Cursor<&Bytes>, width: usize) -> Result<Option<Vec<Frame>>, Error> {
+    let err = || -> Error { "protocol error; invalid frame format".into() };
+    let out = match peek_u8(src)? {
+        b'-' => {
+            if get_line(src)? != b"-1" {
+                return Err(err());
+            }
+            return Ok(None);
+        }
+        b'?' => {
+            if get_line(src)? != b"?" {
+                return Err(err());
+            }
+            let mut out = Vec::new();
+            while peek_u8(src)? != b'.' {
+                out.push(Frame::parse(src)?);
+            }
+            skip(src, 1)?;
+            if !get_line(src)?.is_empty() || out.len() % width != 0 {
+                return Err(err());
+            }
+            out
+        }
+        _ => {
+            let len: usize = get_decimal(src)?.try_into()?;
+            let len = len.checked_mul(width).ok_or_else(err)?;
+            // Every element takes at least three bytes, don't trust the count any further
+            let mut out = Vec::with_capacity(len.min(src.remaining()));
+            for _ in 0..len {
+                out.push(Frame::parse(src)?);
+            }
+            out
         }
+    };
+    Ok(Some(out))
+}
+
+fn pairs(items: Vec<Frame>) -> Vec<(Frame, Frame)> {
+    let mut items = items.into_iter();
+    let mut res = Vec::with_capacity(items.len() / 2);
+    while let (Some(k), Some(v)) = (items.next(), items.next()) {
+        res.push((k, v));
+    }
+    res
+}
 
-        let data = Bytes::copy_from_slice(&src.chunk()[..len]);
-        skip(src, n)?;
-        Ok(Some(data))
+fn get_bulk(src: &mut Cursor<&Bytes>) -> Result<Option<Bytes>, Error> {
+    let data = match get_bulk_slice(src)? {
+        BulkSlice::Null => return Ok(None),
+        BulkSlice::Streamed(data) => return Ok(Some(data.freeze())),
+        BulkSlice::Data(data) => data,
+    };
+    if data.len() < BIG_BULK_SIZE {
+        return Ok(Some(Bytes::copy_from_slice(data)));
     }
+    Ok(Some(src.get_ref().slice_ref(data)))
 }
 
-fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+enum BulkSlice<'a> {
+    Null,
+    Data(&'a [u8]),
+    /// A streamed string, gathered from its chunks
+    Streamed(BytesMut),
+}
+
+/// Reads a bulk string without copying it out of the buffer, unless it was streamed
+fn get_bulk_slice<'a, T>(src: &mut Cursor<&'a T>) -> Result<BulkSlice<'a>, Error>
+where
+    T: AsRef<[u8]> + ?Sized,
+{
+    let err = || -> Error { "protocol error; invalid frame format".into() };
+    match peek_u8(src)? {
+        b'-' => {
+            if get_line(src)? != b"-1" {
+                return Err(err());
+            }
+            Ok(BulkSlice::Null)
+        }
+        b'?' => {
+            // A streamed string, sent as `;<len>` chunks until an empty one
+            if get_line(src)? != b"?" {
+                return Err(err());
+            }
+            let mut data = BytesMut::new();
+            loop {
+                if get_u8(src)? != b';' {
+                    return Err(err());
+                }
+                let len = get_decimal(src)?.try_into()?;
+                if len == 0 {
+                    return Ok(BulkSlice::Streamed(data));
+                }
+                data.extend_from_slice(get_exact(src, len)?);
+            }
+        }
+        _ => {
+            let len = get_decimal(src)?.try_into()?;
+            Ok(BulkSlice::Data(get_exact(src, len)?))
+        }
+    }
+}
+
+/// Reads `len` bytes of data and the CRLF following them.
+fn get_exact<'a, T>(src: &mut Cursor<&'a T>, len: usize) -> Result<&'a [u8], Error>
+where
+    T: AsRef<[u8]> + ?Sized,
+{
+    let start = src.position() as usize;
+    let n = len + 2;
+
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+
+    let buf: &'a T = src.get_ref();
+    let data = &buf.as_ref()[start..start + n];
+    if &data[len..] != b"\r\n" {
+        return Err("protocol error; invalid frame format".into());
+    }
+    skip(src, n)?;
+    Ok(&data[..len])
+}
+
+fn peek_u8<T: AsRef<[u8]> + ?Sized>(src: &mut Cursor<&T>) -> Result<u8, Error> {
     if !src.has_remaining() {
         return Err(Error::Incomplete);
     }
@@ -154,7 +278,7 @@ fn peek_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
     Ok(src.chunk()[0])
 }
 
-fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
+fn get_u8<T: AsRef<[u8]> + ?Sized>(src: &mut Cursor<&T>) -> Result<u8, Error> {
     if !src.has_remaining() {
         return Err(Error::Incomplete);
     }
@@ -162,7 +286,7 @@ fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, Error> {
     Ok(src.get_u8())
 }
 
-fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
+fn skip<T: AsRef<[u8]> + ?Sized>(src: &mut Cursor<&T>, n: usize) -> Result<(), Error> {
     if src.remaining() < n {
         return Err(Error::Incomplete);
     }
@@ -171,24 +295,35 @@ fn skip(src: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<isize, Error> {
+pub fn get_decimal<T: AsRef<[u8]> + ?Sized>(src: &mut Cursor<&T>) -> Result<isize, Error> {
     let line = get_line(src)?;
     atoi::atoi(line).ok_or_else(|| "protocol error; invalid frame format".into())
 }
 
-pub fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+pub fn get_line<'a, T>(src: &mut Cursor<&'a T>) -> Result<&'a [u8], Error>
+where
+    T: AsRef<[u8]> + ?Sized,
+{
     let start = src.position() as usize;
+    let buf: &'a T = src.get_ref();
+    let buf = buf.as_ref();
     // Scan to the second to last byte
-    let end = src.get_ref().len() - 1;
+    let end = buf.len() - 1;
 
-    for i in start..end {
-        if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
+    let mut i = start;
+    while let Some(n) = buf
+        .get(i..end)
+        .and_then(|v| v.iter().position(|&v| v == b'\r'))
+    {
+        i += n;
+        if buf[i + 1] == b'\n' {
             // We found a line, update the position to be *after* the \n
             src.set_position((i + 2) as u64);
 
             // Return the line
-            return Ok(&src.get_ref()[start..i]);
+            return Ok(&buf[start..i]);
         }
+        i += 1;
     }
     Err(Error::Incomplete)
 }
@@ -229,3 +364,205 @@ impl std::fmt::Display for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::encode::AsBytes;
+    use std::mem::discriminant;
+
+    fn encode(frame: &Frame) -> BytesMut {
+        let mut b = BytesMut::new();
+        frame.encode_bytes(&mut b);
+        b
+    }
+
+    /// Parses a single frame that takes up the whole of `data`
+    fn decode(data: &[u8]) -> Result<Frame, Error> {
+        let data = Bytes::copy_from_slice(data);
+        let mut src = Cursor::new(&data);
+        let frame = Frame::parse(&mut src)?;
+        assert_eq!(src.position() as usize, data.len(), "trailing data");
+        Ok(frame)
+    }
+
+    /// Encodes `frame`, decodes it back and checks that it encodes to the
+    /// same bytes again, and that every prefix of the encoding is incomplete
+    fn round_trip(frame: Frame) -> Frame {
+        let encoded = encode(&frame);
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(discriminant(&decoded), discriminant(&frame));
+        assert_eq!(encode(&decoded), encoded);
+        let mut src = Cursor::new(&encoded[..]);
+        Frame::check(&mut src).unwrap();
+        assert_eq!(src.position() as usize, encoded.len());
+        for i in 0..encoded.len() {
+            let prefix = Bytes::copy_from_slice(&encoded[..i]);
+            let res = Frame::parse(&mut Cursor::new(&prefix));
+            assert!(matches!(res, Err(Error::Incomplete)), "{:?}", &encoded[..i]);
+            let res = Frame::check(&mut Cursor::new(&prefix[..]));
+            assert!(matches!(res, Err(Error::Incomplete)), "{:?}", &encoded[..i]);
+        }
+        decoded
+    }
+
+    fn bulk(s: &'static str) -> Frame {
+        Frame::BulkString(Bytes::from_static(s.as_bytes()))
+    }
+
+    #[test]
+    fn resp2_types() {
+        round_trip(Frame::SimpleString("OK".into()));
+        round_trip(Frame::SimpleString("".into()));
+        round_trip(Frame::SimpleError("ERR unknown command".into()));
+        round_trip(Frame::Integer(0));
+        round_trip(Frame::Integer(isize::MIN));
+        round_trip(Frame::Integer(isize::MAX));
+        round_trip(bulk(""));
+        round_trip(bulk("hello"));
+        round_trip(Frame::BulkString(Bytes::from_static(b"\r\n\x00\xff$3\r\n")));
+        round_trip(Frame::Array(vec![]));
+        round_trip(Frame::Array(vec![
+            bulk("SET"),
+            Frame::Integer(-1),
+            Frame::Array(vec![Frame::SimpleString("nested".into())]),
+        ]));
+        round_trip(Frame::None(TypedNone::String));
+        round_trip(Frame::None(TypedNone::Array));
+    }
+
+    #[test]
+    fn resp3_types() {
+        round_trip(Frame::None(TypedNone::Nil));
+        round_trip(Frame::Boolean(true));
+        round_trip(Frame::Boolean(false));
+        round_trip(Frame::BigNumber(
+            "3492890328409238509324850943850943825024385".into(),
+        ));
+        round_trip(Frame::BigNumber("-1".into()));
+        round_trip(Frame::BulkError(Bytes::from_static(
+            b"SYNTAX invalid syntax",
+        )));
+        round_trip(Frame::Map(vec![
+            (Frame::SimpleString("first".into()), Frame::Integer(1)),
+            (bulk("second"), Frame::Map(vec![])),
+        ]));
+        round_trip(Frame::Attributes(vec![(
+            Frame::SimpleString("ttl".into()),
+            Frame::Integer(3600),
+        )]));
+        round_trip(Frame::Set(vec![bulk("a"), Frame::Boolean(false)]));
+        round_trip(Frame::Push(vec![bulk("message"), bulk("ch"), bulk("hi")]));
+    }
+
+    #[test]
+    fn verbatim_strings() {
+        let frame = Frame::VerbatimString(("txt".into(), "Some string".into()));
+        assert_eq!(&encode(&frame)[..], b"=15\r\ntxt:Some string\r\n");
+        let Frame::VerbatimString((format, text)) = round_trip(frame) else {
+            unreachable!()
+        };
+        assert_eq!((format.as_str(), text.as_str()), ("txt", "Some string"));
+        round_trip(Frame::VerbatimString(("mkd".into(), "".into())));
+        round_trip(Frame::VerbatimString(("txt".into(), "a\r\nb".into())));
+
+        assert!(decode(b"=3\r\ntxt\r\n").is_err());
+        assert!(decode(b"=4\r\ntxt;\r\n").is_err());
+    }
+
+    #[test]
+    fn doubles() {
+        for d in [0.0, -0.0, 1.5, -3.25, 1e300, f64::MIN_POSITIVE, f64::MAX] {
+            let Frame::Double(v) = round_trip(Frame::Double(d)) else {
+                unreachable!()
+            };
+            assert_eq!(v.to_bits(), d.to_bits());
+        }
+        for (d, encoded) in [
+            (f64::INFINITY, &b",inf\r\n"[..]),
+            (f64::NEG_INFINITY, b",-inf\r\n"),
+            (f64::NAN, b",nan\r\n"),
+        ] {
+            assert_eq!(&encode(&Frame::Double(d))[..], encoded);
+            let Frame::Double(v) = round_trip(Frame::Double(d)) else {
+                unreachable!()
+            };
+            assert!(v == d || v.is_nan() && d.is_nan());
+        }
+        assert!(decode(b",1.5x\r\n").is_err());
+    }
+
+    #[test]
+    fn streamed_strings() {
+        let frame = decode(b"$?\r\n;4\r\nHell\r\n;5\r\no wor\r\n;2\r\nld\r\n;0\r\n").unwrap();
+        assert_eq!(&encode(&frame)[..], b"$11\r\nHello world\r\n");
+        let frame = decode(b"$?\r\n;0\r\n").unwrap();
+        assert_eq!(&encode(&frame)[..], b"$0\r\n\r\n");
+
+        assert!(matches!(
+            decode(b"$?\r\n;4\r\nHell\r\n"),
+            Err(Error::Incomplete)
+        ));
+        assert!(decode(b"$?\r\n:4\r\nHell\r\n;0\r\n").is_err());
+        assert!(decode(b"$??\r\n;0\r\n").is_err());
+    }
+
+    #[test]
+    fn streamed_aggregates() {
+        let cases: [(&[u8], &[u8]); 6] = [
+            (b"*?\r\n:1\r\n:2\r\n.\r\n", b"*2\r\n:1\r\n:2\r\n"),
+            (b"*?\r\n.\r\n", b"*0\r\n"),
+            (
+                b"~?\r\n+a\r\n$?\r\n;1\r\nb\r\n;0\r\n.\r\n",
+                b"~2\r\n+a\r\n$1\r\nb\r\n",
+            ),
+            (
+                b"%?\r\n+a\r\n:1\r\n+b\r\n*?\r\n.\r\n.\r\n",
+                b"%2\r\n+a\r\n:1\r\n+b\r\n*0\r\n",
+            ),
+            (b"|?\r\n+ttl\r\n:10\r\n.\r\n", b"|1\r\n+ttl\r\n:10\r\n"),
+            (b">?\r\n+message\r\n.\r\n", b">1\r\n+message\r\n"),
+        ];
+        for (streamed, encoded) in cases {
+            let frame = decode(streamed).unwrap();
+            assert_eq!(&encode(&frame)[..], encoded);
+            round_trip(frame);
+        }
+
+        // a map needs an even number of frames before the terminator
+        assert!(decode(b"%?\r\n+a\r\n.\r\n").is_err());
+        assert!(decode(b"*?\r\n:1\r\n.x\r\n").is_err());
+        assert!(matches!(decode(b"*?\r\n:1\r\n"), Err(Error::Incomplete)));
+    }
+
+    #[test]
+    fn large_bulk_strings_are_shared() {
+        let big = "x".repeat(BIG_BULK_SIZE);
+        let data = Bytes::from(format!("*2\r\n$1\r\nk\r\n${}\r\n{big}\r\n", big.len()));
+        let Frame::Array(items) = Frame::parse(&mut Cursor::new(&data)).unwrap() else {
+            unreachable!()
+        };
+        let inside = |v: &Frame| match v {
+            Frame::BulkString(v) => data.as_ptr_range().contains(&v.as_ptr()),
+            _ => unreachable!(),
+        };
+        // a short string is copied so that it doesn't keep the buffer alive
+        assert!(!inside(&items[0]));
+        assert!(inside(&items[1]));
+        assert!(encode(&items[1])[..] == data[11..]);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        assert!(decode(b"$-2\r\n").is_err());
+        assert!(decode(b"$3\r\nabcd\r\n").is_err());
+        assert!(decode(b"#x\r\n").is_err());
+        assert!(decode(b"(12a\r\n").is_err());
+        assert!(decode(b"_x\r\n").is_err());
+        assert!(decode(b"%-1\r\n").is_err());
+        assert!(decode(b"@\r\n").is_err());
+        // a huge count doesn't allocate before the elements arrive
+        let res = decode(b"*9223372036854775807\r\n:1\r\n");
+        assert!(matches!(res, Err(Error::Incomplete)));
+    }
+}
@@ -1,6 +1,5 @@
 use crate::frame::{Frame, TypedNone};
 use bytes::{BufMut, Bytes, BytesMut};
-use std::collections::{HashMap, HashSet};
 
 pub trait AsBytes {
     fn encode_bytes(&self, b: &mut BytesMut);
@@ -24,7 +23,7 @@ impl AsBytes for Frame {
             Attributes(a) => Frame::encode_attributes(a, &mut b),
             Set(s) => Frame::encode_set(s, &mut b),
             Push(p) => Frame::encode_push(p, &mut b),
-            RDB(v) => Frame::encode_rdb(v, &mut b),
+            Rdb(v) => Frame::encode_rdb(v, &mut b),
             None(n) => Frame::encode_none(n, &mut b),
         }
     }
@@ -78,7 +77,7 @@ impl Frame {
     }
     fn encode_double(d: &f64, b: &mut BytesMut) {
         b.put_u8(b',');
-        b.put_slice(d.to_string().as_bytes());
+        b.put_slice(format_double(*d).as_bytes());
         b.put_slice(b"\r\n");
     }
     fn encode_big_number(n: &String, b: &mut BytesMut) {
@@ -87,34 +86,34 @@ impl Frame {
         b.put_slice(b"\r\n");
     }
     fn encode_verbatim_string(s: &(String, String), b: &mut BytesMut) {
-        b.put_slice(format!("={}\r\n{}:{}\r\n", s.1.len(), s.0, s.1).as_bytes())
+        b.put_slice(format!("={}\r\n{}:{}\r\n", s.1.len() + 4, s.0, s.1).as_bytes())
     }
-    fn encode_map(m: &HashMap<String, Frame>, b: &mut BytesMut) {
+    fn encode_map(m: &Vec<(Frame, Frame)>, b: &mut BytesMut) {
         b.put_u8(b'%');
         b.put_slice(m.len().to_string().as_bytes());
         b.put_slice(b"\r\n");
         for (k, v) in m {
-            Frame::encode_bulk_string(&Bytes::from(k.clone()), b);
+            k.encode_bytes(b);
             v.encode_bytes(b);
         }
     }
 
-    fn encode_attributes(m: &HashMap<String, Frame>, b: &mut BytesMut) {
+    fn encode_attributes(m: &Vec<(Frame, Frame)>, b: &mut BytesMut) {
         b.put_u8(b'|');
         b.put_slice(m.len().to_string().as_bytes());
         b.put_slice(b"\r\n");
         for (k, v) in m {
-            Frame::encode_bulk_string(&Bytes::from(k.clone()), b);
+            k.encode_bytes(b);
             v.encode_bytes(b);
         }
     }
 
-    fn encode_set(s: &HashSet<String>, b: &mut BytesMut) {
-        b.put_u8(b'%');
+    fn encode_set(s: &Vec<Frame>, b: &mut BytesMut) {
+        b.put_u8(b'~');
         b.put_slice(s.len().to_string().as_bytes());
         b.put_slice(b"\r\n");
         for v in s {
-            Frame::encode_bulk_string(&Bytes::from(v.clone()), b);
+            v.encode_bytes(b);
         }
     }
 
@@ -142,3 +141,15 @@ impl Frame {
         }
     }
 }
+
+/// Formats a double the way RESP3 spells it, `inf`, `-inf` and `nan` for the values
+/// that aren't numbers.
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".into()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.into()
+    } else {
+        d.to_string()
+    }
+}
@@ -1,4 +1,6 @@
-use super::Frame;
+use super::encode::format_double;
+use super::{Frame, TypedNone};
+use crate::bytestring::ByteString;
 use std::collections::{HashMap, HashSet};
 
 macro_rules! impl_getter {
@@ -28,10 +30,10 @@ impl Frame {
     impl_getter!(double, Double, f64);
     impl_getter!(boolean, Boolean, bool);
     impl_getter!(array, [Array, Push], Vec<Frame>);
-    impl_getter!(map, [Map, Attributes], HashMap<String, Frame>);
+    impl_getter!(map, [Map, Attributes], Vec<(Frame, Frame)>);
     impl_getter!(bulk_string, BulkString, bytes::Bytes);
     impl_getter!(bulk_error, BulkError, bytes::Bytes);
-    impl_getter!(set, Set, HashSet<String>);
+    impl_getter!(set, Set, Vec<Frame>);
 
     pub fn is_array(&self) -> bool {
         match self {
@@ -44,6 +46,7 @@ impl Frame {
         match self {
             Frame::SimpleString(s) => Some(s),
             Frame::BulkString(s) => String::from_utf8(s.to_vec()).ok(),
+            Frame::VerbatimString((_, s)) => Some(s),
             _ => None,
         }
     }
@@ -55,6 +58,42 @@ impl Frame {
             _ => None,
         }
     }
+
+    /// The reply as a client speaking `protocol` expects it. The RESP3 types have
+    /// no RESP2 encoding, they are sent as the arrays, bulk strings and integers
+    /// redis uses for them, while RESP3 has a single null for every type.
+    pub fn into_protocol(self, protocol: u8) -> Frame {
+        let convert = |v: Vec<Frame>| v.into_iter().map(|v| v.into_protocol(protocol)).collect();
+        let convert_pairs = |v: Vec<(Frame, Frame)>| {
+            v.into_iter()
+                .map(|(k, v)| (k.into_protocol(protocol), v.into_protocol(protocol)))
+                .collect()
+        };
+        match self {
+            Frame::Array(v) => Frame::Array(convert(v)),
+            Frame::Push(v) if protocol == 2 => Frame::Array(convert(v)),
+            Frame::Push(v) => Frame::Push(convert(v)),
+            Frame::None(_) if protocol == 3 => Frame::None(TypedNone::Nil),
+            Frame::Map(v) | Frame::Attributes(v) if protocol == 2 => Frame::Array(
+                v.into_iter()
+                    .flat_map(|(k, v)| [k.into_protocol(protocol), v.into_protocol(protocol)])
+                    .collect(),
+            ),
+            Frame::Map(v) => Frame::Map(convert_pairs(v)),
+            Frame::Attributes(v) => Frame::Attributes(convert_pairs(v)),
+            Frame::Set(v) if protocol == 2 => Frame::Array(convert(v)),
+            Frame::Set(v) => Frame::Set(convert(v)),
+            Frame::Double(v) if protocol == 2 => format_double(v).into(),
+            Frame::Boolean(v) if protocol == 2 => (v as usize).into(),
+            Frame::BigNumber(v) if protocol == 2 => v.into(),
+            Frame::VerbatimString((_, v)) if protocol == 2 => v.into(),
+            Frame::BulkError(v) if protocol == 2 => {
+                Frame::SimpleError(String::from_utf8_lossy(&v).into())
+            }
+            Frame::None(TypedNone::Nil) if protocol == 2 => Frame::None(TypedNone::String),
+            v => v,
+        }
+    }
 }
 
 macro_rules! impl_into_frame {
@@ -84,5 +123,7 @@ impl_into_frame!(String => BulkString, |s: String| bytes::Bytes::from(s));
 impl_into_frame!(Vec<Frame> => Array);
 impl_into_frame!(Vec<String> => Array, |v: Vec<String>| v.into_iter().map(|x| x.into()).collect());
 impl_into_frame!(Vec<&str> => Array, |v: Vec<&str>| v.into_iter().map(|x| x.to_string().into()).collect());
-impl_into_frame!(HashSet<String> => Set);
-impl_into_frame!(HashMap<String, Frame> => Map);
+impl_into_frame!(Vec<ByteString> => Array, |v: Vec<ByteString>| v.into_iter().map(|x| x.into()).collect());
+impl_into_frame!(HashSet<String> => Set, |v: HashSet<String>| v.into_iter().map(|x| x.into()).collect());
+impl_into_frame!(HashMap<String, Frame> => Map, |v: HashMap<String, Frame>| v.into_iter().map(|(k, v)| (k.into(), v)).collect());
+impl_into_frame!(Vec<(String, Frame)> => Map, |v: Vec<(String, Frame)>| v.into_iter().map(|(k, v)| (k.into(), v)).collect());
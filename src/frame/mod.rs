@@ -4,26 +4,25 @@ pub(crate) mod encode;
 mod frame;
 
 use bytes::Bytes;
-use std::collections::{HashMap, HashSet};
 
 #[derive(Clone)]
 pub enum Frame {
-    SimpleString(String),               // +
-    BulkString(Bytes),                  // $
-    SimpleError(String),                // -
-    BulkError(Bytes),                   // !
-    Integer(isize),                     // :
-    Array(Vec<Frame>),                  // *
-    Boolean(bool),                      // #
-    Double(f64),                        // ,
-    BigNumber(String),                  // (
-    VerbatimString((String, String)),   // =
-    Map(HashMap<String, Frame>),        // %
-    Attributes(HashMap<String, Frame>), // |
-    Set(HashSet<String>),               // ~
-    Push(Vec<Frame>),                   // >
-    RDB(Bytes),                         // $
-    None(TypedNone),                    // _
+    SimpleString(String),             // +
+    BulkString(Bytes),                // $
+    SimpleError(String),              // -
+    BulkError(Bytes),                 // !
+    Integer(isize),                   // :
+    Array(Vec<Frame>),                // *
+    Boolean(bool),                    // #
+    Double(f64),                      // ,
+    BigNumber(String),                // (
+    VerbatimString((String, String)), // =
+    Map(Vec<(Frame, Frame)>),         // %
+    Attributes(Vec<(Frame, Frame)>),  // |
+    Set(Vec<Frame>),                  // ~
+    Push(Vec<Frame>),                 // >
+    Rdb(Bytes),                       // $
+    None(TypedNone),                  // _
 }
 
 #[derive(Copy, Clone)]
@@ -0,0 +1,70 @@
+use crate::Error;
+use crate::frame::Frame;
+use crate::frame::encode::AsBytes;
+use crate::parser::Parser;
+use bytes::BytesMut;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::time::timeout;
+
+/// how long connecting and waiting for a reply may take
+pub const LINK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A connection to another server, used by sentinels to talk to the instances
+/// and to each other and by cluster nodes to talk over the cluster bus
+pub struct Link {
+    writer: OwnedWriteHalf,
+    parser: Parser,
+    /// our own address on the connection, it is the one announced to the others
+    pub local_ip: String,
+}
+
+impl Link {
+    pub async fn connect(addr: &(String, u16)) -> Result<Link, Error> {
+        let tcp = timeout(LINK_TIMEOUT, TcpStream::connect((addr.0.as_str(), addr.1))).await??;
+        let local_ip = tcp.local_addr()?.ip().to_string();
+        let (reader, writer) = tcp.into_split();
+        let parser = Parser::new(Box::new(reader), BytesMut::new());
+        Ok(Link {
+            writer,
+            parser,
+            local_ip,
+        })
+    }
+
+    pub async fn send<T: AsRef<str>>(&mut self, args: &[T]) -> Result<(), Error> {
+        let args: Vec<&str> = args.iter().map(|v| v.as_ref()).collect();
+        self.send_frame(args.into()).await
+    }
+
+    pub async fn send_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        let mut b = BytesMut::new();
+        frame.encode_bytes(&mut b);
+        self.writer.write_all(&b).await?;
+        Ok(())
+    }
+
+    pub async fn read(&mut self) -> Result<Frame, Error> {
+        self.parser
+            .read_frame()
+            .await?
+            .ok_or("connection closed".into())
+    }
+
+    pub async fn call<T: AsRef<str>>(&mut self, args: &[T]) -> Result<Frame, Error> {
+        let args: Vec<&str> = args.iter().map(|v| v.as_ref()).collect();
+        self.call_frame(args.into()).await
+    }
+
+    /// Sends a command and waits for its reply, error replies become errors
+    pub async fn call_frame(&mut self, frame: Frame) -> Result<Frame, Error> {
+        self.send_frame(frame).await?;
+        let reply = timeout(LINK_TIMEOUT, self.read()).await??;
+        if let Frame::SimpleError(e) = reply {
+            return Err(e.into());
+        }
+        Ok(reply)
+    }
+}
@@ -1,20 +1,28 @@
+use crate::aof::{Aof, FsyncPolicy};
+use crate::cluster::Cluster;
+use crate::rdb::RDB;
 use crate::rdb::decode::RDBParser;
+use crate::rdb::encode::RDBEncoder;
+use crate::sentinel::Sentinel;
+use crate::server::{persistence, string};
 use crate::server::server::Server;
-use crate::store::{Info, Role, Store};
+use crate::store::{Database, Info, Store};
 use bytes::BytesMut;
-use rand::distr::Alphanumeric;
-use rand::Rng;
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
 use std::path::PathBuf;
-use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 
+mod aof;
+mod bytestring;
+mod cluster;
 mod frame;
+mod link;
 mod parser;
 mod rdb;
+mod sentinel;
 mod server;
 mod slave;
 mod store;
@@ -33,16 +41,86 @@ async fn main() -> Result<(), Error> {
     let args: Vec<_> = std::env::args().collect();
 
     let mut info = Info::default();
+    // a sentinel holds no dataset of its own
+    let sentinel_mode = args.iter().any(|v| v == "--sentinel");
+    let default_port = if sentinel_mode { 26379 } else { 6379 };
     let port = get_arg_value(&args, "--port")
         .and_then(|v| v.parse().ok())
-        .unwrap_or(6379);
+        .unwrap_or(default_port);
     info.listening_port = port;
     info.dir = get_arg_value(&args, "--dir").unwrap_or_default();
     info.db_filename = get_arg_value(&args, "--dbfilename").unwrap_or_default();
+    if let Some(save) = get_arg_value(&args, "--save") {
+        info.save_params = Info::parse_save_params(&save).ok_or("invalid save parameters")?;
+    }
+    info.last_save = persistence::unix_time();
+    info.databases = match get_arg_value(&args, "--databases") {
+        // every client starts in database 0
+        Some(databases) => databases
+            .parse()
+            .ok()
+            .filter(|&v| v >= 1)
+            .ok_or("invalid number of databases, it must be at least 1")?,
+        None => 16,
+    };
+    info.appendonly = get_arg_value(&args, "--appendonly").is_some_and(|v| v == "yes");
+    info.append_filename = get_arg_value(&args, "--appendfilename").unwrap_or_default();
+    if let Some(fsync) = get_arg_value(&args, "--appendfsync") {
+        info.appendfsync = FsyncPolicy::parse(&fsync).ok_or("invalid appendfsync policy")?;
+    }
 
-    let mut file_path = PathBuf::from(&info.dir);
-    file_path.push(&info.db_filename);
-    let rdb_file = RDBParser::parse_file(file_path).unwrap_or_default();
+    info.replica_read_only = get_arg_value(&args, "--replica-read-only")
+        .or_else(|| get_arg_value(&args, "--slave-read-only"))
+        .is_none_or(|v| v == "yes");
+    info.master_replid2 = "0".repeat(40);
+    info.second_repl_offset = -1;
+    info.min_replicas_to_write = get_arg_value(&args, "--min-replicas-to-write")
+        .or_else(|| get_arg_value(&args, "--min-slaves-to-write"))
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    info.min_replicas_max_lag = get_arg_value(&args, "--min-replicas-max-lag")
+        .or_else(|| get_arg_value(&args, "--min-slaves-max-lag"))
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    info.repl_backlog_size = get_arg_value(&args, "--repl-backlog-size")
+        .and_then(|v| Info::parse_memory(&v))
+        .unwrap_or(1024 * 1024);
+
+    if sentinel_mode {
+        info.appendonly = false;
+    }
+
+    // the append only file is always more up to date than the snapshot
+    let aof_path = info.aof_path();
+    let appendonly = info.appendonly;
+    let load_aof = appendonly && aof_path.exists();
+    let rdb_path = info.rdb_path();
+    let rdb_file = if !sentinel_mode && !load_aof && rdb_path.exists() {
+        match RDBParser::parse_file(rdb_path.clone()) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!(
+                    "Fatal error loading the DB {}: {e}. Exiting.",
+                    rdb_path.display()
+                );
+                std::process::exit(1);
+            }
+        }
+    } else {
+        RDB::default()
+    };
+
+    let mut databases: Vec<_> = (0..info.databases).map(|_| Database::default()).collect();
+    for (idx, db) in rdb_file.databases {
+        if idx >= databases.len() {
+            eprintln!(
+                "FATAL: Data file was created with a Redis server configured to handle more than {} databases. Exiting.",
+                databases.len()
+            );
+            std::process::exit(1);
+        }
+        databases[idx] = db;
+    }
 
     let default_users = HashMap::from([(
         "default".into(),
@@ -53,55 +131,105 @@ async fn main() -> Result<(), Error> {
     )]);
 
     let redis_store = Arc::new(Mutex::new(Store {
-        kv: rdb_file.database,
-        expiry_queue: rdb_file
-            .expiry_time
-            .clone()
-            .into_iter()
-            .map(|(k, v)| (v, k))
-            .collect(),
-        expiry_time: rdb_file.expiry_time,
+        databases,
         info,
         broadcast: None,
-        get_ack_channel: None,
         channels: HashMap::new(),
         slave_offsets: HashMap::new(),
-        slave_asked_offsets: HashMap::new(),
+        slave_acks: Arc::new(Notify::new()),
+        failover: None,
+        failover_done: Arc::new(Notify::new()),
+        replication_db: None,
+        backlog: None,
+        master_link: None,
+        aof: None,
+        sentinel: None,
+        cluster: None,
         users: default_users,
     }));
 
-    if let Some(idx) = args.iter().position(|v| v == "--replicaof") {
-        let mut addr = args[idx + 1].split(" ");
-        let ip_str = addr.next().unwrap();
-        let ip = if ip_str == "localhost" {
-            Ipv4Addr::from_str("127.0.0.1").unwrap()
-        } else {
-            Ipv4Addr::from_str(ip_str).unwrap()
-        };
-        let port: u16 = addr.next().unwrap().parse().unwrap();
-        redis_store.lock().await.info.master_id = "?".into();
-        redis_store.lock().await.info.role = Role::Slave;
-        let store = redis_store.clone();
-        tokio::spawn(async move {
-            slave::handle(ip, port, store).await.unwrap();
-        });
-    } else {
-        let (tx, _rx) = tokio::sync::broadcast::channel(64);
-        drop(_rx);
-        let _ = redis_store.lock().await.broadcast.insert(tx);
-        let (tx, _rx) = tokio::sync::broadcast::channel(64);
-        drop(_rx);
-        let _ = redis_store.lock().await.get_ack_channel.insert(tx);
-        let master_id: String = rand::rng()
-            .sample_iter(&Alphanumeric)
-            .take(40)
-            .map(char::from)
-            .collect();
+    if load_aof && let Err(e) = persistence::load_aof(redis_store.clone(), aof_path.clone()).await {
+        eprintln!(
+            "Fatal error loading the append only file {}: {e}. Exiting.",
+            aof_path.display()
+        );
+        std::process::exit(1);
+    }
+    if appendonly {
+        let mut store = redis_store.lock().await;
+        if !load_aof {
+            // start the new file with the dataset that was loaded from the snapshot
+            RDBEncoder::save_file(aof_path.clone(), &RDBEncoder::encode(&store))?;
+        }
+        let policy = store.info.appendfsync;
+        store.aof = Some(Aof::open(aof_path, policy)?);
+        tokio::spawn(persistence::aof_cron(redis_store.clone()));
+    }
+
+    if let Some(master) = get_arg_value(&args, "--replicaof") {
+        let mut addr = master.split_whitespace();
+        let host = addr.next().ok_or("invalid replicaof address")?.to_string();
+        let port: u16 = addr.next().ok_or("invalid replicaof address")?.parse()?;
         let mut store = redis_store.lock().await;
-        store.info.role = Role::Master;
-        store.info.master_id = master_id.to_lowercase();
+        store.info.master_id = "?".into();
+        store.demote(host.clone(), port);
+        store.master_link = Some(slave::start(redis_store.clone(), host, port));
+    } else {
+        redis_store.lock().await.promote();
     };
 
+    if sentinel_mode {
+        let down_after = get_arg_value(&args, "--sentinel-down-after-milliseconds")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30000);
+        let failover_timeout = get_arg_value(&args, "--sentinel-failover-timeout")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(180000);
+        let mut sentinel = Sentinel::new();
+        // every `--sentinel-monitor "<name> <host> <port> <quorum>"` is a master to watch
+        let masters = args
+            .windows(2)
+            .filter(|v| v[0] == "--sentinel-monitor")
+            .map(|v| &v[1]);
+        for master in masters {
+            let down_after = Duration::from_millis(down_after);
+            let failover_timeout = Duration::from_millis(failover_timeout);
+            sentinel
+                .monitor(master, down_after, failover_timeout)
+                .await?;
+        }
+        println!("Sentinel ID is {}", sentinel.myid);
+        redis_store.lock().await.sentinel = Some(sentinel);
+        sentinel::start(redis_store.clone()).await;
+    }
+
+    if get_arg_value(&args, "--cluster-enabled").is_some_and(|v| v == "yes") {
+        // the bus listens on the same address as the clients
+        let cport = port
+            .checked_add(cluster::BUS_PORT_OFFSET)
+            .ok_or("port too high for the cluster bus")?;
+        let node_timeout = get_arg_value(&args, "--cluster-node-timeout")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(15000);
+        let mut config_file = PathBuf::from(get_arg_value(&args, "--dir").unwrap_or(".".into()));
+        config_file
+            .push(get_arg_value(&args, "--cluster-config-file").unwrap_or("nodes.conf".into()));
+        let cluster = Cluster::open(
+            "127.0.0.1".into(),
+            port,
+            cport,
+            Duration::from_millis(node_timeout),
+            config_file,
+        )?;
+        println!("Cluster node ID is {}", cluster.myself);
+        redis_store.lock().await.cluster = Some(cluster);
+        cluster::replicate(&redis_store).await;
+        cluster::start(redis_store.clone()).await?;
+    }
+
+    tokio::spawn(persistence::snapshot_cron(redis_store.clone()));
+    tokio::spawn(string::expire_cron(redis_store.clone()));
+
     let listener = TcpListener::bind(format!("127.0.0.1:{port}")).await?;
     while let Ok((stream, _)) = listener.accept().await {
         let store = redis_store.clone();
@@ -1,12 +1,23 @@
 use crate::frame::Frame;
-use bytes::BytesMut;
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::VecDeque;
 use std::io::Cursor;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+/// The longest inline command we buffer while waiting for its newline
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+/// The room made in the buffer before reading from the stream
+const READ_SIZE: usize = 16 * 1024;
+
 pub struct Parser {
     input: Box<dyn AsyncRead + Unpin + Send>,
     buffer: BytesMut,
     pub parsed_bytes: usize,
+    /// Accept inline commands, lines of space separated arguments as sent by
+    /// telnet sessions, along with RESP arrays
+    pub inline: bool,
+    /// frames parsed from the buffer that were not returned yet, with their size
+    pending: VecDeque<(Frame, usize)>,
 }
 
 impl Parser {
@@ -15,14 +26,16 @@ impl Parser {
             input,
             buffer,
             parsed_bytes: 0,
+            inline: false,
+            pending: VecDeque::new(),
         }
     }
 
     /// Read a single `Frame` value from the underlying stream.
     ///
     /// The function waits until it has retrieved enough data to parse a frame.
-    /// Any data remaining in the read buffer after the frame has been parsed is
-    /// kept there for the next call to `read_frame`.
+    /// Every complete frame in the read buffer is parsed at once, the following
+    /// calls return them without touching the stream until they run out.
     ///
     /// # Returns
     ///
@@ -31,9 +44,13 @@ impl Parser {
     /// `None`. Otherwise, an error is returned.
     pub async fn read_frame(&mut self) -> Result<Option<Frame>, crate::Error> {
         loop {
-            // Attempt to parse a frame from the buffered data. If enough data
-            // has been buffered, the frame is returned.
-            if let Some(frame) = self.parse_frame()? {
+            // Attempt to parse the frames from the buffered data. If enough data
+            // has been buffered, the first frame is returned.
+            if self.pending.is_empty() {
+                self.parse_frames()?;
+            }
+            if let Some((frame, size)) = self.pending.pop_front() {
+                self.parsed_bytes = size;
                 return Ok(Some(frame));
             }
 
@@ -42,6 +59,7 @@ impl Parser {
             //
             // On success, the number of bytes is returned. `0` indicates "end
             // of stream".
+            self.buffer.reserve(READ_SIZE);
             if 0 == self.input.read_buf(&mut self.buffer).await? {
                 // The remote closed the connection. For this to be a clean
                 // shutdown, there should be no data in the read buffer. If
@@ -56,19 +74,213 @@ impl Parser {
         }
     }
 
-    pub fn parse_frame(&mut self) -> Result<Option<Frame>, crate::Error> {
+    /// Parses all the complete frames in the buffer, a protocol error is only
+    /// returned once the frames before it have been handed out
+    fn parse_frames(&mut self) -> Result<(), crate::Error> {
+        loop {
+            let parsed = if self.inline && self.buffer.first().is_some_and(|&v| v != b'*') {
+                self.parse_inline()
+            } else {
+                self.parse_resp()
+            };
+            match parsed {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(_) if !self.pending.is_empty() => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Parses the RESP frames at the start of the buffer. The complete frames are
+    /// found first and split off the buffer, so that large bulk strings can be slices
+    /// of it rather than copies.
+    fn parse_resp(&mut self) -> Result<bool, crate::Error> {
         use crate::frame::Error::Incomplete;
         let mut buf = Cursor::new(&self.buffer[..]);
-        match Frame::parse(&mut buf) {
-            Ok(v) => {
-                let parsed = buf.position() as usize;
-                self.buffer.copy_within(parsed.., 0);
-                self.buffer.truncate(self.buffer.len() - parsed);
-                self.parsed_bytes = parsed;
-                Ok(Some(v))
+        let mut sizes = vec![];
+        while buf.has_remaining() && !(self.inline && buf.chunk()[0] != b'*') {
+            let start = buf.position();
+            match Frame::check(&mut buf) {
+                Ok(()) => sizes.push((buf.position() - start) as usize),
+                Err(Incomplete) => break,
+                // The frames before it are handed out first, the next call fails
+                Err(_) if !sizes.is_empty() => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if sizes.is_empty() {
+            return Ok(false);
+        }
+
+        let data = self.buffer.split_to(sizes.iter().sum()).freeze();
+        let mut buf = Cursor::new(&data);
+        for size in sizes {
+            let start = buf.position() as usize;
+            match Frame::parse(&mut buf) {
+                Ok(frame) => self.pending.push_back((frame, size)),
+                Err(e) => {
+                    // Put the invalid frame and the ones after it back, so that the
+                    // error is met again once the frames before it were handed out
+                    let mut rest = BytesMut::from(&data[start..]);
+                    rest.unsplit(std::mem::take(&mut self.buffer));
+                    self.buffer = rest;
+                    return Err(e.into());
+                }
             }
-            Err(Incomplete) => Ok(None),
-            Err(e) => Err(e.into()),
         }
+        Ok(true)
+    }
+
+    /// Reads an inline command as an array of bulk strings, blank lines are skipped
+    fn parse_inline(&mut self) -> Result<bool, crate::Error> {
+        let Some(end) = self.buffer.iter().position(|&v| v == b'\n') else {
+            if self.buffer.len() > MAX_INLINE_SIZE {
+                return Err("Protocol error: too big inline request".into());
+            }
+            return Ok(false);
+        };
+        let args = split_args(&self.buffer[..=end])?;
+        self.buffer.advance(end + 1);
+        if !args.is_empty() {
+            let frame = Frame::Array(args.into_iter().map(Frame::BulkString).collect());
+            self.pending.push_back((frame, end + 1));
+        }
+        Ok(true)
+    }
+}
+
+/// Splits a line into arguments the way redis does: they are separated by whitespace
+/// and can be quoted. "Double quoted" arguments understand the \n, \r, \t, \b, \a
+/// and \xHH escapes, 'single quoted' ones only \'. A closing quote has to end the
+/// argument.
+fn split_args(line: &[u8]) -> Result<Vec<Bytes>, crate::Error> {
+    let unbalanced = || -> crate::Error { "Protocol error: unbalanced quotes in request".into() };
+    let hex = |v: Option<&u8>| v.and_then(|&v| (v as char).to_digit(16));
+    let mut args = vec![];
+    let mut i = 0;
+    loop {
+        while line.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut arg = vec![];
+        let mut quote = None;
+        while let Some(&c) = line.get(i) {
+            i += 1;
+            match (quote, c) {
+                (None, c) if c.is_ascii_whitespace() => break,
+                (None, b'"' | b'\'') => quote = Some(c),
+                (None, c) => arg.push(c),
+                (Some(q), c) if c == q => {
+                    if line.get(i).is_some_and(|v| !v.is_ascii_whitespace()) {
+                        return Err(unbalanced());
+                    }
+                    quote = None;
+                    break;
+                }
+                (Some(b'"'), b'\\') if i < line.len() => {
+                    if line[i] == b'x'
+                        && let (Some(h), Some(l)) = (hex(line.get(i + 1)), hex(line.get(i + 2)))
+                    {
+                        arg.push((h * 16 + l) as u8);
+                        i += 3;
+                        continue;
+                    }
+                    arg.push(match line[i] {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'a' => 0x07,
+                        v => v,
+                    });
+                    i += 1;
+                }
+                (Some(b'\''), b'\\') if line.get(i) == Some(&b'\'') => {
+                    arg.push(b'\'');
+                    i += 1;
+                }
+                (Some(_), c) => arg.push(c),
+            }
+        }
+        if quote.is_some() {
+            return Err(unbalanced());
+        }
+        args.push(arg.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(reads: &[&'static [u8]]) -> Parser {
+        let input = reads.iter().fold(
+            Box::new(&b""[..]) as Box<dyn AsyncRead + Unpin + Send>,
+            |input, &read| Box::new(input.chain(read)),
+        );
+        Parser::new(input, BytesMut::new())
+    }
+
+    async fn next(parser: &mut Parser) -> String {
+        let frame = parser.read_frame().await.unwrap().unwrap();
+        format!("{frame:?}")
+    }
+
+    #[tokio::test]
+    async fn frames_across_reads() {
+        let mut parser = chunked(&[
+            b"*2\r\n$3\r\nGET\r\n$1\r\nk",
+            b"\r\n*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI",
+            b"NG\r\n",
+        ]);
+        assert_eq!(next(&mut parser).await, r#"[b"GET", b"k"]"#);
+        assert_eq!(parser.parsed_bytes, 20);
+        assert_eq!(next(&mut parser).await, r#"[b"PING"]"#);
+        assert_eq!(parser.parsed_bytes, 14);
+        assert_eq!(next(&mut parser).await, r#"[b"PING"]"#);
+        assert!(parser.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn error_after_frames() {
+        // the frame can be delimited but its contents are invalid
+        let mut parser = chunked(&[b"*1\r\n$4\r\nPING\r\n#x\r\n*1\r\n$4\r\nPING\r\n"]);
+        assert_eq!(next(&mut parser).await, r#"[b"PING"]"#);
+        assert!(parser.read_frame().await.is_err());
+
+        // the frame can't be delimited
+        let mut parser = chunked(&[b"*1\r\n$4\r\nPING\r\n$x\r\n"]);
+        assert_eq!(next(&mut parser).await, r#"[b"PING"]"#);
+        assert!(parser.read_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_frame() {
+        let mut parser = chunked(&[b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nPI"]);
+        assert_eq!(next(&mut parser).await, r#"[b"PING"]"#);
+        assert!(parser.read_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn inline_commands() {
+        let mut parser = chunked(&[b"SET k \"a b\"\r\n\r\n*1\r\n$4\r\nPING\r\nPING\n"]);
+        parser.inline = true;
+        assert_eq!(next(&mut parser).await, r#"[b"SET", b"k", b"a b"]"#);
+        assert_eq!(next(&mut parser).await, r#"[b"PING"]"#);
+        assert_eq!(next(&mut parser).await, r#"[b"PING"]"#);
+        assert!(parser.read_frame().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn split_quoted_args() {
+        let args = split_args(b"a \"\\x41\\n\" 'it\\'s'\n").unwrap();
+        assert_eq!(args, [&b"a"[..], b"A\n", b"it's"]);
+        assert!(split_args(b"\"unbalanced\n").is_err());
+        assert!(split_args(b"\"a\"b\n").is_err());
     }
 }
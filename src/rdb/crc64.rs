@@ -0,0 +1,49 @@
+/// Reflected form of the Jones polynomial used by redis for RDB checksums
+const POLY: u64 = 0x95AC9329AC4BC9B5;
+
+const TABLE: [u64; 256] = make_table();
+
+const fn make_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Updates `crc` with `data`, start with a crc of 0 for a new checksum
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for &b in data {
+        crc = TABLE[((crc ^ b as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crc64;
+
+    #[test]
+    fn check_value() {
+        // the test vector of redis' crc64.c
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn incremental() {
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), crc64(0, b"123456789"));
+        assert_eq!(crc64(0, b""), 0);
+    }
+}
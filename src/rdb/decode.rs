@@ -1,131 +1,652 @@
 use super::RDB;
+use super::crc64::crc64;
+use super::listpack::{self, ListpackEntry};
+use super::{lzf, ziplist};
 use crate::Error;
-use crate::store::Value;
+use crate::bytestring::ByteString;
+use crate::frame::Frame;
+use crate::store::{StreamEntry, StreamEntryID, Value, ZSet};
 use bytes::{Buf, Bytes};
+use ordered_float::OrderedFloat;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::Cursor;
 use std::path::PathBuf;
 use std::time::{Duration, UNIX_EPOCH};
 
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+/// the newest RDB version we can load
+const RDB_VERSION: u16 = 11;
+
+const MODULE_OPCODE_EOF: usize = 0;
+const MODULE_OPCODE_SINT: usize = 1;
+const MODULE_OPCODE_UINT: usize = 2;
+const MODULE_OPCODE_FLOAT: usize = 3;
+const MODULE_OPCODE_DOUBLE: usize = 4;
+const MODULE_OPCODE_STRING: usize = 5;
+
+const QUICKLIST_NODE_CONTAINER_PLAIN: usize = 1;
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
 pub struct RDBParser<'a> {
     bytes: Cursor<&'a [u8]>,
 }
 
 impl<'a> RDBParser<'a> {
     pub fn parse_file(path: PathBuf) -> Result<RDB, Error> {
-        let data = Bytes::from(std::fs::read(path)?);
+        let data = std::fs::read(path)?;
+        let (rdb_file, _) = RDBParser::parse(&data)?;
+        Ok(rdb_file)
+    }
 
+    /// Parses an RDB payload from the start of `data`, returning
+    /// it along with the number of bytes that it occupied
+    pub fn parse(data: &'a [u8]) -> Result<(RDB, usize), Error> {
         let mut parser = RDBParser {
-            bytes: Cursor::new(data.as_ref()),
+            bytes: Cursor::new(data),
         };
-        let mut rdb_file = RDB::default();
-        rdb_file.header = parser.parse_header();
-        let mut key_len = 0;
-        let mut _expiry_len = 0;
-
-        while parser.bytes.has_remaining() {
-            let section = parser.bytes.get_u8();
-            match section {
+        let mut rdb_file = RDB {
+            header: parser.parse_header()?,
+            ..Default::default()
+        };
+        let mut expiry = None;
+        let mut db = 0;
+
+        loop {
+            let opcode = parser.get_u8()?;
+            match opcode {
                 0xFA => {
-                    let (k, v) = parser.parse_metadata();
+                    let (k, v) = parser.parse_metadata()?;
                     rdb_file.metadata.insert(k, v);
                 }
                 0xFE => {
-                    let _idx = parser.bytes.get_u8();
-                    if parser.bytes.get_ref()[parser.bytes.position() as usize] == 0xFB {
-                        parser.bytes.advance(1);
-                        key_len = parser.parse_encoded_length().unwrap();
-                        _expiry_len = parser.parse_encoded_length().unwrap();
+                    db = parser.parse_length()?;
+                }
+                0xFB => {
+                    let _key_len = parser.parse_length()?;
+                    let _expiry_len = parser.parse_length()?;
+                }
+                0xFD => {
+                    // expiry time in seconds
+                    let time = parser.take(4)?.get_u32_le();
+                    expiry = Some(UNIX_EPOCH + Duration::from_secs(time as u64));
+                }
+                0xFC => {
+                    // expiry time in milliseconds
+                    let time = parser.take(8)?.get_u64_le();
+                    expiry = Some(UNIX_EPOCH + Duration::from_millis(time));
+                }
+                0xF9 => {
+                    // LFU frequency of the next key
+                    parser.take(1)?;
+                }
+                0xF8 => {
+                    // LRU idle time of the next key
+                    parser.parse_length()?;
+                }
+                0xF5 => {
+                    // the code of a function library, functions are not supported
+                    parser.parse_encoded_bytes()?;
+                }
+                0xF7 => {
+                    // a function as saved by the 7.0 release candidates: its name,
+                    // engine, an optional description and its code
+                    parser.parse_encoded_bytes()?;
+                    parser.parse_encoded_bytes()?;
+                    if parser.parse_length()? != 0 {
+                        parser.parse_encoded_bytes()?;
+                    }
+                    parser.parse_encoded_bytes()?;
+                }
+                0xF6 => {
+                    // auxiliary data of a module: the module id, when it was saved
+                    // and the values written by the module
+                    parser.parse_length()?;
+                    if parser.parse_length()? != MODULE_OPCODE_UINT {
+                        return Err("corrupt module auxiliary data".into());
                     }
-                    for _ in 0..key_len {
-                        let kv_type = parser.bytes.get_ref()[parser.bytes.position() as usize];
-                        match kv_type {
-                            0xFD => {
-                                parser.bytes.advance(1);
-                                // expiry time in seconds
-                                let time = parser.bytes.get_u32_le();
-                                let expiry = UNIX_EPOCH + Duration::from_secs(time as u64);
-                                let (k, v) = parser.parse_key_value();
-                                rdb_file.database.insert(k.clone(), v);
-                                rdb_file.expiry_time.insert(k, expiry);
-                            }
-                            0xFC => {
-                                parser.bytes.advance(1);
-                                // expiry time in milliseconds
-                                let time = parser.bytes.get_u64_le();
-                                let expiry = UNIX_EPOCH + Duration::from_millis(time);
-                                let (k, v) = parser.parse_key_value();
-                                rdb_file.database.insert(k.clone(), v);
-                                rdb_file.expiry_time.insert(k, expiry);
-                            }
-                            0x00 => {
-                                let (k, v) = parser.parse_key_value();
-                                rdb_file.database.insert(k.clone(), v);
-                            }
-                            _ => continue,
-                        }
+                    parser.parse_length()?;
+                    parser.skip_module_value()?;
+                }
+                0xFF => {
+                    parser.verify_checksum(&rdb_file.header)?;
+                    break;
+                }
+                value_type => {
+                    let (k, v) = parser.parse_key_value(value_type)?;
+                    let database = rdb_file.databases.entry(db).or_default();
+                    if let Some(expiry) = expiry.take() {
+                        database.set_expiry(k.clone(), expiry);
                     }
+                    database.insert(k, v);
                 }
-                _ => continue,
             }
         }
-        // println!("database: {:?}", rdb_file.database.keys());
-        Ok(rdb_file)
+
+        Ok((rdb_file, parser.bytes.position() as usize))
+    }
+
+    fn parse_header(&mut self) -> Result<String, Error> {
+        let header = self.take(9)?;
+        if !header.starts_with(b"REDIS") {
+            return Err("wrong signature trying to load DB from file".into());
+        }
+        Ok(String::from_utf8_lossy(header).into())
     }
 
-    fn parse_header(&mut self) -> String {
-        let header = &self.bytes.get_ref()[..9];
-        self.bytes.advance(9);
-        String::from_utf8_lossy(header).into()
+    /// Files since version 5 end with a CRC64 of everything before it,
+    /// a zero checksum means that the writer had checksums disabled
+    fn verify_checksum(&mut self, header: &str) -> Result<(), Error> {
+        let data = *self.bytes.get_ref();
+        let version: usize = header[5..].parse()?;
+        if version < 5 {
+            return Ok(());
+        }
+        let checked = self.bytes.position() as usize;
+        let expected = self.take(8)?.get_u64_le();
+        let actual = crc64(0, &data[..checked]);
+        if expected != 0 && expected != actual {
+            return Err(
+                format!("Wrong RDB checksum expected: ({expected:x}) got: ({actual:x})").into(),
+            );
+        }
+        Ok(())
+    }
+
+    fn parse_metadata(&mut self) -> Result<(String, String), Error> {
+        let key = self.parse_encoded_string()?;
+        let value = self.parse_encoded_string()?;
+        Ok((key, value))
     }
 
-    fn parse_metadata(&mut self) -> (String, String) {
-        let key = self.parse_encoded_string();
-        let value = self.parse_encoded_string();
-        (key, value)
+    /// Parses the value of a payload created with DUMP: the RDB encoding of the
+    /// value, the RDB version it was encoded with and a CRC64 of both
+    pub fn restore(payload: &'a [u8]) -> Result<Value, Error> {
+        let invalid = "DUMP payload version or checksum are wrong";
+        let len = payload.len().checked_sub(10).ok_or(invalid)?;
+        let (data, footer) = payload.split_at(len);
+        let version = u16::from_le_bytes([footer[0], footer[1]]);
+        let checksum = u64::from_le_bytes(footer[2..].try_into()?);
+        if version > RDB_VERSION || crc64(0, &payload[..len + 2]) != checksum {
+            return Err(invalid.into());
+        }
+        let mut parser = RDBParser {
+            bytes: Cursor::new(data),
+        };
+        let value_type = parser.get_u8()?;
+        parser.parse_value(value_type)
     }
 
-    fn parse_key_value(&mut self) -> (String, Value) {
-        let value_type = self.bytes.get_u8();
-        let key = self.parse_encoded_string();
+    fn parse_key_value(&mut self, value_type: u8) -> Result<(ByteString, Value), Error> {
+        let key = self.parse_encoded_bytes()?.into();
+        let value = self.parse_value(value_type)?;
+        Ok((key, value))
+    }
+
+    fn parse_value(&mut self, value_type: u8) -> Result<Value, Error> {
         let value = match value_type {
-            0x00 => self.parse_encoded_string().into(),
-            _ => unimplemented!(),
+            TYPE_STRING => ByteString::from(self.parse_encoded_bytes()?).into(),
+            TYPE_LIST => {
+                let len = self.parse_length()?;
+                let mut list = VecDeque::with_capacity(len.min(self.bytes.remaining()));
+                for _ in 0..len {
+                    list.push_back(self.parse_encoded_bytes()?);
+                }
+                Value::List(list.into_iter().map(Frame::BulkString).collect())
+            }
+            TYPE_SET => {
+                let len = self.parse_length()?;
+                let mut set = HashSet::with_capacity(len.min(self.bytes.remaining()));
+                for _ in 0..len {
+                    set.insert(self.parse_encoded_bytes()?.into());
+                }
+                Value::Set(set)
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.parse_length()?;
+                let mut zset = ZSet::default();
+                for _ in 0..len {
+                    let member = self.parse_encoded_bytes()?.into();
+                    let score = if value_type == TYPE_ZSET {
+                        self.parse_string_double()?
+                    } else {
+                        self.take(8)?.get_f64_le()
+                    };
+                    zset_insert(&mut zset, member, score);
+                }
+                Value::ZSet(zset)
+            }
+            TYPE_HASH => {
+                let len = self.parse_length()?;
+                let mut hash = HashMap::with_capacity(len.min(self.bytes.remaining()));
+                for _ in 0..len {
+                    let field = self.parse_encoded_bytes()?.into();
+                    let value = self.parse_encoded_bytes()?.into();
+                    hash.insert(field, value);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_HASH_ZIPMAP => {
+                let blob = self.parse_encoded_bytes()?;
+                let hash = ziplist::decode_zipmap(&blob)?
+                    .into_iter()
+                    .map(|(k, v)| (k.into(), v.into()))
+                    .collect();
+                Value::Hash(hash)
+            }
+            TYPE_LIST_ZIPLIST => {
+                let blob = self.parse_encoded_bytes()?;
+                let list = ziplist::decode_ziplist(&blob)?;
+                Value::List(list.into_iter().map(entry_frame).collect())
+            }
+            TYPE_SET_INTSET => {
+                let blob = self.parse_encoded_bytes()?;
+                let set = ziplist::decode_intset(&blob)?;
+                Value::Set(set.into_iter().map(|v| v.to_string().into()).collect())
+            }
+            TYPE_SET_LISTPACK => {
+                let blob = self.parse_encoded_bytes()?;
+                let set = listpack::decode(&blob)?;
+                Value::Set(set.into_iter().map(|v| v.to_bytes().into()).collect())
+            }
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let blob = self.parse_encoded_bytes()?;
+                let entries = if value_type == TYPE_ZSET_ZIPLIST {
+                    ziplist::decode_ziplist(&blob)?
+                } else {
+                    listpack::decode(&blob)?
+                };
+                let mut zset = ZSet::default();
+                for pair in entries.chunks_exact(2) {
+                    let score = match &pair[1] {
+                        ListpackEntry::Integer(v) => *v as f64,
+                        ListpackEntry::String(s) => parse_double(s)?,
+                    };
+                    zset_insert(&mut zset, pair[0].to_bytes().into(), score);
+                }
+                Value::ZSet(zset)
+            }
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let blob = self.parse_encoded_bytes()?;
+                let entries = if value_type == TYPE_HASH_ZIPLIST {
+                    ziplist::decode_ziplist(&blob)?
+                } else {
+                    listpack::decode(&blob)?
+                };
+                let hash = entries
+                    .chunks_exact(2)
+                    .map(|pair| (pair[0].to_bytes().into(), pair[1].to_bytes().into()))
+                    .collect();
+                Value::Hash(hash)
+            }
+            TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.parse_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = if value_type == TYPE_LIST_QUICKLIST_2 {
+                        self.parse_length()?
+                    } else {
+                        0
+                    };
+                    let blob = self.parse_encoded_bytes()?;
+                    if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+                        list.push_back(Frame::BulkString(blob));
+                    } else if value_type == TYPE_LIST_QUICKLIST {
+                        list.extend(ziplist::decode_ziplist(&blob)?.into_iter().map(entry_frame));
+                    } else {
+                        list.extend(listpack::decode(&blob)?.into_iter().map(entry_frame));
+                    }
+                }
+                Value::List(list)
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                Value::Stream(self.parse_stream(value_type)?)
+            }
+            _ => return Err(format!("unknown RDB value type {value_type}").into()),
         };
-        (key, value)
-    }
-
-    fn parse_encoded_string(&mut self) -> String {
-        let length = self.parse_encoded_length();
-        match length {
-            Ok(length) => {
-                let position = self.bytes.position() as usize;
-                let length = length.min(self.bytes.get_ref().len() - position);
-                let data =
-                    String::from_utf8_lossy(&self.bytes.get_ref()[position..position + length]);
-                self.bytes.advance(length);
-                data.into()
-            }
-            Err(encoded) => match encoded {
-                0xC0 => self.bytes.get_u8().to_string(),
-                0xC1 => self.bytes.get_u16().to_string(),
-                0xC2 => self.bytes.get_u32().to_string(),
-                _ => unimplemented!(),
-            },
+        Ok(value)
+    }
+
+    /// Streams are a radix tree of listpacks keyed by the id of the first entry of
+    /// the node, followed by the stream metadata and the consumer groups.
+    fn parse_stream(&mut self, value_type: u8) -> Result<Vec<StreamEntry>, Error> {
+        let mut stream = vec![];
+
+        let nodes = self.parse_length()?;
+        for _ in 0..nodes {
+            let mut key = self.parse_encoded_bytes()?;
+            if key.len() != 16 {
+                return Err("corrupt stream node key".into());
+            }
+            let master_time = key.get_u64() as i64;
+            let master_sqn = key.get_u64() as i64;
+
+            let lp = self.parse_encoded_bytes()?;
+            let mut entries = listpack::decode(&lp)?.into_iter();
+            let count = next_int(&mut entries)? + next_int(&mut entries)?;
+            let master_fields_count = next_int(&mut entries)?;
+            let master_fields = (0..master_fields_count)
+                .map(|_| Ok(next_entry(&mut entries)?.to_bytes().into()))
+                .collect::<Result<Vec<ByteString>, Error>>()?;
+            // the master entry is terminated by a zero
+            next_entry(&mut entries)?;
+
+            for _ in 0..count {
+                let flags = next_int(&mut entries)?;
+                let time = (master_time + next_int(&mut entries)?) as usize;
+                let sqn = (master_sqn + next_int(&mut entries)?) as usize;
+                let data = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+                    master_fields
+                        .iter()
+                        .map(|f| Ok((f.clone(), next_entry(&mut entries)?.to_bytes().into())))
+                        .collect::<Result<HashMap<_, _>, Error>>()?
+                } else {
+                    (0..next_int(&mut entries)?)
+                        .map(|_| {
+                            let field = next_entry(&mut entries)?.to_bytes().into();
+                            Ok((field, next_entry(&mut entries)?.to_bytes().into()))
+                        })
+                        .collect::<Result<HashMap<_, _>, Error>>()?
+                };
+                // lp-count
+                next_entry(&mut entries)?;
+                if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+                    let id = StreamEntryID { time, sqn };
+                    stream.push(StreamEntry { id, data });
+                }
+            }
+        }
+
+        // length and last id
+        self.parse_length()?;
+        self.parse_length()?;
+        self.parse_length()?;
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // first id, max deleted id and entries added
+            for _ in 0..5 {
+                self.parse_length()?;
+            }
+        }
+
+        // consumer groups are not supported so they are read and dropped
+        let groups = self.parse_length()?;
+        for _ in 0..groups {
+            self.parse_encoded_bytes()?;
+            self.parse_length()?;
+            self.parse_length()?;
+            if value_type >= TYPE_STREAM_LISTPACKS_2 {
+                self.parse_length()?;
+            }
+            let pending = self.parse_length()?;
+            for _ in 0..pending {
+                // raw id, delivery time and delivery count
+                self.take(16 + 8)?;
+                self.parse_length()?;
+            }
+            let consumers = self.parse_length()?;
+            for _ in 0..consumers {
+                self.parse_encoded_bytes()?;
+                // seen time and active time
+                self.take(8)?;
+                if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                    self.take(8)?;
+                }
+                let pending = self.parse_length()?;
+                for _ in 0..pending {
+                    self.take(16)?;
+                }
+            }
+        }
+
+        stream.sort();
+        Ok(stream)
+    }
+
+    /// Skips a value written by a module that isn't loaded, it is a list of typed
+    /// values terminated by an EOF opcode
+    fn skip_module_value(&mut self) -> Result<(), Error> {
+        loop {
+            match self.parse_length()? {
+                MODULE_OPCODE_EOF => return Ok(()),
+                MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                    self.parse_length()?;
+                }
+                MODULE_OPCODE_FLOAT => {
+                    self.take(4)?;
+                }
+                MODULE_OPCODE_DOUBLE => {
+                    self.take(8)?;
+                }
+                MODULE_OPCODE_STRING => {
+                    self.parse_encoded_bytes()?;
+                }
+                v => return Err(format!("unknown module opcode {v}").into()),
+            }
         }
     }
 
-    fn parse_encoded_length(&mut self) -> Result<usize, usize> {
-        let starting = self.bytes.get_u8();
-        match starting >> 6 {
+    /// Scores of the old zset encoding are stored as strings
+    /// prefixed by a single byte length, or a special value for nan and infinities.
+    fn parse_string_double(&mut self) -> Result<f64, Error> {
+        let len = self.get_u8()?;
+        match len {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_double(self.take(len as usize)?),
+        }
+    }
+
+    fn parse_encoded_string(&mut self) -> Result<String, Error> {
+        let data = self.parse_encoded_bytes()?;
+        Ok(String::from_utf8_lossy(&data).into())
+    }
+
+    fn parse_encoded_bytes(&mut self) -> Result<Bytes, Error> {
+        match self.parse_encoded_length()? {
+            Ok(length) => Ok(Bytes::copy_from_slice(self.take(length)?)),
+            Err(0xC3) => {
+                let compressed_len = self.parse_length()?;
+                let len = self.parse_length()?;
+                let compressed = self.take(compressed_len)?;
+                Ok(Bytes::from(lzf::decompress(compressed, len)?))
+            }
+            Err(encoded) => {
+                let v = match encoded {
+                    0xC0 => self.take(1)?.get_i8() as i64,
+                    0xC1 => self.take(2)?.get_i16_le() as i64,
+                    0xC2 => self.take(4)?.get_i32_le() as i64,
+                    v => return Err(format!("unknown string encoding {v:#x}").into()),
+                };
+                Ok(Bytes::from(v.to_string()))
+            }
+        }
+    }
+
+    fn parse_length(&mut self) -> Result<usize, Error> {
+        self.parse_encoded_length()?
+            .map_err(|v| format!("unexpected string encoding {v:#x}").into())
+    }
+
+    fn parse_encoded_length(&mut self) -> Result<Result<usize, usize>, Error> {
+        let starting = self.get_u8()?;
+        Ok(match starting >> 6 {
             0b00 => Ok(starting as usize),
             0b01 => {
                 let first = (starting & 0x3F) as usize;
-                let second = self.bytes.get_u8() as usize;
+                let second = self.get_u8()? as usize;
                 Ok(first << 8 | second)
             }
-            0b10 => Ok(self.bytes.get_u32() as usize),
-            0b11 => Err(starting as usize),
-            _ => panic!("not possible"),
+            0b10 if starting == 0x81 => Ok(self.take(8)?.get_u64() as usize),
+            0b10 => Ok(self.take(4)?.get_u32() as usize),
+            _ => Err(starting as usize),
+        })
+    }
+
+    fn get_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Consumes the next `n` bytes, failing if the file ends before that
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let eof = "unexpected end of RDB file";
+        let position = self.bytes.position() as usize;
+        let data = *self.bytes.get_ref();
+        let end = position.checked_add(n).ok_or(eof)?;
+        let res = data.get(position..end).ok_or(eof)?;
+        self.bytes.advance(n);
+        Ok(res)
+    }
+}
+
+fn zset_insert(zset: &mut ZSet, member: ByteString, score: f64) {
+    let score = OrderedFloat(score);
+    zset.scores.insert(member.clone(), score);
+    zset.ordered.insert((score, member));
+}
+
+fn next_entry(entries: &mut impl Iterator<Item = ListpackEntry>) -> Result<ListpackEntry, Error> {
+    entries
+        .next()
+        .ok_or_else(|| "corrupt stream listpack".into())
+}
+
+fn next_int(entries: &mut impl Iterator<Item = ListpackEntry>) -> Result<i64, Error> {
+    next_entry(entries)?
+        .integer()
+        .ok_or_else(|| "corrupt stream listpack".into())
+}
+
+fn entry_frame(entry: ListpackEntry) -> Frame {
+    Frame::BulkString(entry.to_bytes())
+}
+
+fn parse_double(s: &[u8]) -> Result<f64, Error> {
+    let s = std::str::from_utf8(s)?;
+    match s {
+        "inf" | "+inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        _ => Ok(s.parse()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends the RDB version and the CRC64 that DUMP adds to `data`
+    fn dump_payload(data: &[u8]) -> Vec<u8> {
+        let mut payload = data.to_vec();
+        payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+        let checksum = crc64(0, &payload);
+        payload.extend_from_slice(&checksum.to_le_bytes());
+        payload
+    }
+
+    fn list(value: &Value) -> Vec<Bytes> {
+        let list = value.list().expect("not a list");
+        list.iter()
+            .map(|v| match v {
+                Frame::BulkString(v) => v.clone(),
+                _ => panic!("not a bulk string"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restore_string() {
+        // DUMP of "10" from the redis documentation
+        let value = RDBParser::restore(b"\x00\xc0\n\n\x00n\x9fWE\x0e\xaec\xbb").unwrap();
+        assert!(*value.string().unwrap() == "10");
+    }
+
+    #[test]
+    fn restore_ziplist() {
+        // the RESTORE example of the redis documentation, a list of 1, 2 and 3
+        let payload = b"\n\x17\x17\x00\x00\x00\x12\x00\x00\x00\x03\x00\x00\xc0\x01\x00\x04\xc0\x02\x00\x04\xc0\x03\x00\xff\x04\x00u#<\xc0;.\xe9\xdd";
+        let value = RDBParser::restore(payload).unwrap();
+        assert_eq!(list(&value), ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn restore_wrong_checksum() {
+        let payload = b"\x00\xc0\n\n\x00n\x9fWE\x0e\xaec\xbc";
+        assert!(RDBParser::restore(payload).is_err());
+        assert!(RDBParser::restore(b"\x00").is_err());
+    }
+
+    #[test]
+    fn restore_huge_lengths() {
+        // lengths close to 2^64 with a valid checksum must not allocate or overflow
+        let eof = "unexpected end of RDB file";
+        let huge = b"\x81\xff\xff\xff\xff\xff\xff\xff\xf0";
+        for value_type in [TYPE_STRING, TYPE_LIST, TYPE_SET, TYPE_HASH, TYPE_ZSET_2] {
+            let mut data = vec![value_type];
+            data.extend_from_slice(huge);
+            let err = RDBParser::restore(&dump_payload(&data)).err().unwrap();
+            assert_eq!(err.to_string(), eof);
         }
+        // an LZF string claiming an absurd uncompressed length
+        let mut data = vec![TYPE_STRING, 0xc3, 0x09];
+        data.extend_from_slice(huge);
+        data.extend_from_slice(b"\x01aa\xe0\x13\x00\x01aa");
+        assert!(RDBParser::restore(&dump_payload(&data)).is_err());
+    }
+
+    #[test]
+    fn parse_file_with_functions_and_module_aux() {
+        let mut data = b"REDIS0011".to_vec();
+        // aux field
+        data.extend_from_slice(b"\xfa\x09redis-ver\x057.2.4");
+        // a function library
+        data.extend_from_slice(b"\xf5\x0c#!lua name=l");
+        // module auxiliary data: the module id, the when opcode and value,
+        // then an unsigned integer, a string and a double
+        data.extend_from_slice(b"\xf6\x81\x01\x02\x03\x04\x05\x06\x07\x08\x02\x02");
+        data.extend_from_slice(b"\x02\x05\x05\x03abc\x04");
+        data.extend_from_slice(&1.5f64.to_le_bytes());
+        data.push(0x00);
+        // database 0, one key holding 32 LZF compressed "a"
+        data.extend_from_slice(b"\xfe\x00\xfb\x01\x00");
+        data.extend_from_slice(b"\x00\x01k\xc3\x09\x20\x01aa\xe0\x13\x00\x01aa");
+        data.push(0xff);
+        let checksum = crc64(0, &data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+
+        let (rdb, len) = RDBParser::parse(&data).unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(rdb.metadata["redis-ver"], "7.2.4");
+        let value = &rdb.databases[&0].kv[&ByteString::from("k")];
+        assert!(*value.string().unwrap() == *"a".repeat(32));
+
+        // an unknown module opcode is an error
+        let pos = data.windows(2).position(|w| w == b"\x02\x05").unwrap();
+        data[pos] = 0x09;
+        assert!(RDBParser::parse(&data).is_err());
+    }
+
+    #[test]
+    fn parse_truncated_file() {
+        assert!(RDBParser::parse(b"REDIS0011\xfe\x00\x00\x01k").is_err());
+        assert!(RDBParser::parse(b"REDIX0011\xff").is_err());
     }
 }
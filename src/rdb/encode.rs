@@ -0,0 +1,455 @@
+use super::crc64::crc64;
+use super::listpack::ListpackWriter;
+use crate::Error;
+use crate::bytestring::ByteString;
+use crate::frame::Frame;
+use crate::store::{Store, StreamEntry, Value};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const RDB_VERSION: &str = "0011";
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+pub struct RDBEncoder {
+    bytes: BytesMut,
+}
+
+impl RDBEncoder {
+    /// Serializes the whole dataset held by the store into an RDB file
+    pub fn encode(store: &Store) -> Bytes {
+        let mut encoder = RDBEncoder {
+            bytes: BytesMut::with_capacity(4 * 1024),
+        };
+        encoder.encode_header();
+        encoder.encode_metadata("redis-ver", "7.2.0");
+        encoder.encode_metadata("redis-bits", "64");
+        let ctime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        encoder.encode_metadata("ctime", &ctime.to_string());
+        encoder.encode_metadata("aof-base", "0");
+        // the database the replication stream continues in
+        if let Some(db) = store.replication_db {
+            encoder.encode_metadata("repl-stream-db", &db.to_string());
+        }
+
+        for (idx, db) in store.databases.iter().enumerate() {
+            // vector sets can't be created yet so they are left out
+            let keys: Vec<_> = db
+                .kv
+                .iter()
+                .filter(|(_, v)| !matches!(v, Value::VectorSet))
+                .collect();
+            if keys.is_empty() {
+                continue;
+            }
+            encoder.bytes.put_u8(0xFE);
+            encoder.encode_length(idx);
+            encoder.bytes.put_u8(0xFB);
+            encoder.encode_length(keys.len());
+            let expires = keys
+                .iter()
+                .filter(|(k, _)| db.expiry_time.contains_key(*k))
+                .count();
+            encoder.encode_length(expires);
+
+            for (k, v) in keys {
+                if let Some(expiry) = db.expiry_time.get(k) {
+                    let time = expiry
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis();
+                    encoder.bytes.put_u8(0xFC);
+                    encoder.bytes.put_u64_le(time as u64);
+                }
+                encoder.encode_key_value(k, v);
+            }
+        }
+
+        encoder.bytes.put_u8(0xFF);
+        let checksum = crc64(0, &encoder.bytes);
+        encoder.bytes.put_u64_le(checksum);
+        encoder.bytes.freeze()
+    }
+
+    /// Serializes a single value the way DUMP does: its RDB encoding
+    /// followed by the RDB version and a CRC64 of both
+    pub fn dump(value: &Value) -> Bytes {
+        let mut encoder = RDBEncoder {
+            bytes: BytesMut::new(),
+        };
+        encoder.encode_value(value);
+        encoder
+            .bytes
+            .put_u16_le(RDB_VERSION.parse().unwrap_or_default());
+        let checksum = crc64(0, &encoder.bytes);
+        encoder.bytes.put_u64_le(checksum);
+        encoder.bytes.freeze()
+    }
+
+    /// Atomically replaces the file at `path` with `data`
+    /// by writing to a temporary file first and then renaming it
+    pub fn save_file(path: PathBuf, data: &[u8]) -> Result<(), Error> {
+        let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+        std::fs::write(&temp, data)?;
+        std::fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    fn encode_header(&mut self) {
+        self.bytes.put_slice(b"REDIS");
+        self.bytes.put_slice(RDB_VERSION.as_bytes());
+    }
+
+    fn encode_metadata(&mut self, key: &str, value: &str) {
+        self.bytes.put_u8(0xFA);
+        self.encode_string(key.as_bytes());
+        self.encode_string(value.as_bytes());
+    }
+
+    fn encode_key_value(&mut self, key: &[u8], value: &Value) {
+        self.bytes.put_u8(value_type(value));
+        self.encode_string(key);
+        self.encode_value_data(value);
+    }
+
+    fn encode_value(&mut self, value: &Value) {
+        self.bytes.put_u8(value_type(value));
+        self.encode_value_data(value);
+    }
+
+    fn encode_value_data(&mut self, value: &Value) {
+        match value {
+            Value::String(s) => {
+                self.encode_string(s.as_bytes());
+            }
+            Value::List(list) => {
+                self.encode_length(list.len());
+                list.iter().for_each(|v| self.encode_frame(v));
+            }
+            Value::Set(set) => {
+                self.encode_length(set.len());
+                set.iter().for_each(|v| self.encode_string(v.as_bytes()));
+            }
+            Value::ZSet(zset) => {
+                self.encode_length(zset.ordered.len());
+                for (score, member) in zset.ordered.iter().rev() {
+                    self.encode_string(member.as_bytes());
+                    self.bytes.put_f64_le(score.0);
+                }
+            }
+            Value::Hash(hash) => {
+                self.encode_length(hash.len());
+                for (field, value) in hash {
+                    self.encode_string(field.as_bytes());
+                    self.encode_string(value.as_bytes());
+                }
+            }
+            Value::Stream(stream) => {
+                self.encode_stream(stream);
+            }
+            Value::VectorSet => {}
+        }
+    }
+
+    /// Streams are stored as a radix tree of listpacks, every node is keyed by
+    /// the 128 bit big endian id of its first entry and every entry in the node
+    /// is delta encoded against it.
+    fn encode_stream(&mut self, stream: &[StreamEntry]) {
+        let nodes: Vec<_> = stream.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+        self.encode_length(nodes.len());
+        for node in nodes {
+            let master = &node[0];
+            let mut master_id = BytesMut::with_capacity(16);
+            master_id.put_u64(master.id.time as u64);
+            master_id.put_u64(master.id.sqn as u64);
+            self.encode_string(&master_id);
+
+            let fields: Vec<&ByteString> = master.data.keys().collect();
+            let mut lp = ListpackWriter::new();
+            lp.push_int(node.len() as i64);
+            lp.push_int(0);
+            lp.push_int(fields.len() as i64);
+            fields.iter().for_each(|f| lp.push_str(f.as_bytes()));
+            lp.push_int(0);
+
+            for entry in node {
+                let ms_diff = (entry.id.time - master.id.time) as i64;
+                let seq_diff = entry.id.sqn as i64 - master.id.sqn as i64;
+                let same_fields = entry.data.len() == fields.len()
+                    && fields.iter().all(|f| entry.data.contains_key(*f));
+                if same_fields {
+                    lp.push_int(STREAM_ITEM_FLAG_SAMEFIELDS);
+                    lp.push_int(ms_diff);
+                    lp.push_int(seq_diff);
+                    fields
+                        .iter()
+                        .for_each(|f| lp.push_str(entry.data[*f].as_bytes()));
+                    lp.push_int(3 + fields.len() as i64);
+                } else {
+                    lp.push_int(0);
+                    lp.push_int(ms_diff);
+                    lp.push_int(seq_diff);
+                    lp.push_int(entry.data.len() as i64);
+                    for (k, v) in &entry.data {
+                        lp.push_str(k.as_bytes());
+                        lp.push_str(v.as_bytes());
+                    }
+                    lp.push_int(4 + 2 * entry.data.len() as i64);
+                }
+            }
+            self.encode_string(&lp.finish());
+        }
+
+        let first = stream.first().map(|v| v.id);
+        let last = stream.last().map(|v| v.id);
+        // length, last id, first id, max deleted id and entries added
+        self.encode_length(stream.len());
+        self.encode_length(last.map(|v| v.time).unwrap_or(0));
+        self.encode_length(last.map(|v| v.sqn).unwrap_or(0));
+        self.encode_length(first.map(|v| v.time).unwrap_or(0));
+        self.encode_length(first.map(|v| v.sqn).unwrap_or(0));
+        self.encode_length(0);
+        self.encode_length(0);
+        self.encode_length(stream.len());
+        // consumer groups
+        self.encode_length(0);
+    }
+
+    fn encode_frame(&mut self, frame: &Frame) {
+        match frame {
+            Frame::BulkString(b) => self.encode_string(b),
+            Frame::SimpleString(s) => self.encode_string(s.as_bytes()),
+            Frame::Integer(i) => self.encode_string(i.to_string().as_bytes()),
+            _ => self.encode_string(b""),
+        }
+    }
+
+    fn encode_string(&mut self, s: &[u8]) {
+        self.encode_length(s.len());
+        self.bytes.put_slice(s);
+    }
+
+    fn encode_length(&mut self, length: usize) {
+        if length < 1 << 6 {
+            self.bytes.put_u8(length as u8);
+        } else if length < 1 << 14 {
+            self.bytes.put_u8(0x40 | (length >> 8) as u8);
+            self.bytes.put_u8((length & 0xFF) as u8);
+        } else if length <= u32::MAX as usize {
+            self.bytes.put_u8(0x80);
+            self.bytes.put_u32(length as u32);
+        } else {
+            self.bytes.put_u8(0x81);
+            self.bytes.put_u64(length as u64);
+        }
+    }
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET_2,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Stream(_) | Value::VectorSet => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdb::decode::RDBParser;
+    use crate::store::{Database, StreamEntryID, ZSet};
+    use ordered_float::OrderedFloat;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::time::Duration;
+
+    fn list(items: &[&'static str]) -> Value {
+        Value::List(items.iter().map(|&v| Frame::BulkString(v.into())).collect())
+    }
+
+    fn zset(members: &[(&str, f64)]) -> Value {
+        let mut zset = ZSet::default();
+        for &(member, score) in members {
+            zset.scores.insert(member.into(), OrderedFloat(score));
+            zset.ordered.insert((OrderedFloat(score), member.into()));
+        }
+        Value::ZSet(zset)
+    }
+
+    /// A stream spread over several nodes, some entries with fields other
+    /// than the ones of the first entry of their node
+    fn stream(len: usize) -> Value {
+        let entries = (0..len)
+            .map(|i| {
+                let mut data = HashMap::from([("temp".into(), i.to_string().into())]);
+                if i % 7 == 3 {
+                    data.insert("extra".into(), "x".into());
+                }
+                StreamEntry {
+                    id: StreamEntryID {
+                        time: 1000 + i / 3,
+                        sqn: i % 3,
+                    },
+                    data,
+                }
+            })
+            .collect();
+        Value::Stream(entries)
+    }
+
+    fn values() -> Vec<(&'static str, Value)> {
+        vec![
+            ("string", Value::String("value".into())),
+            ("number", Value::String("12345".into())),
+            ("list", list(&["a", "b", "c"])),
+            ("set", Value::Set(HashSet::from(["x".into(), "y".into()]))),
+            (
+                "zset",
+                zset(&[("a", 1.0), ("b", -2.5), ("c", f64::INFINITY)]),
+            ),
+            (
+                "hash",
+                Value::Hash(HashMap::from([
+                    ("f".into(), "1".into()),
+                    ("g".into(), "".into()),
+                ])),
+            ),
+            ("stream", stream(250)),
+            ("empty stream", stream(0)),
+        ]
+    }
+
+    fn strings(list: &VecDeque<Frame>) -> Vec<Bytes> {
+        list.iter()
+            .map(|v| match v {
+                Frame::BulkString(v) => v.clone(),
+                _ => panic!("not a bulk string"),
+            })
+            .collect()
+    }
+
+    fn assert_same(a: &Value, b: &Value) {
+        match (a, b) {
+            (Value::String(a), Value::String(b)) => assert_eq!(a, b),
+            (Value::List(a), Value::List(b)) => assert_eq!(strings(a), strings(b)),
+            (Value::Set(a), Value::Set(b)) => assert_eq!(a, b),
+            (Value::ZSet(a), Value::ZSet(b)) => {
+                assert_eq!(a.scores, b.scores);
+                assert_eq!(a.ordered, b.ordered);
+            }
+            (Value::Hash(a), Value::Hash(b)) => assert_eq!(a, b),
+            (Value::Stream(a), Value::Stream(b)) => {
+                assert_eq!(a.len(), b.len());
+                for (a, b) in a.iter().zip(b) {
+                    assert_eq!(a.id.to_string(), b.id.to_string());
+                    assert_eq!(a.data, b.data);
+                }
+            }
+            _ => panic!("values of different types"),
+        }
+    }
+
+    #[test]
+    fn encode_and_parse_store() {
+        // expiry times are saved in milliseconds
+        let expiry = UNIX_EPOCH + Duration::from_millis(4_000_000_000_000);
+        let mut store = Store {
+            databases: vec![Database::default(), Database::default()],
+            replication_db: Some(1),
+            ..Store::default()
+        };
+        for (key, value) in values() {
+            store.databases[0].insert(key.into(), value);
+        }
+        store.databases[0].set_expiry("string".into(), expiry);
+        store.databases[0].insert("vector set".into(), Value::VectorSet);
+        store.databases[1].insert("other".into(), list(&["1"]));
+
+        let data = RDBEncoder::encode(&store);
+        let (rdb, len) = RDBParser::parse(&data).unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(rdb.metadata["repl-stream-db"], "1");
+        assert_eq!(rdb.databases.len(), 2);
+
+        let db = &rdb.databases[&0];
+        assert_eq!(db.kv.len(), values().len());
+        for (key, value) in values() {
+            assert_same(&db.kv[&ByteString::from(key)], &value);
+        }
+        assert_eq!(db.expiry_time, HashMap::from([("string".into(), expiry)]));
+        assert_same(
+            &rdb.databases[&1].kv[&ByteString::from("other")],
+            &list(&["1"]),
+        );
+
+        // the checksum covers the whole file
+        let mut corrupted = data.to_vec();
+        corrupted[len / 2] ^= 1;
+        assert!(RDBParser::parse(&corrupted).is_err());
+    }
+
+    #[test]
+    fn dump_and_restore() {
+        for (_, value) in values() {
+            let payload = RDBEncoder::dump(&value);
+            let len = payload.len();
+            // the RDB version and a CRC64 of everything before it
+            let version = u16::from_le_bytes([payload[len - 10], payload[len - 9]]);
+            assert_eq!(version.to_string(), RDB_VERSION.trim_start_matches('0'));
+            let checksum = u64::from_le_bytes(payload[len - 8..].try_into().unwrap());
+            assert_eq!(checksum, crc64(0, &payload[..len - 8]));
+            assert_same(&RDBParser::restore(&payload).unwrap(), &value);
+
+            for i in [0, len - 10, len - 1] {
+                let mut corrupted = payload.to_vec();
+                corrupted[i] ^= 1;
+                assert!(RDBParser::restore(&corrupted).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn restore_stream_with_groups() {
+        // consumer groups are not kept, a stream that has some restores to its entries
+        let value = stream(10);
+        let dump = RDBEncoder::dump(&value);
+        // the name, last delivered id 1003-0 and entries read of the group
+        let mut group = b"\x05group\x43\xeb\x00\x0a".to_vec();
+        // a pending entry: its id, delivery time and count
+        group.push(1);
+        group.extend_from_slice(&[0; 16 + 8]);
+        group.push(1);
+        // a consumer: its name, seen and active times and pending ids
+        group.extend_from_slice(b"\x01\x08consumer");
+        group.extend_from_slice(&[0; 8 + 8]);
+        group.push(1);
+        group.extend_from_slice(&[0; 16]);
+
+        let with_group = |group: &[u8]| {
+            // the data without the trailer and the number of groups
+            let mut payload = dump[..dump.len() - 11].to_vec();
+            payload.push(1);
+            payload.extend_from_slice(group);
+            payload.extend_from_slice(&dump[dump.len() - 10..dump.len() - 8]);
+            let checksum = crc64(0, &payload);
+            payload.extend_from_slice(&checksum.to_le_bytes());
+            RDBParser::restore(&payload)
+        };
+        assert_same(&with_group(&group).unwrap(), &value);
+        assert!(with_group(&group[..group.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use crate::Error;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt::{Display, Formatter};
+
+const LP_HEADER_SIZE: usize = 6;
+const LP_EOF: u8 = 0xFF;
+
+/// A single element of a listpack or a ziplist
+#[derive(Debug, PartialEq)]
+pub enum ListpackEntry {
+    Integer(i64),
+    String(Bytes),
+}
+
+impl ListpackEntry {
+    pub fn integer(&self) -> Option<i64> {
+        match self {
+            ListpackEntry::Integer(v) => Some(*v),
+            ListpackEntry::String(s) => std::str::from_utf8(s).ok()?.parse().ok(),
+        }
+    }
+
+    /// The string the entry stands for, integers are written in decimal
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            ListpackEntry::Integer(v) => Bytes::from(v.to_string()),
+            ListpackEntry::String(s) => s.clone(),
+        }
+    }
+}
+
+impl Display for ListpackEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListpackEntry::Integer(v) => write!(f, "{v}"),
+            ListpackEntry::String(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+        }
+    }
+}
+
+/// Decodes all the elements of a listpack blob
+pub fn decode(lp: &[u8]) -> Result<Vec<ListpackEntry>, Error> {
+    let err = || -> Error { "corrupt listpack".into() };
+    let take = |pos: usize, n: usize| lp.get(pos..pos + n).ok_or_else(err);
+
+    let mut res = vec![];
+    let mut pos = LP_HEADER_SIZE;
+    loop {
+        let start = pos;
+        let encoding = take(pos, 1)?[0];
+        pos += 1;
+        let entry = if encoding == LP_EOF {
+            break;
+        } else if encoding & 0x80 == 0 {
+            ListpackEntry::Integer((encoding & 0x7F) as i64)
+        } else if encoding & 0xC0 == 0x80 {
+            let len = (encoding & 0x3F) as usize;
+            pos += len;
+            ListpackEntry::String(Bytes::copy_from_slice(take(pos - len, len)?))
+        } else if encoding & 0xE0 == 0xC0 {
+            let v = ((encoding & 0x1F) as i64) << 8 | take(pos, 1)?[0] as i64;
+            pos += 1;
+            ListpackEntry::Integer(if v >= 1 << 12 { v - (1 << 13) } else { v })
+        } else if encoding & 0xF0 == 0xE0 {
+            let len = ((encoding & 0x0F) as usize) << 8 | take(pos, 1)?[0] as usize;
+            pos += 1 + len;
+            ListpackEntry::String(Bytes::copy_from_slice(take(pos - len, len)?))
+        } else if encoding == 0xF0 {
+            let len = int_le(take(pos, 4)?) as u32 as usize;
+            pos += 4 + len;
+            ListpackEntry::String(Bytes::copy_from_slice(take(pos - len, len)?))
+        } else {
+            let width = match encoding {
+                0xF1 => 2,
+                0xF2 => 3,
+                0xF3 => 4,
+                0xF4 => 8,
+                _ => return Err(err()),
+            };
+            pos += width;
+            ListpackEntry::Integer(int_le(take(pos - width, width)?))
+        };
+        pos += backlen_size(pos - start);
+        res.push(entry);
+    }
+    Ok(res)
+}
+
+/// Reads a little endian signed integer
+/// of the width of the given slice
+pub(super) fn int_le(b: &[u8]) -> i64 {
+    let mut v = [0u8; 8];
+    v[..b.len()].copy_from_slice(b);
+    // sign extend from the width of the encoding
+    let shift = 64 - 8 * b.len();
+    (i64::from_le_bytes(v) << shift) >> shift
+}
+
+/// Builds a listpack blob, the compact encoding redis uses for small
+/// aggregates and for the nodes of a stream.
+/// ```
+/// <total-bytes u32> <num-elements u16> <element ...> <0xFF>
+/// ```
+/// where every element is `<encoding-type><element-data><backlen>`
+pub struct ListpackWriter {
+    bytes: BytesMut,
+    count: usize,
+}
+
+impl ListpackWriter {
+    pub fn new() -> Self {
+        let mut bytes = BytesMut::new();
+        bytes.put_bytes(0, LP_HEADER_SIZE);
+        ListpackWriter { bytes, count: 0 }
+    }
+
+    /// Appends an integer using the smallest encoding that can hold it
+    pub fn push_int(&mut self, v: i64) {
+        let start = self.bytes.len();
+        if (0..=127).contains(&v) {
+            self.bytes.put_u8(v as u8);
+        } else if (-4096..=4095).contains(&v) {
+            let v = if v < 0 { (1 << 13) + v } else { v } as u16;
+            self.bytes.put_u8(0xC0 | (v >> 8) as u8);
+            self.bytes.put_u8((v & 0xFF) as u8);
+        } else if i16::try_from(v).is_ok() {
+            self.bytes.put_u8(0xF1);
+            self.bytes.put_i16_le(v as i16);
+        } else if (-(1 << 23)..(1 << 23)).contains(&v) {
+            self.bytes.put_u8(0xF2);
+            self.bytes.put_slice(&(v as i32).to_le_bytes()[..3]);
+        } else if i32::try_from(v).is_ok() {
+            self.bytes.put_u8(0xF3);
+            self.bytes.put_i32_le(v as i32);
+        } else {
+            self.bytes.put_u8(0xF4);
+            self.bytes.put_i64_le(v);
+        }
+        self.finish_entry(start);
+    }
+
+    /// Appends a string, integers written as strings are stored as strings
+    pub fn push_str(&mut self, s: &[u8]) {
+        let start = self.bytes.len();
+        let len = s.len();
+        if len < 64 {
+            self.bytes.put_u8(0x80 | len as u8);
+        } else if len < 4096 {
+            self.bytes.put_u8(0xE0 | (len >> 8) as u8);
+            self.bytes.put_u8((len & 0xFF) as u8);
+        } else {
+            self.bytes.put_u8(0xF0);
+            self.bytes.put_u32_le(len as u32);
+        }
+        self.bytes.put_slice(s);
+        self.finish_entry(start);
+    }
+
+    pub fn finish(mut self) -> Bytes {
+        self.bytes.put_u8(LP_EOF);
+        let total = self.bytes.len() as u32;
+        let count = self.count.min(u16::MAX as usize) as u16;
+        self.bytes[0..4].copy_from_slice(&total.to_le_bytes());
+        self.bytes[4..6].copy_from_slice(&count.to_le_bytes());
+        self.bytes.freeze()
+    }
+
+    /// Writes the back length of the element that starts at `start`
+    /// so that the listpack can also be traversed from the tail
+    fn finish_entry(&mut self, start: usize) {
+        let l = self.bytes.len() - start;
+        let size = backlen_size(l);
+        for i in (0..size).rev() {
+            let chunk = ((l >> (7 * i)) & 127) as u8;
+            if i == size - 1 {
+                self.bytes.put_u8(chunk);
+            } else {
+                self.bytes.put_u8(chunk | 128);
+            }
+        }
+        self.count += 1;
+    }
+}
+
+impl Default for ListpackWriter {
+    fn default() -> Self {
+        ListpackWriter::new()
+    }
+}
+
+/// Number of bytes used by the back length of an element
+/// whose encoding and data take `l` bytes
+fn backlen_size(l: usize) -> usize {
+    match l {
+        0..=127 => 1,
+        128..16383 => 2,
+        16383..2097151 => 3,
+        2097151..268435455 => 4,
+        _ => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &'static str) -> ListpackEntry {
+        ListpackEntry::String(Bytes::from_static(s.as_bytes()))
+    }
+
+    #[test]
+    fn decode_small_hash() {
+        // HSET h a 1 as redis 7 encodes it, "1" is stored as a 7 bit integer
+        let lp = b"\x0c\x00\x00\x00\x02\x00\x81a\x02\x01\x01\xff";
+        assert_eq!(
+            decode(lp).unwrap(),
+            vec![string("a"), ListpackEntry::Integer(1)]
+        );
+    }
+
+    #[test]
+    fn decode_every_encoding() {
+        let long = "x".repeat(100);
+        let mut lp = vec![0; LP_HEADER_SIZE];
+        // 13 bit integer -1
+        lp.extend_from_slice(b"\xdf\xff\x02");
+        // 16 bit integer 10000
+        lp.extend_from_slice(b"\xf1\x10\x27\x03");
+        // 24 bit integer -100000
+        lp.extend_from_slice(b"\xf2\x60\x79\xfe\x04");
+        // 32 bit integer 100000000
+        lp.extend_from_slice(b"\xf3\x00\xe1\xf5\x05\x05");
+        // 64 bit integer
+        lp.extend_from_slice(b"\xf4\x00\x10\xa5\xd4\xe8\x00\x00\x00\x09");
+        // 12 bit length string
+        lp.extend_from_slice(b"\xe0\x64");
+        lp.extend_from_slice(long.as_bytes());
+        lp.extend_from_slice(b"\x66");
+        lp.push(LP_EOF);
+        assert_eq!(
+            decode(&lp).unwrap(),
+            vec![
+                ListpackEntry::Integer(-1),
+                ListpackEntry::Integer(10000),
+                ListpackEntry::Integer(-100000),
+                ListpackEntry::Integer(100000000),
+                ListpackEntry::Integer(1000000000000),
+                ListpackEntry::String(long.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        let ints = [
+            0,
+            127,
+            128,
+            -1,
+            -4096,
+            4095,
+            4096,
+            -32768,
+            1 << 23,
+            -(1 << 31),
+            i64::MIN,
+        ];
+        let strings: [&[u8]; 4] = [b"", b"a", &[b'y'; 64], &[b'z'; 5000]];
+        let mut writer = ListpackWriter::new();
+        ints.iter().for_each(|&v| writer.push_int(v));
+        strings.iter().for_each(|v| writer.push_str(v));
+        let lp = writer.finish();
+
+        let total = u32::from_le_bytes(lp[0..4].try_into().unwrap()) as usize;
+        assert_eq!(total, lp.len());
+        let mut expected: Vec<_> = ints.into_iter().map(ListpackEntry::Integer).collect();
+        expected.extend(strings.map(|v| ListpackEntry::String(Bytes::copy_from_slice(v))));
+        assert_eq!(decode(&lp).unwrap(), expected);
+    }
+
+    #[test]
+    fn decode_truncated() {
+        assert!(decode(b"\x0c\x00\x00\x00\x02\x00\x81a\x02\x01").is_err());
+        assert!(decode(b"\x0c\x00\x00\x00\x01\x00\x85ab\x02\xff").is_err());
+    }
+}
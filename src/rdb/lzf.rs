@@ -0,0 +1,90 @@
+use crate::Error;
+
+/// Decompresses an LZF compressed buffer whose uncompressed size is known.
+///
+/// The input is a sequence of chunks each starting with a control byte,
+/// `000LLLLL` is a run of `L + 1` literal bytes and `LLLooooo` is a back
+/// reference of `L + 2` bytes (with an extra length byte when `L == 7`)
+/// starting `(ooooo << 8) + next byte + 1` bytes behind the output position.
+pub fn decompress(input: &[u8], out_len: usize) -> Result<Vec<u8>, Error> {
+    let err = || -> Error { "invalid LZF compressed string".into() };
+    // three bytes of input expand to 264 bytes at most, the length comes from
+    // the file and isn't trusted any further
+    if out_len > input.len().saturating_mul(88) {
+        return Err(err());
+    }
+    let mut out = Vec::with_capacity(out_len);
+    let mut ip = 0;
+
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+
+        if ctrl < 1 << 5 {
+            let len = ctrl + 1;
+            let literal = input.get(ip..ip + len).ok_or_else(err)?;
+            out.extend_from_slice(literal);
+            ip += len;
+        } else {
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(ip).ok_or_else(err)? as usize;
+                ip += 1;
+            }
+            len += 2;
+            let offset = ((ctrl & 0x1F) << 8) + *input.get(ip).ok_or_else(err)? as usize + 1;
+            ip += 1;
+            let start = out.len().checked_sub(offset).ok_or_else(err)?;
+            // the reference may overlap with the bytes being written
+            for i in start..start + len {
+                out.push(out[i]);
+            }
+        }
+
+        if out.len() > out_len {
+            return Err(err());
+        }
+    }
+
+    if out.len() != out_len {
+        return Err(err());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompress;
+
+    #[test]
+    fn overlapping_reference() {
+        // 32 times "a" as liblzf compresses it: two literals and a
+        // reference one byte behind that repeats them, then two more literals
+        let compressed = b"\x01aa\xe0\x13\x00\x01aa";
+        assert_eq!(decompress(compressed, 32).unwrap(), vec![b'a'; 32]);
+    }
+
+    #[test]
+    fn short_reference() {
+        let compressed = b"\x03abca\x80\x02\x01bc";
+        assert_eq!(decompress(compressed, 12).unwrap(), b"abcabcabcabc");
+    }
+
+    #[test]
+    fn literals_only() {
+        assert_eq!(decompress(b"\x04hello", 5).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn invalid_input() {
+        // wrong uncompressed length
+        assert!(decompress(b"\x04hello", 6).is_err());
+        assert!(decompress(b"\x04hello", 4).is_err());
+        // reference before the start of the output
+        assert!(decompress(b"\x00a\x20\x05", 4).is_err());
+        // truncated literal run
+        assert!(decompress(b"\x04hel", 5).is_err());
+        // a length that the input could never expand to
+        assert!(decompress(b"\x01aa\xe0\x13\x00\x01aa", usize::MAX).is_err());
+    }
+}
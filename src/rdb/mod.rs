@@ -1,12 +1,16 @@
-use crate::store::Value;
-use std::collections::HashMap;
+use crate::store::Database;
+use std::collections::{BTreeMap, HashMap};
 
+mod crc64;
 pub mod decode;
+pub mod encode;
+mod listpack;
+mod lzf;
+mod ziplist;
 
 #[derive(Default)]
 pub struct RDB {
     pub header: String,
     pub metadata: HashMap<String, String>,
-    pub database: HashMap<String, Value>,
-    pub expiry_time: HashMap<String, std::time::SystemTime>,
+    pub databases: BTreeMap<usize, Database>,
 }
@@ -0,0 +1,194 @@
+use super::listpack::{ListpackEntry, int_le};
+use crate::Error;
+use bytes::Bytes;
+
+const ZIPLIST_HEADER_SIZE: usize = 10;
+const ZIP_END: u8 = 0xFF;
+
+fn corrupt() -> Error {
+    "corrupt ziplist".into()
+}
+
+fn take(data: &[u8], pos: usize, n: usize) -> Result<&[u8], Error> {
+    data.get(pos..pos + n).ok_or_else(corrupt)
+}
+
+/// Decodes all the entries of a ziplist
+/// ```
+/// <zlbytes u32> <zltail u32> <zllen u16> <entry ...> <0xFF>
+/// ```
+/// where every entry is `<prevlen> <encoding> <entry-data>`
+pub fn decode_ziplist(zl: &[u8]) -> Result<Vec<ListpackEntry>, Error> {
+    let mut res = vec![];
+    let mut pos = ZIPLIST_HEADER_SIZE;
+    loop {
+        let prevlen = take(zl, pos, 1)?[0];
+        if prevlen == ZIP_END {
+            break;
+        }
+        pos += if prevlen == 0xFE { 5 } else { 1 };
+
+        let encoding = take(zl, pos, 1)?[0];
+        pos += 1;
+        let entry = match encoding >> 6 {
+            0b00 => {
+                let len = (encoding & 0x3F) as usize;
+                pos += len;
+                ListpackEntry::String(Bytes::copy_from_slice(take(zl, pos - len, len)?))
+            }
+            0b01 => {
+                let len = ((encoding & 0x3F) as usize) << 8 | take(zl, pos, 1)?[0] as usize;
+                pos += 1 + len;
+                ListpackEntry::String(Bytes::copy_from_slice(take(zl, pos - len, len)?))
+            }
+            0b10 => {
+                let b = take(zl, pos, 4)?;
+                let len = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+                pos += 4 + len;
+                ListpackEntry::String(Bytes::copy_from_slice(take(zl, pos - len, len)?))
+            }
+            _ => {
+                let width = match encoding {
+                    0xC0 => 2,
+                    0xD0 => 4,
+                    0xE0 => 8,
+                    0xF0 => 3,
+                    0xFE => 1,
+                    // 4 bit immediate integer between 0 and 12
+                    0xF1..=0xFD => {
+                        res.push(ListpackEntry::Integer((encoding & 0x0F) as i64 - 1));
+                        continue;
+                    }
+                    _ => return Err(corrupt()),
+                };
+                pos += width;
+                ListpackEntry::Integer(int_le(take(zl, pos - width, width)?))
+            }
+        };
+        res.push(entry);
+    }
+    Ok(res)
+}
+
+/// Decodes an intset, a sorted array of integers all having the same width
+/// ```
+/// <encoding u32> <length u32> <contents>
+/// ```
+pub fn decode_intset(is: &[u8]) -> Result<Vec<i64>, Error> {
+    let header = take(is, 0, 8)?;
+    let width = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err("corrupt intset".into());
+    }
+    let contents = take(is, 8, width * len)?;
+    Ok(contents.chunks(width).map(int_le).collect())
+}
+
+/// Decodes a zipmap, the encoding used for small hashes before redis 2.6
+/// ```
+/// <zmlen> <len>"key"<len><free>"value"<free bytes> ... <0xFF>
+/// ```
+pub fn decode_zipmap(zm: &[u8]) -> Result<Vec<(Bytes, Bytes)>, Error> {
+    let length = |pos: &mut usize| -> Result<usize, Error> {
+        let first = take(zm, *pos, 1)?[0];
+        if first < 254 {
+            *pos += 1;
+            Ok(first as usize)
+        } else {
+            let b = take(zm, *pos + 1, 4)?;
+            *pos += 5;
+            Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        }
+    };
+
+    let mut res = vec![];
+    let mut pos = 1;
+    while take(zm, pos, 1)?[0] != ZIP_END {
+        let len = length(&mut pos)?;
+        let key = Bytes::copy_from_slice(take(zm, pos, len)?);
+        pos += len;
+        let len = length(&mut pos)?;
+        let free = take(zm, pos, 1)?[0] as usize;
+        pos += 1;
+        let value = Bytes::copy_from_slice(take(zm, pos, len)?);
+        pos += len + free;
+        res.push((key, value));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_immediate_integers() {
+        // the ziplist holding "2" and "5" from the description in redis' ziplist.c
+        let zl = b"\x0f\x00\x00\x00\x0c\x00\x00\x00\x02\x00\x00\xf3\x02\xf6\xff";
+        let entries = decode_ziplist(zl).unwrap();
+        assert_eq!(
+            entries,
+            vec![ListpackEntry::Integer(2), ListpackEntry::Integer(5)]
+        );
+    }
+
+    #[test]
+    fn decode_string() {
+        // the same ziplist after pushing "Hello World", as in ziplist.c
+        let zl = b"\x1c\x00\x00\x00\x0e\x00\x00\x00\x03\x00\x00\xf3\x02\xf6\x02\x0bHello World\xff";
+        let entries = decode_ziplist(zl).unwrap();
+        assert_eq!(entries[2], ListpackEntry::String("Hello World".into()));
+    }
+
+    #[test]
+    fn decode_int16() {
+        // the list [1, 2, 3] of the RESTORE example in the redis documentation
+        let zl = b"\x17\x00\x00\x00\x12\x00\x00\x00\x03\x00\x00\xc0\x01\x00\x04\xc0\x02\x00\x04\xc0\x03\x00\xff";
+        let entries = decode_ziplist(zl).unwrap();
+        assert_eq!(
+            entries,
+            (1..=3).map(ListpackEntry::Integer).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn decode_truncated_ziplist() {
+        assert!(decode_ziplist(b"\x0f\x00\x00\x00\x0c\x00\x00\x00\x02\x00\x00\xf3").is_err());
+        assert!(decode_ziplist(b"\x0f\x00\x00\x00\x0c\x00\x00\x00\x01\x00\x00\x05ab\xff").is_err());
+    }
+
+    #[test]
+    fn decode_intsets() {
+        let is = b"\x02\x00\x00\x00\x03\x00\x00\x00\x01\x00\x02\x00\x03\x00";
+        assert_eq!(decode_intset(is).unwrap(), vec![1, 2, 3]);
+        let is = b"\x04\x00\x00\x00\x02\x00\x00\x00\xff\xff\xff\xff\x00\x00\x01\x00";
+        assert_eq!(decode_intset(is).unwrap(), vec![-1, 65536]);
+        let is = b"\x08\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x80";
+        assert_eq!(decode_intset(is).unwrap(), vec![i64::MIN]);
+    }
+
+    #[test]
+    fn decode_corrupt_intsets() {
+        assert!(decode_intset(b"\x03\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00").is_err());
+        assert!(decode_intset(b"\x02\x00\x00\x00\xff\xff\xff\xff\x01\x00").is_err());
+    }
+
+    #[test]
+    fn decode_zipmaps() {
+        // "foo" => "bar", "hello" => "world" from the description in redis' zipmap.c
+        let zm = b"\x02\x03foo\x03\x00bar\x05hello\x05\x00world\xff";
+        let entries = decode_zipmap(zm).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("foo".into(), "bar".into()),
+                ("hello".into(), "world".into())
+            ]
+        );
+        // free bytes after a value that shrank are skipped
+        let zm = b"\x01\x01k\x01\x02vxx\xff";
+        assert_eq!(decode_zipmap(zm).unwrap(), vec![("k".into(), "v".into())]);
+        assert!(decode_zipmap(b"\x01\x01k\x05\x00v\xff").is_err());
+    }
+}
@@ -0,0 +1,780 @@
+use crate::Error;
+use crate::frame::Frame;
+use crate::link::Link;
+use crate::store::Store;
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const PERIOD: Duration = Duration::from_secs(1);
+const HELLO_PERIOD: Duration = Duration::from_secs(2);
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+/// how long a replica has to claim to be a master before we turn it back into a
+/// replica, long enough for the hello messages of a failover to reach us first
+const ROLE_GRACE: Duration = Duration::from_secs(8);
+
+pub type Addr = (String, u16);
+
+/// State of a node running in sentinel mode
+pub struct Sentinel {
+    pub myid: String,
+    pub current_epoch: u64,
+    pub masters: BTreeMap<String, Master>,
+}
+
+/// A master watched by the sentinel along with what is known about
+/// its replicas and the other sentinels watching it
+pub struct Master {
+    pub name: String,
+    pub addr: Addr,
+    pub quorum: usize,
+    pub down_after: Duration,
+    pub failover_timeout: Duration,
+    pub config_epoch: u64,
+    /// last time the master replied to a PING
+    pub last_ok: Instant,
+    /// subjectively down, as seen by this sentinel alone
+    pub s_down: bool,
+    /// objectively down, enough sentinels agree on it
+    pub o_down: bool,
+    pub replicas: BTreeMap<Addr, Replica>,
+    /// the other sentinels by their id
+    pub sentinels: HashMap<String, Peer>,
+    /// the sentinel we voted for to lead the failover of `leader_epoch`
+    pub leader: String,
+    pub leader_epoch: u64,
+    /// when we last tried to fail over this master
+    pub failover_start: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct Replica {
+    pub last_ok: Option<Instant>,
+    pub offset: usize,
+    pub link_up: bool,
+    /// since when the replica claims to be a master
+    pub master_since: Option<Instant>,
+}
+
+pub struct Peer {
+    pub addr: Addr,
+    pub last_hello: Instant,
+}
+
+impl Sentinel {
+    pub fn new() -> Self {
+        Sentinel {
+            myid: hex::encode(rand::random::<[u8; 20]>()),
+            current_epoch: 0,
+            masters: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a master from a `<name> <host> <port> <quorum>` description
+    pub async fn monitor(
+        &mut self,
+        description: &str,
+        down_after: Duration,
+        failover_timeout: Duration,
+    ) -> Result<(), Error> {
+        let parts: Vec<_> = description.split_whitespace().collect();
+        let [name, host, port, quorum] = parts[..] else {
+            return Err("sentinel monitor expects <name> <host> <port> <quorum>".into());
+        };
+        let port: u16 = port.parse()?;
+        // instances are known by their ip, just like the replicas report them
+        let ip = tokio::net::lookup_host((host, port))
+            .await?
+            .find(|v| v.is_ipv4())
+            .ok_or("can't resolve the address of the master")?
+            .ip()
+            .to_string();
+        let master = Master {
+            name: name.to_string(),
+            addr: (ip, port),
+            quorum: quorum.parse()?,
+            down_after,
+            failover_timeout,
+            config_epoch: 0,
+            last_ok: Instant::now(),
+            s_down: false,
+            o_down: false,
+            replicas: BTreeMap::new(),
+            sentinels: HashMap::new(),
+            leader: String::new(),
+            leader_epoch: 0,
+            failover_start: None,
+        };
+        self.masters.insert(name.to_string(), master);
+        Ok(())
+    }
+
+    pub fn master_by_addr(&mut self, addr: &Addr) -> Option<&mut Master> {
+        self.masters.values_mut().find(|v| &v.addr == addr)
+    }
+
+    /// Votes for `runid` to lead the failover of `epoch`, unless we already voted
+    /// for someone in that epoch, and returns the leader we voted for
+    pub fn vote(&mut self, addr: &Addr, epoch: u64, runid: &str) -> Option<(String, u64)> {
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            println!("+new-epoch {epoch}");
+        }
+        let master = self.master_by_addr(addr)?;
+        if master.leader_epoch < epoch {
+            master.leader = runid.to_string();
+            master.leader_epoch = epoch;
+            println!("+vote-for-leader {runid} {epoch}");
+        }
+        Some((master.leader.clone(), master.leader_epoch))
+    }
+}
+
+impl Master {
+    fn instances(&self) -> Vec<Addr> {
+        let mut res = vec![self.addr.clone()];
+        res.extend(self.replicas.keys().cloned());
+        res
+    }
+
+    fn event(&self, event: &str) {
+        let (ip, port) = &self.addr;
+        println!("{event} master {} {ip} {port}", self.name);
+    }
+
+    /// The votes needed to lead its failover: a majority of all the sentinels,
+    /// counting this one, and at least the quorum
+    fn votes_needed(&self) -> usize {
+        self.quorum.max(self.sentinels.len().div_ceil(2) + 1)
+    }
+
+    /// Makes `addr` the master, the old master is kept as a replica
+    fn switch(&mut self, addr: Addr, config_epoch: u64) {
+        let (old_ip, old_port) = &self.addr;
+        let (ip, port) = &addr;
+        println!(
+            "+switch-master {} {old_ip} {old_port} {ip} {port}",
+            self.name
+        );
+        let old = std::mem::replace(&mut self.addr, addr);
+        self.replicas.remove(&self.addr);
+        self.replicas.insert(old, Replica::default());
+        self.config_epoch = config_epoch;
+        self.last_ok = Instant::now();
+        self.s_down = false;
+        self.o_down = false;
+        self.failover_start = None;
+    }
+}
+
+/// Starts watching every master the sentinel was configured with
+pub async fn start(store: Arc<Mutex<Store>>) {
+    let names: Vec<_> = match store.lock().await.sentinel.as_ref() {
+        Some(sentinel) => sentinel.masters.keys().cloned().collect(),
+        None => return,
+    };
+    for name in names {
+        tokio::spawn(watch(store.clone(), name));
+    }
+}
+
+/// The connections of a sentinel, opened on demand and dropped on errors
+#[derive(Default)]
+struct Links(HashMap<Addr, Link>);
+
+impl Links {
+    async fn call(&mut self, addr: &Addr, args: &[&str]) -> Result<Frame, Error> {
+        if !self.0.contains_key(addr) {
+            self.0.insert(addr.clone(), Link::connect(addr).await?);
+        }
+        let link = self.0.get_mut(addr).ok_or("no link")?;
+        let reply = link.call(args).await;
+        if reply.is_err() {
+            self.0.remove(addr);
+        }
+        reply
+    }
+
+    async fn info(&mut self, addr: &Addr) -> Result<HashMap<String, String>, Error> {
+        let reply = self.call(addr, &["INFO", "replication"]).await?;
+        let text = reply.string().ok_or("invalid INFO reply")?;
+        Ok(text
+            .lines()
+            .filter_map(|v| v.split_once(':'))
+            .map(|(k, v)| (k.to_string(), v.trim().to_string()))
+            .collect())
+    }
+}
+
+/// Watches a master along with its replicas: pings them, discovers the replicas and
+/// the other sentinels, agrees with them on the master being down and fails it over
+async fn watch(store: Arc<Mutex<Store>>, name: String) {
+    let mut links = Links::default();
+    let mut listening = HashSet::new();
+    let mut last_hello = None::<Instant>;
+    let mut interval = tokio::time::interval(PERIOD);
+    loop {
+        interval.tick().await;
+        let Some(instances) = with_master(&store, &name, |_, m| m.instances()).await else {
+            return;
+        };
+        for addr in instances {
+            if listening.insert(addr.clone()) {
+                tokio::spawn(listen_hello(store.clone(), name.clone(), addr.clone()));
+            }
+            refresh(&store, &name, &mut links, &addr).await;
+        }
+        if last_hello.is_none_or(|v| v.elapsed() >= HELLO_PERIOD) {
+            send_hello(&store, &name, &mut links).await;
+            last_hello = Some(Instant::now());
+        }
+        check_down(&store, &name, &mut links).await;
+        if let Some(epoch) = try_failover(&store, &name, &mut links).await
+            && failover(&store, &name, &mut links, epoch).await
+        {
+            send_hello(&store, &name, &mut links).await;
+        }
+    }
+}
+
+async fn with_master<T>(
+    store: &Mutex<Store>,
+    name: &str,
+    f: impl FnOnce(&mut Sentinel, &mut Master) -> T,
+) -> Option<T> {
+    let mut store = store.lock().await;
+    let sentinel = store.sentinel.as_mut()?;
+    let mut master = sentinel.masters.remove(name)?;
+    let res = f(sentinel, &mut master);
+    sentinel.masters.insert(name.to_string(), master);
+    Some(res)
+}
+
+/// Pings an instance and updates what we know about it from its INFO
+async fn refresh(store: &Mutex<Store>, name: &str, links: &mut Links, addr: &Addr) {
+    let pong = links.call(addr, &["PING"]).await.is_ok();
+    let info = match pong {
+        true => links.info(addr).await.ok(),
+        false => None,
+    };
+    let stale = with_master(store, name, |_, master| {
+        if &master.addr == addr {
+            if pong {
+                master.last_ok = Instant::now();
+            }
+            // the replicas as the master sees them
+            let replicas = info.iter().flat_map(|v| v.iter());
+            for (key, value) in replicas {
+                if let Some(addr) = parse_replica(key, value)
+                    && !master.replicas.contains_key(&addr)
+                {
+                    println!("+slave slave {}:{} @ {}", addr.0, addr.1, master.name);
+                    master.replicas.insert(addr, Replica::default());
+                }
+            }
+            return false;
+        }
+        let failing_over = master.failover_start.is_some() || master.s_down;
+        let Some(replica) = master.replicas.get_mut(addr) else {
+            return false;
+        };
+        let Some(info) = info else {
+            return false;
+        };
+        replica.last_ok = Some(Instant::now());
+        replica.link_up = info.get("master_link_status").is_some_and(|v| v == "up");
+        replica.offset = info
+            .get("slave_repl_offset")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+        if info.get("role").is_some_and(|v| v == "master") {
+            let since = *replica.master_since.get_or_insert_with(Instant::now);
+            // an old master that came back, or a failover we missed the news of
+            return !failing_over && since.elapsed() >= ROLE_GRACE;
+        }
+        replica.master_since = None;
+        false
+    })
+    .await;
+
+    if stale == Some(true)
+        && let Some(master) = with_master(store, name, |_, m| m.addr.clone()).await
+    {
+        println!("+convert-to-slave slave {}:{} @ {name}", addr.0, addr.1);
+        let port = master.1.to_string();
+        let _ = links.call(addr, &["REPLICAOF", &master.0, &port]).await;
+    }
+}
+
+/// Parses a `slaveN:ip=..,port=..,...` line of INFO replication
+fn parse_replica(key: &str, value: &str) -> Option<Addr> {
+    key.strip_prefix("slave")?.parse::<usize>().ok()?;
+    let fields: HashMap<_, _> = value.split(',').filter_map(|v| v.split_once('=')).collect();
+    Some((
+        fields.get("ip")?.to_string(),
+        fields.get("port")?.parse().ok()?,
+    ))
+}
+
+/// Announces ourselves, along with the configuration of the master, to every
+/// instance so that the other sentinels watching them can discover us
+async fn send_hello(store: &Mutex<Store>, name: &str, links: &mut Links) {
+    let Some((hello, instances)) = with_master(store, name, |sentinel, master| {
+        let (ip, port) = &master.addr;
+        let hello = format!(
+            "{},{},{},{ip},{port},{}",
+            sentinel.myid, sentinel.current_epoch, master.name, master.config_epoch
+        );
+        (hello, master.instances())
+    })
+    .await
+    else {
+        return;
+    };
+    let port = store.lock().await.info.listening_port;
+    for addr in instances {
+        // the address we announce is the one the instance sees us with
+        let Some(ip) = links.0.get(&addr).map(|v| v.local_ip.clone()) else {
+            continue;
+        };
+        let message = format!("{ip},{port},{hello}");
+        let _ = links
+            .call(&addr, &["PUBLISH", HELLO_CHANNEL, &message])
+            .await;
+    }
+}
+
+/// Listens to the hello messages published on an instance while it belongs to the master
+async fn listen_hello(store: Arc<Mutex<Store>>, name: String, addr: Addr) {
+    loop {
+        let watched = with_master(&store, &name, |_, m| m.instances().contains(&addr)).await;
+        if watched != Some(true) {
+            return;
+        }
+        if let Ok(mut link) = Link::connect(&addr).await
+            && link.send(&["SUBSCRIBE", HELLO_CHANNEL]).await.is_ok()
+        {
+            while let Ok(frame) = link.read().await {
+                let message = frame.array().and_then(|v| v.into_iter().nth(2)?.string());
+                if let Some(message) = message {
+                    receive_hello(&store, &message).await;
+                }
+            }
+        }
+        tokio::time::sleep(PERIOD).await;
+    }
+}
+
+/// `ip,port,runid,current_epoch,master_name,master_ip,master_port,master_config_epoch`
+async fn receive_hello(store: &Mutex<Store>, message: &str) {
+    let parts: Vec<_> = message.split(',').collect();
+    let [
+        ip,
+        port,
+        runid,
+        epoch,
+        name,
+        master_ip,
+        master_port,
+        config_epoch,
+    ] = parts[..]
+    else {
+        return;
+    };
+    let (Ok(port), Ok(epoch), Ok(master_port), Ok(config_epoch)) = (
+        port.parse::<u16>(),
+        epoch.parse::<u64>(),
+        master_port.parse::<u16>(),
+        config_epoch.parse::<u64>(),
+    ) else {
+        return;
+    };
+    with_master(store, name, |sentinel, master| {
+        if runid == sentinel.myid {
+            return;
+        }
+        if !master.sentinels.contains_key(runid) {
+            println!("+sentinel sentinel {runid} {ip} {port} @ {name}");
+        }
+        let peer = Peer {
+            addr: (ip.to_string(), port),
+            last_hello: Instant::now(),
+        };
+        master.sentinels.insert(runid.to_string(), peer);
+        if epoch > sentinel.current_epoch {
+            sentinel.current_epoch = epoch;
+            println!("+new-epoch {epoch}");
+        }
+        // another sentinel failed the master over
+        let addr = (master_ip.to_string(), master_port);
+        if config_epoch > master.config_epoch && addr != master.addr {
+            master.switch(addr, config_epoch);
+        }
+    })
+    .await;
+}
+
+/// Flags the master as subjectively down when it doesn't reply in time and as
+/// objectively down when enough of the other sentinels agree with us
+async fn check_down(store: &Mutex<Store>, name: &str, links: &mut Links) {
+    let Some((s_down, addr, peers, quorum)) = with_master(store, name, |_, master| {
+        let s_down = master.last_ok.elapsed() > master.down_after;
+        if s_down != master.s_down {
+            master.s_down = s_down;
+            master.event(if s_down { "+sdown" } else { "-sdown" });
+        }
+        let peers: Vec<_> = master.sentinels.values().map(|v| v.addr.clone()).collect();
+        (s_down, master.addr.clone(), peers, master.quorum)
+    })
+    .await
+    else {
+        return;
+    };
+
+    let mut agree = 0;
+    if s_down {
+        agree += 1;
+        let port = addr.1.to_string();
+        for peer in peers {
+            let args = [
+                "SENTINEL",
+                "is-master-down-by-addr",
+                &addr.0,
+                &port,
+                "0",
+                "*",
+            ];
+            let down = links.call(&peer, &args).await.ok().and_then(|v| {
+                let reply = v.array()?;
+                reply.into_iter().next()?.int()
+            });
+            if down == Some(1) {
+                agree += 1;
+            }
+        }
+    }
+    with_master(store, name, |_, master| {
+        // another sentinel may have switched to a new master meanwhile
+        if master.addr != addr {
+            return;
+        }
+        let o_down = agree >= quorum;
+        if o_down != master.o_down {
+            master.o_down = o_down;
+            let event = if o_down { "+odown" } else { "-odown" };
+            println!(
+                "{event} master {name} {} {} #quorum {agree}/{quorum}",
+                addr.0, addr.1
+            );
+        }
+    })
+    .await;
+}
+
+/// Asks the other sentinels to elect us to fail the master over once it is objectively
+/// down, returns the epoch of the failover when we got the majority of the votes
+async fn try_failover(store: &Mutex<Store>, name: &str, links: &mut Links) -> Option<u64> {
+    let ready = with_master(store, name, |_, master| {
+        master.o_down
+            && master
+                .failover_start
+                .is_none_or(|v| v.elapsed() >= master.failover_timeout)
+    })
+    .await?;
+    if !ready {
+        return None;
+    }
+    // sentinels that noticed at the same time would split the votes otherwise
+    let delay = rand::rng().random_range(0..1000);
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+
+    let (epoch, myid, addr, peers, needed) = with_master(store, name, |sentinel, master| {
+        if !master.o_down
+            || master
+                .failover_start
+                .is_some_and(|v| v.elapsed() < master.failover_timeout)
+        {
+            return None;
+        }
+        sentinel.current_epoch += 1;
+        let epoch = sentinel.current_epoch;
+        println!("+new-epoch {epoch}");
+        master.event("+try-failover");
+        master.failover_start = Some(Instant::now());
+        master.leader = sentinel.myid.clone();
+        master.leader_epoch = epoch;
+        let peers: Vec<_> = master.sentinels.values().map(|v| v.addr.clone()).collect();
+        let needed = master.votes_needed();
+        Some((
+            epoch,
+            sentinel.myid.clone(),
+            master.addr.clone(),
+            peers,
+            needed,
+        ))
+    })
+    .await??;
+
+    let mut votes = 1;
+    let (port, epoch_arg) = (addr.1.to_string(), epoch.to_string());
+    for peer in peers {
+        let args = [
+            "SENTINEL",
+            "is-master-down-by-addr",
+            &addr.0,
+            &port,
+            &epoch_arg,
+            &myid,
+        ];
+        let vote = links.call(&peer, &args).await.ok().and_then(|v| {
+            let mut reply = v.array()?.into_iter().skip(1);
+            Some((reply.next()?.string()?, reply.next()?.int()?))
+        });
+        if vote == Some((myid.clone(), epoch as isize)) {
+            votes += 1;
+        }
+    }
+    if votes < needed {
+        println!(
+            "-failover-abort-not-elected master {name} {} {}",
+            addr.0, addr.1
+        );
+        return None;
+    }
+    println!("+elected-leader master {name} {} {}", addr.0, addr.1);
+    Some(epoch)
+}
+
+/// Promotes the best replica of the master and points the others to it
+async fn failover(store: &Mutex<Store>, name: &str, links: &mut Links, epoch: u64) -> bool {
+    let selected = with_master(store, name, |_, master| {
+        // the failover is over if another sentinel switched the master while we were voted
+        master.failover_start?;
+        let down_after = master.down_after;
+        master
+            .replicas
+            .iter()
+            .filter(|(_, v)| v.last_ok.is_some_and(|t| t.elapsed() <= down_after))
+            .max_by_key(|(_, v)| v.offset)
+            .map(|(addr, _)| addr.clone())
+    })
+    .await
+    .flatten();
+    let Some(selected) = selected else {
+        println!("-failover-abort-no-good-slave master {name}");
+        return false;
+    };
+    println!(
+        "+selected-slave slave {}:{} @ {name}",
+        selected.0, selected.1
+    );
+
+    if links
+        .call(&selected, &["REPLICAOF", "NO", "ONE"])
+        .await
+        .is_err()
+    {
+        println!("-failover-abort-slave-timeout master {name}");
+        return false;
+    }
+    // wait for the replica to report its new role
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let info = links.info(&selected).await.unwrap_or_default();
+        if info.get("role").is_some_and(|v| v == "master") {
+            break;
+        }
+        if Instant::now() >= deadline {
+            println!("-failover-abort-slave-timeout master {name}");
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    println!(
+        "+promoted-slave slave {}:{} @ {name}",
+        selected.0, selected.1
+    );
+
+    let replicas = with_master(store, name, |_, master| {
+        master.switch(selected.clone(), epoch);
+        master.replicas.keys().cloned().collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+    let port = selected.1.to_string();
+    for replica in replicas {
+        // the old master is turned into a replica once it comes back
+        if links
+            .call(&replica, &["REPLICAOF", &selected.0, &port])
+            .await
+            .is_ok()
+        {
+            println!(
+                "+slave-reconf-sent slave {}:{} @ {name}",
+                replica.0, replica.1
+            );
+        }
+    }
+    println!("+failover-end master {name}");
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> Addr {
+        ("127.0.0.1".to_string(), port)
+    }
+
+    fn master(port: u16, quorum: usize) -> Master {
+        Master {
+            name: "mymaster".to_string(),
+            addr: addr(port),
+            quorum,
+            down_after: Duration::from_secs(5),
+            failover_timeout: Duration::from_secs(60),
+            config_epoch: 0,
+            last_ok: Instant::now(),
+            s_down: false,
+            o_down: false,
+            replicas: BTreeMap::new(),
+            sentinels: HashMap::new(),
+            leader: String::new(),
+            leader_epoch: 0,
+            failover_start: None,
+        }
+    }
+
+    fn add_sentinels(master: &mut Master, count: u16) {
+        for i in 0..count {
+            let peer = Peer {
+                addr: addr(26380 + i),
+                last_hello: Instant::now(),
+            };
+            master.sentinels.insert(i.to_string(), peer);
+        }
+    }
+
+    fn store_watching(master: Master) -> Mutex<Store> {
+        let mut sentinel = Sentinel::new();
+        sentinel.masters.insert(master.name.clone(), master);
+        Mutex::new(Store {
+            sentinel: Some(sentinel),
+            ..Store::default()
+        })
+    }
+
+    #[test]
+    fn replica_lines() {
+        let line = "ip=127.0.0.1,port=6380,state=online,offset=14,lag=0";
+        assert_eq!(parse_replica("slave0", line), Some(addr(6380)));
+        assert_eq!(parse_replica("slave12", line), Some(addr(6380)));
+        assert_eq!(parse_replica("slaves", line), None);
+        assert_eq!(parse_replica("master_host", line), None);
+        assert_eq!(parse_replica("slave0", "ip=127.0.0.1,state=online"), None);
+        assert_eq!(parse_replica("slave0", "ip=127.0.0.1,port=x"), None);
+    }
+
+    #[test]
+    fn one_vote_per_epoch() {
+        let mut sentinel = Sentinel::new();
+        sentinel.masters.insert("mymaster".into(), master(6379, 2));
+        let leader = |id: &str, epoch| Some((id.to_string(), epoch));
+        assert_eq!(sentinel.vote(&addr(6379), 1, "a"), leader("a", 1));
+        assert_eq!(sentinel.vote(&addr(6379), 1, "b"), leader("a", 1));
+        assert_eq!(sentinel.vote(&addr(6379), 2, "b"), leader("b", 2));
+        // a request for an older epoch gets the vote of the newest one
+        assert_eq!(sentinel.vote(&addr(6379), 1, "c"), leader("b", 2));
+        assert_eq!(sentinel.current_epoch, 2);
+        // the epoch is still learnt from a request about a master we don't watch
+        assert_eq!(sentinel.vote(&addr(6390), 3, "c"), None);
+        assert_eq!(sentinel.current_epoch, 3);
+    }
+
+    #[test]
+    fn votes_needed() {
+        let needed = |quorum, sentinels| {
+            let mut master = master(6379, quorum);
+            add_sentinels(&mut master, sentinels);
+            master.votes_needed()
+        };
+        assert_eq!(needed(1, 0), 1);
+        assert_eq!(needed(2, 0), 2);
+        assert_eq!(needed(1, 2), 2);
+        assert_eq!(needed(2, 2), 2);
+        assert_eq!(needed(1, 3), 3);
+        assert_eq!(needed(1, 4), 3);
+        assert_eq!(needed(4, 4), 4);
+    }
+
+    #[test]
+    fn switch_keeps_the_old_master_as_replica() {
+        let mut master = master(6379, 2);
+        master.replicas.insert(addr(6380), Replica::default());
+        master.replicas.insert(addr(6381), Replica::default());
+        master.s_down = true;
+        master.o_down = true;
+        master.failover_start = Some(Instant::now());
+
+        master.switch(addr(6380), 5);
+        assert_eq!(master.addr, addr(6380));
+        let replicas: Vec<_> = master.replicas.keys().cloned().collect();
+        assert_eq!(replicas, [addr(6379), addr(6381)]);
+        assert_eq!(master.config_epoch, 5);
+        assert!(!master.s_down && !master.o_down);
+        assert!(master.failover_start.is_none());
+    }
+
+    #[tokio::test]
+    async fn hello_messages() {
+        let store = store_watching(master(6379, 2));
+        let myid = store.lock().await.sentinel.as_ref().unwrap().myid.clone();
+        let runid = "b".repeat(40);
+
+        // malformed ones, our own and the ones about other masters are ignored
+        for message in [
+            format!("10.0.0.2,26380,{runid},3,mymaster,127.0.0.1,6380"),
+            format!("10.0.0.2,port,{runid},3,mymaster,127.0.0.1,6380,2"),
+            format!("10.0.0.2,26380,{runid},3,other,127.0.0.1,6380,2"),
+            format!("10.0.0.2,26380,{myid},3,mymaster,127.0.0.1,6380,2"),
+        ] {
+            receive_hello(&store, &message).await;
+        }
+        {
+            let store = store.lock().await;
+            let sentinel = store.sentinel.as_ref().unwrap();
+            assert_eq!(sentinel.current_epoch, 0);
+            assert!(sentinel.masters["mymaster"].sentinels.is_empty());
+            assert_eq!(sentinel.masters["mymaster"].addr, addr(6379));
+        }
+
+        // a newer configuration of the master is adopted
+        let hello = format!("10.0.0.2,26380,{runid},3,mymaster,127.0.0.1,6380,2");
+        receive_hello(&store, &hello).await;
+        {
+            let store = store.lock().await;
+            let sentinel = store.sentinel.as_ref().unwrap();
+            let master = &sentinel.masters["mymaster"];
+            assert_eq!(sentinel.current_epoch, 3);
+            assert_eq!(
+                master.sentinels[&runid].addr,
+                ("10.0.0.2".to_string(), 26380)
+            );
+            assert_eq!((master.addr.clone(), master.config_epoch), (addr(6380), 2));
+        }
+
+        // but not an older one
+        let hello = format!("10.0.0.3,26381,{runid},3,mymaster,127.0.0.1,6379,1");
+        receive_hello(&store, &hello).await;
+        let store = store.lock().await;
+        let master = &store.sentinel.as_ref().unwrap().masters["mymaster"];
+        assert_eq!(master.addr, addr(6380));
+        assert_eq!(
+            master.sentinels[&runid].addr,
+            ("10.0.0.3".to_string(), 26381)
+        );
+    }
+}
@@ -33,7 +33,7 @@ impl Server {
     /// ```
     pub async fn acl_get_user(&mut self, mut args: Args) -> Result {
         let username = args.pop_front().ok_or(wrong_num_arguments("getuser"))?;
-        if let Some(user) = self.store.lock().await.users.get(&username) {
+        if let Some(user) = self.store.lock().await.users.get(&username.to_string()) {
             let mut res: Vec<Frame> = vec![];
             let mut keys = user.keys().collect::<Vec<&String>>();
             keys.sort();
@@ -60,16 +60,16 @@ impl Server {
     /// ```
     pub async fn acl_set_user(&mut self, mut args: Args) -> Result {
         let username = args.pop_front().ok_or(wrong_num_arguments("getuser"))?;
+        let username = username.to_string();
         let mut store = self.store.lock().await;
         let user = store.users.entry(username).or_insert(HashMap::from([
             ("flags".into(), vec!["nopass".into()]),
             ("passwords".into(), vec![]),
         ]));
         for arg in args {
-            if arg.starts_with(">") {
+            if arg.starts_with(b">") {
                 let mut hasher = Sha256::default();
-                let password = arg[1..].to_string();
-                hasher.update(password.as_bytes());
+                hasher.update(&arg[1..]);
                 let x = hasher.finalize_fixed();
                 user.get_mut(&"passwords".to_string())
                     .unwrap()
@@ -91,10 +91,11 @@ impl Server {
     /// ```
     pub async fn auth(&mut self, mut args: Args) -> Result {
         let username = args.pop_front().ok_or(wrong_num_arguments("getuser"))?;
+        let username = username.to_string();
         if let Some(user) = self.store.lock().await.users.get(&username) {
             let mut hasher = Sha256::default();
             let password = args.pop_front().ok_or(wrong_num_arguments("getuser"))?;
-            hasher.update(password.as_bytes());
+            hasher.update(&password);
             let x = hasher.finalize_fixed();
             let passwords = user.get("passwords").unwrap();
             if passwords.contains(&format!("{:x}", x)) {
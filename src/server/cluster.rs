@@ -0,0 +1,672 @@
+use super::command;
+use super::errors::*;
+use super::server::Server;
+use super::{Args, Result};
+use crate::Error;
+use crate::bytestring::ByteString;
+use crate::cluster::{self, Cluster, SLOTS};
+use crate::frame::{Frame, TypedNone};
+use crate::link::Link;
+use crate::rdb::decode::RDBParser;
+use crate::rdb::encode::RDBEncoder;
+use crate::slave;
+use crate::store::{Database, Value};
+use bytes::Bytes;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+impl Server {
+    /// Inspects and changes the configuration of a node running in cluster mode.
+    ///
+    /// Slots are assigned with ADDSLOTS or ADDSLOTSRANGE and nodes join the
+    /// cluster with MEET, the rest of the nodes learn about both over the bus.
+    /// A slot is moved by marking it IMPORTING on the target and MIGRATING on
+    /// the source, moving its keys with MIGRATE and then assigning it with NODE.
+    /// An empty node becomes the replica of a master with REPLICATE, it takes
+    /// over the slots of its master when the master fails.
+    /// ```
+    /// CLUSTER KEYSLOT key
+    /// CLUSTER MYID
+    /// CLUSTER INFO
+    /// CLUSTER NODES
+    /// CLUSTER SLOTS
+    /// CLUSTER SHARDS
+    /// CLUSTER ADDSLOTS slot [slot ...]
+    /// CLUSTER ADDSLOTSRANGE start-slot end-slot [start-slot end-slot ...]
+    /// CLUSTER MEET ip port [cluster-bus-port]
+    /// CLUSTER SETSLOT slot <IMPORTING node-id | MIGRATING node-id | NODE node-id | STABLE>
+    /// CLUSTER GETKEYSINSLOT slot count
+    /// CLUSTER COUNTKEYSINSLOT slot
+    /// CLUSTER REPLICATE node-id
+    /// CLUSTER REPLICAS | SLAVES node-id
+    /// ```
+    pub async fn cluster(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("cluster");
+        let subcommand = args.pop_front().ok_or(err())?.to_lowercase();
+        let mut guard = self.store.lock().await;
+        let store = &mut *guard;
+        let db = &mut store.databases[self.db];
+        let cluster = store.cluster.as_mut().ok_or(cluster_disabled())?;
+        let mut follow = None;
+        let res: Frame = match subcommand.as_str() {
+            "keyslot" => {
+                let key = args.pop_front().ok_or(err())?;
+                (cluster::key_slot(&key) as usize).into()
+            }
+            "myid" => cluster.myself.clone().into(),
+            "info" => Frame::BulkString(info(cluster).into()),
+            "nodes" => {
+                let nodes = cluster
+                    .nodes
+                    .values()
+                    .map(|v| cluster.describe(v, true) + "\n");
+                Frame::BulkString(nodes.collect::<String>().into())
+            }
+            "slots" => slots(cluster),
+            "shards" => shards(cluster),
+            "addslots" | "addslotsrange" => {
+                let slots: Vec<u16> = args
+                    .iter()
+                    .map(parse_slot)
+                    .collect::<std::result::Result<_, _>>()?;
+                let slots = if subcommand == "addslots" {
+                    slots
+                } else {
+                    if !slots.len().is_multiple_of(2) {
+                        return Err(err().into());
+                    }
+                    if let Some(v) = slots.chunks(2).find(|v| v[0] > v[1]) {
+                        return Err(make_io_error(&format!(
+                            "ERR start slot number {} is greater than end slot number {}",
+                            v[0], v[1]
+                        ))
+                        .into());
+                    }
+                    slots.chunks(2).flat_map(|v| v[0]..=v[1]).collect()
+                };
+                if slots.is_empty() {
+                    return Err(err().into());
+                }
+                for (idx, slot) in slots.iter().enumerate() {
+                    if cluster.slots[*slot as usize].is_some() {
+                        return Err(
+                            make_io_error(&format!("ERR Slot {slot} is already busy")).into()
+                        );
+                    }
+                    if slots[..idx].contains(slot) {
+                        return Err(make_io_error(&format!(
+                            "ERR Slot {slot} specified multiple times"
+                        ))
+                        .into());
+                    }
+                }
+                for slot in slots {
+                    cluster.slots[slot as usize] = Some(cluster.myself.clone());
+                }
+                "OK".into()
+            }
+            "meet" => {
+                let ip = args.pop_front().ok_or(err())?;
+                let port: u16 = args
+                    .pop_front()
+                    .ok_or(err())?
+                    .parse()
+                    .map_err(|_| make_io_error("ERR Invalid node address specified"))?;
+                let cport = match args.pop_front() {
+                    Some(v) => v.parse().ok(),
+                    None => port.checked_add(cluster::BUS_PORT_OFFSET),
+                }
+                .ok_or(make_io_error("ERR Invalid node address specified"))?;
+                cluster::meet(self.store.clone(), (ip.to_string(), cport));
+                "OK".into()
+            }
+            "setslot" => {
+                let slot = parse_slot(&args.pop_front().ok_or(err())?)?;
+                let action = args.pop_front().ok_or(err())?.to_lowercase();
+                let owned = cluster.slots[slot as usize].as_ref() == Some(&cluster.myself);
+                match action.as_str() {
+                    "migrating" => {
+                        let id = known_node(cluster, args.pop_front())?;
+                        if !owned {
+                            return Err(make_io_error(&format!(
+                                "ERR I'm not the owner of hash slot {slot}"
+                            ))
+                            .into());
+                        }
+                        cluster.migrating.insert(slot, id);
+                    }
+                    "importing" => {
+                        let id = known_node(cluster, args.pop_front())?;
+                        if owned {
+                            return Err(make_io_error(&format!(
+                                "ERR I'm already the owner of hash slot {slot}"
+                            ))
+                            .into());
+                        }
+                        cluster.importing.insert(slot, id);
+                    }
+                    "stable" => {
+                        cluster.migrating.remove(&slot);
+                        cluster.importing.remove(&slot);
+                    }
+                    "node" => {
+                        let id = known_node(cluster, args.pop_front())?;
+                        let myself = id == cluster.myself;
+                        if owned && !myself && db.keys_in_slot(slot).next().is_some() {
+                            return Err(make_io_error(&format!(
+                                "ERR Can't assign hashslot {slot} to a different node while I still hold keys for this hash slot."
+                            ))
+                            .into());
+                        }
+                        if !myself {
+                            cluster.migrating.remove(&slot);
+                        }
+                        // the end of an import, the new owner has to win against the old one
+                        if myself && cluster.importing.remove(&slot).is_some() {
+                            cluster.bump_epoch();
+                        }
+                        cluster.slots[slot as usize] = Some(id);
+                    }
+                    _ => {
+                        return Err(make_io_error(
+                            "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP",
+                        )
+                        .into());
+                    }
+                }
+                "OK".into()
+            }
+            "getkeysinslot" => {
+                let slot = parse_slot(&args.pop_front().ok_or(err())?)?;
+                let count: usize = args
+                    .pop_front()
+                    .ok_or(err())?
+                    .parse()
+                    .map_err(|_| make_io_error("ERR Invalid number of keys"))?;
+                let keys: Vec<ByteString> = db.keys_in_slot(slot).take(count).cloned().collect();
+                keys.into()
+            }
+            "countkeysinslot" => {
+                let slot = parse_slot(&args.pop_front().ok_or(err())?)?;
+                db.keys_in_slot(slot).count().into()
+            }
+            "replicate" => {
+                let id = args.pop_front().ok_or(err())?.to_string();
+                let Some(master) = cluster.nodes.get(&id) else {
+                    return Err(make_io_error(&format!("ERR Unknown node {id}")).into());
+                };
+                if id == cluster.myself {
+                    return Err(make_io_error("ERR Can't replicate myself").into());
+                }
+                if master.master.is_some() {
+                    return Err(
+                        make_io_error("ERR I can only replicate a master, not a replica.").into(),
+                    );
+                }
+                let myself = cluster.myself();
+                if myself.master.is_none()
+                    && (!cluster.slot_ranges(&myself.id).is_empty() || !db.kv.is_empty())
+                {
+                    return Err(make_io_error(
+                        "ERR To set a master the node must be empty and without assigned slots.",
+                    )
+                    .into());
+                }
+                follow = Some((master.ip.clone(), master.port));
+                if let Some(myself) = cluster.nodes.get_mut(&cluster.myself) {
+                    myself.master = Some(id);
+                }
+                "OK".into()
+            }
+            "replicas" | "slaves" => {
+                let id = args.pop_front().ok_or(err())?.to_string();
+                let Some(master) = cluster.nodes.get(&id) else {
+                    return Err(make_io_error(&format!("ERR Unknown node {id}")).into());
+                };
+                if master.master.is_some() {
+                    return Err(make_io_error("ERR The specified node is not a master").into());
+                }
+                let replicas: Vec<String> = cluster
+                    .replicas(&id)
+                    .map(|v| cluster.describe(v, true))
+                    .collect();
+                replicas.into()
+            }
+            _ => {
+                return Err(make_io_error(&format!(
+                    "ERR unknown subcommand '{subcommand}'. Try CLUSTER HELP."
+                ))
+                .into());
+            }
+        };
+        cluster.save();
+        if let Some((host, port)) = follow {
+            store.demote(host.clone(), port);
+            store.master_link = Some(slave::start(self.store.clone(), host, port));
+        }
+        Ok(res)
+    }
+
+    /// Commands on keys served by another node are redirected to it with `-MOVED`,
+    /// all the keys of a command have to be in the same slot. While a slot is
+    /// being moved the keys that are not here anymore are looked for on the target
+    /// with `-ASK`, where they are served right after ASKING.
+    pub(crate) async fn cluster_redirect(&self, args: &Args) -> Option<String> {
+        let command = command::lookup(args)?;
+        let store = self.store.lock().await;
+        let cluster = store.cluster.as_ref()?;
+        let keys = command.keys(args);
+        let mut slots = keys.iter().map(|v| cluster::key_slot(v));
+        let slot = slots.next()?;
+        if slots.any(|v| v != slot) {
+            return Some("CROSSSLOT Keys in request don't hash to the same slot".into());
+        }
+        let db = &store.databases[self.db];
+        let missing = keys.iter().filter(|v| db.get(v).is_none()).count();
+        let asking = self.asking || command.name == "restore-asking";
+        if asking && cluster.importing.contains_key(&slot) {
+            // the keys that were not moved yet are still on the source
+            return (missing > 0 && keys.len() > 1)
+                .then(|| "TRYAGAIN Multiple keys request during rehashing of slot".into());
+        }
+        match cluster.owner(slot) {
+            None => Some("CLUSTERDOWN Hash slot not served".into()),
+            // until one of its replicas takes over
+            Some(node) if node.fail.is_some() => Some("CLUSTERDOWN The cluster is down".into()),
+            Some(node) if node.id == cluster.myself => {
+                let target = cluster.migrating.get(&slot).filter(|_| missing > 0);
+                let target = target.and_then(|v| cluster.nodes.get(v))?;
+                if missing < keys.len() {
+                    return Some("TRYAGAIN Multiple keys request during rehashing of slot".into());
+                }
+                Some(format!("ASK {slot} {}:{}", target.ip, target.port))
+            }
+            Some(node) => Some(format!("MOVED {slot} {}:{}", node.ip, node.port)),
+        }
+    }
+
+    /// The next command of the connection may be about a slot that is being imported
+    /// to this node, it is served instead of being redirected to the owner of the slot.
+    /// ```
+    /// ASKING
+    /// ```
+    pub async fn asking(&mut self, _: Args) -> Result {
+        if self.store.lock().await.cluster.is_none() {
+            return Err(cluster_disabled().into());
+        }
+        self.asking = true;
+        Ok("OK".into())
+    }
+
+    /// Serialize the value stored at key in a Redis-specific format and return it to the user.
+    /// The returned value can be synthesized back into a Redis key using the RESTORE command.
+    /// ```
+    /// DUMP key
+    /// ```
+    pub async fn dump(&mut self, mut args: Args) -> Result {
+        let key = args.pop_front().ok_or(wrong_num_arguments("dump"))?;
+        let store = self.store.lock().await;
+        let res = match store.databases[self.db].get(&key) {
+            None | Some(Value::VectorSet) => Frame::None(TypedNone::String),
+            Some(value) => Frame::BulkString(RDBEncoder::dump(value)),
+        };
+        Ok(res)
+    }
+
+    /// Create a key associated with a value that is obtained by deserializing the provided
+    /// serialized value (obtained via DUMP). If ttl is 0 the key is created without any
+    /// expire, otherwise it is set in milliseconds, or as a unix time with ABSTTL.
+    /// ```
+    /// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]
+    /// ```
+    pub async fn restore(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("restore");
+        let key = args.pop_front().ok_or(err())?;
+        let ttl: i64 = args
+            .pop_front()
+            .ok_or(err())?
+            .parse()
+            .map_err(|_| out_of_range())?;
+        let payload = args.pop_front().ok_or(err())?;
+        let (mut replace, mut absttl) = (false, false);
+        while let Some(option) = args.pop_front() {
+            match option.to_lowercase().as_str() {
+                "replace" => replace = true,
+                "absttl" => absttl = true,
+                // there is no eviction to make use of these
+                "idletime" | "freq" => {
+                    args.pop_front().ok_or(syntax_error())?;
+                }
+                _ => return Err(syntax_error().into()),
+            }
+        }
+        if ttl < 0 {
+            return Err(make_io_error("ERR Invalid TTL value, must be >= 0").into());
+        }
+        let value =
+            RDBParser::restore(&payload).map_err(|_| make_io_error("ERR Bad data format"))?;
+
+        let mut store = self.store.lock().await;
+        let db = &mut store.databases[self.db];
+        if !replace && db.get(&key).is_some() {
+            return Err(make_io_error("BUSYKEY Target key name already exists.").into());
+        }
+        db.remove(&key);
+        let ttl = Duration::from_millis(ttl as u64);
+        let expiry = match (ttl.is_zero(), absttl) {
+            (true, _) => None,
+            (false, true) => Some(UNIX_EPOCH + ttl),
+            (false, false) => Some(SystemTime::now() + ttl),
+        };
+        // a key that would expire right away is not created at all
+        if expiry.is_some_and(|v| v <= SystemTime::now()) {
+            return Ok("OK".into());
+        }
+        db.insert(key.clone(), value);
+        if let Some(time) = expiry {
+            db.set_expiry(key, time);
+        }
+        Ok("OK".into())
+    }
+
+    /// Atomically transfer a key from a source Redis instance to a destination Redis instance.
+    /// On success the key is deleted from the original instance, unless COPY is given.
+    /// Many keys can be moved at once with KEYS, the key argument is then an empty string.
+    /// ```
+    /// MIGRATE host port <key | ""> destination-db timeout [COPY] [REPLACE]
+    ///   [AUTH password | AUTH2 username password] [KEYS key [key ...]]
+    /// ```
+    pub async fn migrate(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("migrate");
+        let mut next = || args.pop_front().ok_or(err());
+        let host = next()?;
+        let port: u16 = next()?.parse().map_err(|_| out_of_range())?;
+        let key = next()?;
+        let db: usize = next()?.parse().map_err(|_| out_of_range())?;
+        let timeout: u64 = next()?.parse().map_err(|_| out_of_range())?;
+        let (mut copy, mut replace, mut auth, mut keys) = (false, false, None, vec![]);
+        while let Some(option) = args.pop_front() {
+            match option.to_lowercase().as_str() {
+                "copy" => copy = true,
+                "replace" => replace = true,
+                "auth" => auth = Some(vec![args.pop_front().ok_or(syntax_error())?]),
+                "auth2" => {
+                    let user = args.pop_front().ok_or(syntax_error())?;
+                    let pass = args.pop_front().ok_or(syntax_error())?;
+                    auth = Some(vec![user, pass]);
+                }
+                "keys" => {
+                    if !key.is_empty() {
+                        return Err(make_io_error(
+                            "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string",
+                        )
+                        .into());
+                    }
+                    keys.extend(args.drain(..));
+                }
+                _ => return Err(syntax_error().into()),
+            }
+        }
+        if !key.is_empty() {
+            keys.push(key);
+        }
+
+        let (dumped, restore) = {
+            let store = self.store.lock().await;
+            let dumped = dump_keys(&store.databases[self.db], keys);
+            // the target serves the keys of a slot it is importing only when asked to
+            let restore = match store.cluster {
+                Some(_) => "RESTORE-ASKING",
+                None => "RESTORE",
+            };
+            (dumped, restore)
+        };
+        if dumped.is_empty() {
+            return Ok("NOKEY".into());
+        }
+
+        let timeout = Duration::from_millis(if timeout == 0 { 1000 } else { timeout });
+        let transfer = async {
+            let mut link = Link::connect(&(host.to_string(), port))
+                .await
+                .map_err(|_| make_io_error("IOERR error or timeout connecting to the client"))?;
+            let target_error =
+                |e: Error| make_io_error(&format!("ERR Target instance replied with error: {e}"));
+            if let Some(auth) = auth {
+                let mut command: Vec<Frame> = vec!["AUTH".to_string().into()];
+                command.extend(auth.into_iter().map(|v| v.into()));
+                link.call_frame(command.into())
+                    .await
+                    .map_err(target_error)?;
+            }
+            link.call(&["SELECT".to_string(), db.to_string()])
+                .await
+                .map_err(target_error)?;
+            let now = SystemTime::now();
+            for (key, expiry, payload) in &dumped {
+                let ttl = expiry.map_or(0, |v| {
+                    v.duration_since(now).map_or(1, |v| v.as_millis().max(1))
+                });
+                let mut command: Vec<Frame> = vec![
+                    restore.to_string().into(),
+                    key.clone().into(),
+                    ttl.to_string().into(),
+                    Frame::BulkString(payload.clone()),
+                ];
+                if replace {
+                    command.push("REPLACE".to_string().into());
+                }
+                link.call_frame(command.into())
+                    .await
+                    .map_err(target_error)?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        match tokio::time::timeout(timeout, transfer).await {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(
+                    make_io_error("IOERR error or timeout reading to target instance").into(),
+                );
+            }
+        }
+
+        if !copy {
+            let mut store = self.store.lock().await;
+            remove_migrated(&mut store.databases[self.db], &dumped);
+        }
+        Ok("OK".into())
+    }
+}
+
+/// A key dumped for MIGRATE along with its expiry time
+type Dumped = (ByteString, Option<SystemTime>, Bytes);
+
+fn dump_keys(db: &Database, keys: Vec<ByteString>) -> Vec<Dumped> {
+    keys.into_iter()
+        .filter_map(|key| {
+            let payload = RDBEncoder::dump(db.get(&key)?);
+            let expiry = db.expiry_time.get(&key).copied();
+            Some((key, expiry, payload))
+        })
+        .collect()
+}
+
+/// Removes the keys that were moved by MIGRATE. The store isn't locked while the
+/// target restores them, a key that was written to in the meantime is kept as the
+/// write would be lost otherwise.
+fn remove_migrated(db: &mut Database, dumped: &[Dumped]) {
+    for (key, expiry, payload) in dumped {
+        let unchanged = db.get(key).is_some_and(|v| RDBEncoder::dump(v) == payload)
+            && db.expiry_time.get(key) == expiry.as_ref();
+        if unchanged {
+            db.remove(key);
+        }
+    }
+}
+
+fn cluster_disabled() -> std::io::Error {
+    make_io_error("ERR This instance has cluster support disabled")
+}
+
+fn known_node(
+    cluster: &Cluster,
+    id: Option<ByteString>,
+) -> std::result::Result<String, std::io::Error> {
+    let id = id.ok_or(wrong_num_arguments("cluster"))?.to_string();
+    if !cluster.nodes.contains_key(&id) {
+        return Err(make_io_error(&format!("ERR I don't know about node {id}")));
+    }
+    Ok(id)
+}
+
+/// The keys of the database that hash to the slot
+fn parse_slot(slot: &ByteString) -> std::result::Result<u16, std::io::Error> {
+    slot.parse()
+        .ok()
+        .filter(|&v: &u16| (v as usize) < SLOTS)
+        .ok_or(make_io_error("ERR Invalid or out of range slot"))
+}
+
+fn info(cluster: &Cluster) -> String {
+    let owners = || {
+        cluster
+            .slots
+            .iter()
+            .flatten()
+            .filter_map(|v| cluster.nodes.get(v))
+    };
+    let assigned = owners().count();
+    let pfail = owners().filter(|v| v.pfail && v.fail.is_none()).count();
+    let fail = owners().filter(|v| v.fail.is_some()).count();
+    let size = cluster
+        .nodes
+        .keys()
+        .filter(|id| cluster.slots.iter().any(|v| v.as_ref() == Some(id)))
+        .count();
+    let state = if assigned == SLOTS && fail == 0 {
+        "ok"
+    } else {
+        "fail"
+    };
+    let myself = cluster.myself();
+    format!(
+        "cluster_enabled:1\r\n\
+         cluster_state:{state}\r\n\
+         cluster_slots_assigned:{assigned}\r\n\
+         cluster_slots_ok:{}\r\n\
+         cluster_slots_pfail:{pfail}\r\n\
+         cluster_slots_fail:{fail}\r\n\
+         cluster_known_nodes:{}\r\n\
+         cluster_size:{size}\r\n\
+         cluster_current_epoch:{}\r\n\
+         cluster_my_epoch:{}\r\n",
+        assigned - pfail - fail,
+        cluster.nodes.len(),
+        cluster.current_epoch,
+        myself.config_epoch
+    )
+}
+
+/// `[start, end, [ip, port, id], [ip, port, id] ...]` for every range of consecutive
+/// slots, its master comes first and then its replicas
+fn slots(cluster: &Cluster) -> Frame {
+    let mut res: Vec<Frame> = vec![];
+    for node in cluster.nodes.values() {
+        for (start, end) in cluster.slot_ranges(&node.id) {
+            let mut range: Vec<Frame> = vec![(start as usize).into(), (end as usize).into()];
+            let replicas = cluster.replicas(&node.id).filter(|v| v.fail.is_none());
+            for node in std::iter::once(node).chain(replicas) {
+                let description: Vec<Frame> = vec![
+                    node.ip.clone().into(),
+                    (node.port as usize).into(),
+                    node.id.clone().into(),
+                ];
+                range.push(description.into());
+            }
+            res.push(range.into());
+        }
+    }
+    res.into()
+}
+
+/// The slots and the nodes of every shard, a master along with its replicas, as
+/// `slots` and `nodes` pairs
+fn shards(cluster: &Cluster) -> Frame {
+    let mut res: Vec<Frame> = vec![];
+    for master in cluster.nodes.values().filter(|v| v.master.is_none()) {
+        let ranges: Vec<Frame> = cluster
+            .slot_ranges(&master.id)
+            .into_iter()
+            .flat_map(|(start, end)| [(start as usize).into(), (end as usize).into()])
+            .collect();
+        let nodes: Vec<Frame> = std::iter::once(master)
+            .chain(cluster.replicas(&master.id))
+            .map(|node| {
+                let role = if node.master.is_some() {
+                    "replica"
+                } else {
+                    "master"
+                };
+                let health = if node.fail_flag().is_some() || !node.connected {
+                    "fail"
+                } else {
+                    "online"
+                };
+                let description: Vec<Frame> = vec![
+                    "id".into(),
+                    node.id.clone().into(),
+                    "port".into(),
+                    (node.port as usize).into(),
+                    "ip".into(),
+                    node.ip.clone().into(),
+                    "endpoint".into(),
+                    node.ip.clone().into(),
+                    "role".into(),
+                    role.into(),
+                    "replication-offset".into(),
+                    0usize.into(),
+                    "health".into(),
+                    health.into(),
+                ];
+                description.into()
+            })
+            .collect();
+        let shard: Vec<Frame> = vec!["slots".into(), ranges.into(), "nodes".into(), nodes.into()];
+        res.push(shard.into());
+    }
+    res.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_keeps_keys_written_during_the_transfer() {
+        let mut db = Database::default();
+        let expiry = SystemTime::now() + Duration::from_secs(100);
+        for key in ["same", "changed", "expiry", "deleted"] {
+            db.insert(key.into(), Value::String("v".into()));
+            db.set_expiry(key.into(), expiry);
+        }
+        let dumped = dump_keys(
+            &db,
+            ["same", "changed", "expiry", "deleted", "missing"]
+                .map(Into::into)
+                .into(),
+        );
+        assert_eq!(dumped.len(), 4);
+
+        // written to by clients while the target restores the keys
+        db.insert("changed".into(), Value::String("w".into()));
+        db.set_expiry("expiry".into(), expiry + Duration::from_secs(1));
+        db.remove(b"deleted");
+        db.insert("deleted".into(), Value::String("v".into()));
+
+        remove_migrated(&mut db, &dumped);
+        assert!(db.get(b"same").is_none());
+        assert!(*db.get(b"changed").unwrap().string().unwrap() == "w");
+        assert!(db.get(b"expiry").is_some());
+        assert!(db.get(b"deleted").is_some());
+    }
+}
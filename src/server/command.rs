@@ -0,0 +1,323 @@
+use super::Args;
+use crate::bytestring::ByteString;
+use crate::frame::Frame;
+use crate::store::Database;
+use std::time::UNIX_EPOCH;
+
+/// the command may modify the keyspace
+pub const WRITE: u16 = 1 << 0;
+/// the command only reads from the keyspace
+pub const READONLY: u16 = 1 << 1;
+/// an administrative command, like SAVE or REPLICAOF
+pub const ADMIN: u16 = 1 << 2;
+/// a pub/sub related command
+pub const PUBSUB: u16 = 1 << 3;
+/// the command may block the client
+pub const BLOCKING: u16 = 1 << 4;
+/// the command runs in constant or logarithmic time
+pub const FAST: u16 = 1 << 5;
+/// the command has no effect on the keyspace but still
+/// has to be sent to the replicas, like PUBLISH
+pub const MAY_REPLICATE: u16 = 1 << 6;
+
+/// An entry of the command table
+pub struct Command {
+    pub name: &'static str,
+    pub flags: u16,
+    /// position of the first key among the arguments, 0 when there are no keys
+    pub first_key: usize,
+    /// position of the last key, negative positions count from the end
+    pub last_key: isize,
+    /// distance between two keys
+    pub key_step: usize,
+}
+
+impl Command {
+    pub fn is(&self, flags: u16) -> bool {
+        self.flags & flags != 0
+    }
+
+    /// The keys the command is called with, `args` start with the command name
+    pub fn keys<'a>(&self, args: &'a Args) -> Vec<&'a ByteString> {
+        if self.name == "migrate" {
+            // a single key, or the ones following KEYS when it is empty
+            return match args.get(3) {
+                Some(key) if !key.is_empty() => vec![key],
+                _ => match args.iter().position(|v| v.eq_ignore_ascii_case("keys")) {
+                    Some(idx) => args.iter().skip(idx + 1).collect(),
+                    None => vec![],
+                },
+            };
+        }
+        if self.name == "xread" {
+            // the keys are the first half of what follows STREAMS
+            let Some(idx) = args.iter().position(|v| v.eq_ignore_ascii_case("streams")) else {
+                return vec![];
+            };
+            let count = (args.len() - idx - 1) / 2;
+            return args.iter().skip(idx + 1).take(count).collect();
+        }
+        if self.first_key == 0 {
+            return vec![];
+        }
+        let last = match self.last_key {
+            v if v < 0 => args.len() as isize + v,
+            v => v,
+        };
+        let Ok(last) = usize::try_from(last) else {
+            return vec![];
+        };
+        (self.first_key..=last)
+            .step_by(self.key_step)
+            .filter_map(|v| args.get(v))
+            .collect()
+    }
+}
+
+macro_rules! commands {
+    ($($name:literal => $flags:expr $(; keys($first:expr, $last:expr, $step:expr))?),* $(,)?) => {
+        &[$(commands!(@entry $name, $flags $(, $first, $last, $step)?)),*]
+    };
+    (@entry $name:literal, $flags:expr) => {
+        commands!(@entry $name, $flags, 0, 0, 0)
+    };
+    (@entry $name:literal, $flags:expr, $first:expr, $last:expr, $step:expr) => {
+        Command { name: $name, flags: $flags, first_key: $first, last_key: $last, key_step: $step }
+    };
+}
+
+/// Every command that is known to the server, subcommands of container
+/// commands are listed as `<command>|<subcommand>` just like redis does.
+/// `keys(first, last, step)` tells where the keys are among the arguments.
+static COMMANDS: &[Command] = commands! {
+    "ping" => FAST,
+    "echo" => FAST,
+    "hello" => FAST,
+    "client" => 0,
+    "client|id" => FAST,
+    "client|getname" => FAST,
+    "client|setname" => FAST,
+    "info" => 0,
+    "type" => READONLY | FAST; keys(1, 1, 1),
+    "keys" => READONLY,
+    "config" => ADMIN,
+    "config|get" => ADMIN,
+    "config|set" => ADMIN,
+    // strings
+    "get" => READONLY | FAST; keys(1, 1, 1),
+    "set" => WRITE; keys(1, 1, 1),
+    "incr" => WRITE | FAST; keys(1, 1, 1),
+    // lists
+    "rpush" => WRITE | FAST; keys(1, 1, 1),
+    "lpush" => WRITE | FAST; keys(1, 1, 1),
+    "lpop" => WRITE | FAST; keys(1, 1, 1),
+    "blpop" => WRITE | BLOCKING; keys(1, -2, 1),
+    "lrange" => READONLY; keys(1, 1, 1),
+    "llen" => READONLY | FAST; keys(1, 1, 1),
+    // streams
+    "xadd" => WRITE | FAST; keys(1, 1, 1),
+    "xdel" => WRITE | FAST; keys(1, 1, 1),
+    "xlen" => READONLY | FAST; keys(1, 1, 1),
+    "xrange" => READONLY; keys(1, 1, 1),
+    "xread" => READONLY | BLOCKING,
+    "xinfo" => 0,
+    "xinfo|stream" => READONLY; keys(2, 2, 1),
+    // sorted sets
+    "zadd" => WRITE | FAST; keys(1, 1, 1),
+    "zrem" => WRITE | FAST; keys(1, 1, 1),
+    "zcard" => READONLY | FAST; keys(1, 1, 1),
+    "zcount" => READONLY | FAST; keys(1, 1, 1),
+    "zrank" => READONLY | FAST; keys(1, 1, 1),
+    "zrange" => READONLY; keys(1, 1, 1),
+    "zscore" => READONLY | FAST; keys(1, 1, 1),
+    // geospatial
+    "geoadd" => WRITE; keys(1, 1, 1),
+    "geopos" => READONLY; keys(1, 1, 1),
+    "geodist" => READONLY; keys(1, 1, 1),
+    "geosearch" => READONLY; keys(1, 1, 1),
+    // keyspace
+    "select" => FAST,
+    "move" => WRITE | FAST; keys(1, 1, 1),
+    "swapdb" => WRITE | FAST,
+    "flushdb" => WRITE,
+    "del" => WRITE; keys(1, -1, 1),
+    "dump" => READONLY; keys(1, 1, 1),
+    "restore" => WRITE; keys(1, 1, 1),
+    "restore-asking" => WRITE; keys(1, 1, 1),
+    "migrate" => WRITE,
+    "unlink" => WRITE | FAST; keys(1, -1, 1),
+    // transactions
+    "multi" => FAST,
+    "exec" => 0,
+    "discard" => FAST,
+    // pub/sub
+    "subscribe" => PUBSUB,
+    "unsubscribe" => PUBSUB,
+    "publish" => PUBSUB | FAST | MAY_REPLICATE,
+    // persistence
+    "save" => ADMIN,
+    "bgsave" => ADMIN,
+    "bgrewriteaof" => ADMIN,
+    "lastsave" => FAST,
+    // replication
+    "replconf" => ADMIN,
+    "psync" => ADMIN,
+    "wait" => BLOCKING,
+    "replicaof" => ADMIN,
+    "slaveof" => ADMIN,
+    "role" => FAST,
+    "failover" => ADMIN,
+    // sentinel
+    "sentinel" => ADMIN,
+    // cluster
+    "cluster" => 0,
+    "cluster|addslots" => ADMIN,
+    "cluster|addslotsrange" => ADMIN,
+    "cluster|meet" => ADMIN,
+    "cluster|setslot" => ADMIN,
+    "cluster|replicate" => ADMIN,
+    "asking" => FAST,
+    // acl
+    "auth" => FAST,
+    "acl" => 0,
+    "acl|whoami" => FAST,
+    "acl|getuser" => ADMIN,
+    "acl|setuser" => ADMIN | MAY_REPLICATE,
+};
+
+/// Looks up the entry of the command, `args` start with the command name
+pub fn lookup(args: &Args) -> Option<&'static Command> {
+    let name = args.front()?.to_lowercase();
+    let find = |name: &str| COMMANDS.iter().find(|v| v.name == name);
+    args.get(1)
+        .and_then(|sub| find(&format!("{name}|{}", sub.to_lowercase())))
+        .or_else(|| find(&name))
+}
+
+/// Rewrites the commands whose effect depends on when they are executed into a
+/// form that has the same effect on the replicas and when the append only file
+/// is loaded, `None` means that there is nothing to propagate
+pub fn rewrite(db: &Database, mut args: Args, response: &Frame) -> Option<Args> {
+    match args.front()?.to_lowercase().as_str() {
+        "set" => {
+            // relative expire times become an absolute unix time in milliseconds
+            let relative = args
+                .iter()
+                .skip(3)
+                .position(|v| v.eq_ignore_ascii_case("ex") || v.eq_ignore_ascii_case("px"));
+            if let Some(idx) = relative.map(|v| v + 3) {
+                let time = db.expiry_time.get(args.get(1)?)?;
+                let millis = time.duration_since(UNIX_EPOCH).ok()?.as_millis();
+                args[idx] = "PXAT".into();
+                *args.get_mut(idx + 1)? = millis.to_string().into();
+            }
+            Some(args)
+        }
+        "xadd" => {
+            // the id that was generated for the entry
+            if args.get(2)?.contains(&b'*') {
+                args[2] = response.clone().bulk_string()?.into();
+            }
+            Some(args)
+        }
+        "restore" | "restore-asking" => {
+            let key = args.get(1)?.clone();
+            // the key was not created as it had already expired
+            if !db.kv.contains_key(&key) {
+                return Some(Args::from(["DEL".into(), key]));
+            }
+            args[0] = "RESTORE".into();
+            // the time to live becomes an absolute unix time in milliseconds
+            let millis = match db.expiry_time.get(&key) {
+                Some(time) => time.duration_since(UNIX_EPOCH).ok()?.as_millis(),
+                None => 0,
+            };
+            args[2] = millis.to_string().into();
+            if !args.iter().any(|v| v.eq_ignore_ascii_case("absttl")) {
+                args.push_back("ABSTTL".into());
+            }
+            Some(args)
+        }
+        "migrate" => {
+            // the keys that were moved are gone, nothing changed with COPY and
+            // the keys written to during the transfer were kept
+            let copy = args.iter().skip(6).any(|v| v.eq_ignore_ascii_case("copy"));
+            if copy || response.clone().string()? != "OK" {
+                return None;
+            }
+            let keys = lookup(&args)?.keys(&args).into_iter();
+            let mut del: Args = keys.filter(|v| !db.kv.contains_key(*v)).cloned().collect();
+            if del.is_empty() {
+                return None;
+            }
+            del.push_front("DEL".into());
+            Some(del)
+        }
+        "blpop" => {
+            // a blocking pop that got an element is just a pop, nothing is sent on a timeout
+            let key = response
+                .clone()
+                .array()?
+                .into_iter()
+                .next()?
+                .bulk_string()?;
+            Some(Args::from(["LPOP".into(), key.into()]))
+        }
+        _ => Some(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        args.iter().map(|&v| v.into()).collect()
+    }
+
+    fn propagated(command: &[&str]) -> bool {
+        lookup(&args(command)).is_some_and(|v| v.is(WRITE | MAY_REPLICATE))
+    }
+
+    #[test]
+    fn lookup_subcommands() {
+        let command = lookup(&args(&["ACL", "SetUser", "alice", "on"])).unwrap();
+        assert_eq!(command.name, "acl|setuser");
+        assert_eq!(lookup(&args(&["acl", "nosuch"])).unwrap().name, "acl");
+        assert_eq!(lookup(&args(&["get", "setuser"])).unwrap().name, "get");
+        assert!(lookup(&args(&["nosuch"])).is_none());
+        assert!(lookup(&args(&[])).is_none());
+    }
+
+    #[test]
+    fn propagated_commands() {
+        assert!(propagated(&["acl", "setuser", "alice", "on", ">pass"]));
+        assert!(propagated(&["rpush", "l", "a"]));
+        assert!(propagated(&["incr", "n"]));
+        assert!(propagated(&["publish", "ch", "hi"]));
+        assert!(!propagated(&["acl", "getuser", "alice"]));
+        assert!(!propagated(&["get", "k"]));
+        assert!(!propagated(&["config", "set", "maxmemory", "1"]));
+    }
+
+    #[test]
+    fn command_keys() {
+        let keys = |command: &[&str]| {
+            let args = args(command);
+            let keys = lookup(&args).unwrap().keys(&args);
+            keys.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(keys(&["del", "a", "b"]), ["a", "b"]);
+        assert_eq!(keys(&["blpop", "a", "b", "0"]), ["a", "b"]);
+        assert_eq!(
+            keys(&["xread", "count", "1", "streams", "a", "b", "0", "0"]),
+            ["a", "b"]
+        );
+        assert_eq!(
+            keys(&["migrate", "h", "1", "", "0", "10", "keys", "a", "b"]),
+            ["a", "b"]
+        );
+        assert_eq!(keys(&["migrate", "h", "1", "k", "0", "10"]), ["k"]);
+        assert!(keys(&["ping"]).is_empty());
+    }
+}
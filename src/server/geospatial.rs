@@ -27,10 +27,8 @@ impl Server {
     pub async fn geoadd(&mut self, mut args: Args) -> Result {
         let mut store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("zadd"))?;
-        let set = store
-            .kv
-            .entry(key)
-            .or_insert(Value::ZSet(ZSet::default()))
+        let set = store.databases[self.db]
+            .get_or_insert(key, Value::ZSet(ZSet::default()))
             .zset_mut()
             .ok_or(wrong_type())?;
         let mut res = 0usize;
@@ -93,7 +91,7 @@ impl Server {
         let store = self.store.lock().await;
         let key = args.pop_front().ok_or(err())?;
         let mut response = vec![];
-        if let Some(v) = store.kv.get(&key) {
+        if let Some(v) = store.databases[self.db].get(&key) {
             let z = v.zset().ok_or(wrong_type())?;
             for member in args {
                 let res = if let Some(score) = z.scores.get(&member) {
@@ -127,7 +125,7 @@ impl Server {
 
         let store = self.store.lock().await;
         let key = args.pop_front().ok_or(err())?;
-        if let Some(v) = store.kv.get(&key) {
+        if let Some(v) = store.databases[self.db].get(&key) {
             let z = v.zset().ok_or(wrong_type())?;
             let mut coors = vec![];
             for member in args {
@@ -186,7 +184,7 @@ impl Server {
             .parse()
             .map_err(|_| syntax_error())?;
 
-        if let Some(v) = store.kv.get(&key) {
+        if let Some(v) = store.databases[self.db].get(&key) {
             let z = v.zset().ok_or(wrong_type())?;
             let res = z
                 .scores
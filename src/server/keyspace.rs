@@ -0,0 +1,137 @@
+use super::errors::*;
+use super::server::Server;
+use super::{Args, Result};
+
+impl Server {
+    /// Select the Redis logical database having the specified zero-based numeric index.
+    /// New connections always use the database 0.
+    /// ```
+    /// SELECT index
+    /// ```
+    pub async fn select(&mut self, mut args: Args) -> Result {
+        let index: usize = args
+            .pop_front()
+            .ok_or(wrong_num_arguments("select"))?
+            .parse()
+            .map_err(|_| out_of_range())?;
+        let mut store = self.store.lock().await;
+        if store.cluster.is_some() && index != 0 {
+            return Err("ERR SELECT is not allowed in cluster mode".into());
+        }
+        if index >= store.databases.len() {
+            return Err("ERR DB index is out of range".into());
+        }
+        self.db = index;
+        if self.from_master {
+            // remembered so that the link can continue after a reconnect
+            store.replication_db = Some(index);
+        }
+        Ok("OK".into())
+    }
+
+    /// Move key from the currently selected database to the specified destination database.
+    /// When key already exists in the destination database, or it does not exist in the
+    /// source database, it does nothing. It is possible to use MOVE as a locking primitive
+    /// because of this.
+    /// ```
+    /// MOVE key db
+    /// ```
+    pub async fn move_key(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("move");
+        let key = args.pop_front().ok_or(err())?;
+        let target: usize = args
+            .pop_front()
+            .ok_or(err())?
+            .parse()
+            .map_err(|_| out_of_range())?;
+
+        let mut store = self.store.lock().await;
+        if target >= store.databases.len() {
+            return Err("ERR DB index is out of range".into());
+        }
+        if target == self.db {
+            return Err("ERR source and destination objects are the same".into());
+        }
+        if store.databases[target].get(&key).is_some() {
+            return Ok(0usize.into());
+        }
+        let Some((value, expiry)) = store.databases[self.db].remove(&key) else {
+            return Ok(0usize.into());
+        };
+        let db = &mut store.databases[target];
+        if let Some(time) = expiry {
+            db.set_expiry(key.clone(), time);
+        }
+        db.insert(key, value);
+        Ok(1usize.into())
+    }
+
+    /// This command swaps two Redis databases, so that immediately all the clients
+    /// connected to a given database will see the data of the other database,
+    /// and the other way around.
+    /// ```
+    /// SWAPDB index1 index2
+    /// ```
+    pub async fn swapdb(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("swapdb");
+        let first: usize = args
+            .pop_front()
+            .ok_or(err())?
+            .parse()
+            .map_err(|_| make_io_error("ERR invalid first DB index"))?;
+        let second: usize = args
+            .pop_front()
+            .ok_or(err())?
+            .parse()
+            .map_err(|_| make_io_error("ERR invalid second DB index"))?;
+
+        let mut store = self.store.lock().await;
+        if first >= store.databases.len() || second >= store.databases.len() {
+            return Err("ERR DB index is out of range".into());
+        }
+        store.databases.swap(first, second);
+        Ok("OK".into())
+    }
+
+    /// Delete all the keys of the currently selected DB. This command never fails.
+    /// ```
+    /// FLUSHDB [ASYNC | SYNC]
+    /// ```
+    pub async fn flushdb(&mut self, _: Args) -> Result {
+        self.store.lock().await.databases[self.db].clear();
+        Ok("OK".into())
+    }
+
+    /// Removes the specified keys. A key is ignored if it does not exist.
+    /// ```
+    /// DEL key [key ...]
+    /// ```
+    pub async fn del(&mut self, args: Args) -> Result {
+        if args.is_empty() {
+            return Err(wrong_num_arguments("del").into());
+        }
+        let mut store = self.store.lock().await;
+        let db = &mut store.databases[self.db];
+        let mut removed = 0usize;
+        for key in args {
+            // a logically expired key is dropped without being counted
+            if db.get(&key).is_some() {
+                removed += 1;
+            }
+            db.remove(&key);
+        }
+        Ok(removed.into())
+    }
+
+    /// This command is very similar to DEL: it removes the specified keys. The actual
+    /// memory reclaiming here happens right away as well, values are simply dropped.
+    /// ```
+    /// UNLINK key [key ...]
+    /// ```
+    pub async fn unlink(&mut self, args: Args) -> Result {
+        if args.is_empty() {
+            return Err(wrong_num_arguments("unlink").into());
+        }
+        self.del(args).await
+    }
+}
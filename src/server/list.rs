@@ -24,10 +24,8 @@ impl<'a> Server {
     pub async fn rpush(&mut self, mut args: Args) -> Result {
         let mut store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("rpush"))?;
-        let e = store
-            .kv
-            .entry(key)
-            .or_insert(Value::List(VecDeque::new()))
+        let e = store.databases[self.db]
+            .get_or_insert(key, Value::List(VecDeque::new()))
             .list_mut()
             .ok_or(wrong_type())?;
         args.into_iter().for_each(|v| e.push_back(v.into()));
@@ -50,10 +48,8 @@ impl<'a> Server {
     pub async fn lpush(&mut self, mut args: Args) -> Result {
         let mut store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("lpush"))?;
-        let e = store
-            .kv
-            .entry(key)
-            .or_insert(Value::List(VecDeque::new()))
+        let e = store.databases[self.db]
+            .get_or_insert(key, Value::List(VecDeque::new()))
             .list_mut()
             .ok_or(wrong_type())?;
         args.into_iter().for_each(|v| e.push_front(v.into()));
@@ -78,7 +74,10 @@ impl<'a> Server {
             .map_err(|_| syntax_error())?;
 
         let mut store = self.store.lock().await;
-        let res = if let Some(list) = store.kv.get_mut(&key).and_then(|v| v.list_mut()) {
+        let res = if let Some(list) = store.databases[self.db]
+            .get_mut(&key)
+            .and_then(|v| v.list_mut())
+        {
             let count = count.min(list.len());
             if count == 0 {
                 Frame::None(TypedNone::String)
@@ -119,7 +118,7 @@ impl<'a> Server {
 
         while now.elapsed().as_secs_f64() < time_out {
             let mut store = self.store.lock().await;
-            let list = match store.kv.get_mut(&key) {
+            let list = match store.databases[self.db].get_mut(&key) {
                 Some(l) => l.list_mut().unwrap(),
                 None => continue,
             };
@@ -147,14 +146,13 @@ impl<'a> Server {
     pub async fn lrange(&mut self, mut args: Args) -> Result {
         let err = || wrong_num_arguments("lrange");
 
-        let mut store = self.store.lock().await;
+        let store = self.store.lock().await;
         let key = args.pop_front().ok_or(err())?;
-        let list = store
-            .kv
-            .entry(key)
-            .or_insert(Value::List(VecDeque::new()))
-            .list_mut()
-            .unwrap();
+        let empty = VecDeque::new();
+        let list = match store.databases[self.db].get(&key) {
+            Some(v) => v.list().ok_or(wrong_type())?,
+            None => &empty,
+        };
         let n = list.len();
 
         let mut start: isize = args.pop_front().ok_or(err())?.parse().unwrap();
@@ -185,7 +183,7 @@ impl<'a> Server {
         let store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("llen"))?;
 
-        let n = match store.kv.get(&key).and_then(|v| v.list()) {
+        let n = match store.databases[self.db].get(&key).and_then(|v| v.list()) {
             Some(l) => l.len(),
             None => 0,
         };
@@ -2,6 +2,7 @@ use super::errors::*;
 use super::server::Server;
 use super::{Args, Result};
 use crate::frame::{Frame, TypedNone};
+use crate::store::Role;
 
 impl Server {
     /// Returns the string representation of the type of the value stored at key.
@@ -13,8 +14,7 @@ impl Server {
     pub async fn redis_type(&mut self, mut args: Args) -> Result {
         let key = args.pop_front().ok_or(wrong_num_arguments("type"))?;
         let store = self.store.lock().await;
-        let resp = store
-            .kv
+        let resp = store.databases[self.db]
             .get(&key)
             .map(|v| v.redis_type())
             .unwrap_or("none".into())
@@ -28,10 +28,10 @@ impl Server {
     /// ```
     /// INFO [section [section ...]]
     /// ```
-    pub async fn info(&mut self, _: Args) -> Result {
-        Ok(Frame::BulkString(
-            self.store.lock().await.info.to_string().into(),
-        ))
+    pub async fn info(&mut self, args: Args) -> Result {
+        let sections: Vec<String> = args.iter().map(|v| v.to_string()).collect();
+        let info = self.store.lock().await.info(&sections)?;
+        Ok(Frame::BulkString(info.into()))
     }
 
     /// Returns message.
@@ -50,7 +50,124 @@ impl Server {
         Ok("PONG".into())
     }
 
+    /// Switch to a different protocol, optionally authenticating and setting the connection's
+    /// name, or provide a contextual client report. Replies with a map of the server's
+    /// properties, in the protocol that was just negotiated.
+    /// ```
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    /// ```
+    pub async fn hello(&mut self, mut args: Args) -> Result {
+        let protocol = match args.pop_front() {
+            Some(v) => match v.parse() {
+                Ok(v @ (2 | 3)) => v,
+                Ok(_) => {
+                    return Err(make_io_error("NOPROTO unsupported protocol version").into());
+                }
+                Err(_) => {
+                    return Err(make_io_error(
+                        "ERR Protocol version is not an integer or out of range",
+                    )
+                    .into());
+                }
+            },
+            None => self.protocol,
+        };
+        let (mut auth, mut name) = (None, None);
+        while let Some(option) = args.pop_front() {
+            match option.to_lowercase().as_str() {
+                "auth" => {
+                    let user = args.pop_front().ok_or(syntax_error())?;
+                    let pass = args.pop_front().ok_or(syntax_error())?;
+                    auth = Some([user, pass].into());
+                }
+                "setname" => name = Some(args.pop_front().ok_or(syntax_error())?),
+                _ => {
+                    return Err(make_io_error(&format!(
+                        "ERR Syntax error in HELLO option '{option}'"
+                    ))
+                    .into());
+                }
+            }
+        }
+        match auth {
+            Some(auth) => {
+                self.auth(auth).await?;
+            }
+            None if !self.authenticated => {
+                return Err(make_io_error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time").into());
+            }
+            None => {}
+        }
+        if let Some(name) = name {
+            self.name = valid_client_name(name.to_string())?;
+        }
+        self.protocol = protocol;
+
+        let store = self.store.lock().await;
+        let mode = if store.cluster.is_some() {
+            "cluster"
+        } else if store.sentinel.is_some() {
+            "sentinel"
+        } else {
+            "standalone"
+        };
+        let role = match store.info.role {
+            Role::Master => "master",
+            Role::Slave => "replica",
+        };
+        let res = vec![
+            ("server".into(), "redis".to_string().into()),
+            (
+                "version".into(),
+                env!("CARGO_PKG_VERSION").to_string().into(),
+            ),
+            ("proto".into(), (protocol as usize).into()),
+            ("id".into(), self.id.into()),
+            ("mode".into(), mode.to_string().into()),
+            ("role".into(), role.to_string().into()),
+            ("modules".into(), Frame::Array(vec![])),
+        ];
+        Ok(res.into())
+    }
+
+    /// Inspects and names the current connection.
+    /// ```
+    /// CLIENT ID
+    /// CLIENT GETNAME
+    /// CLIENT SETNAME connection-name
+    /// ```
+    pub async fn client(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("client");
+        let subcommand = args.pop_front().ok_or(err())?.to_lowercase();
+        let res = match subcommand.as_str() {
+            "id" => self.id.into(),
+            "getname" if self.name.is_empty() => Frame::None(TypedNone::String),
+            "getname" => self.name.clone().into(),
+            "setname" => {
+                self.name = valid_client_name(args.pop_front().ok_or(err())?.to_string())?;
+                "OK".into()
+            }
+            _ => {
+                return Err(make_io_error(&format!(
+                    "ERR unknown subcommand '{subcommand}'. Try CLIENT HELP."
+                ))
+                .into());
+            }
+        };
+        Ok(res)
+    }
+
     pub async fn invalid(&mut self, _: Args) -> Result {
         Ok(Frame::None(TypedNone::Nil))
     }
 }
+
+/// Connection names are shown space separated, they can't contain spaces or special characters
+fn valid_client_name(name: String) -> std::result::Result<String, std::io::Error> {
+    if name.chars().any(|v| !v.is_ascii_graphic()) {
+        return Err(make_io_error(
+            "ERR Client names cannot contain spaces, newlines or special characters.",
+        ));
+    }
+    Ok(name)
+}
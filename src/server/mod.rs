@@ -1,21 +1,26 @@
 use crate::Error;
+use crate::bytestring::ByteString;
 use crate::frame::Frame;
 use std::collections::VecDeque;
 
 mod acl;
+mod cluster;
+mod command;
 mod errors;
 mod geospatial;
+mod keyspace;
 mod list;
 mod misc;
-mod persistence;
+pub mod persistence;
 mod pubsub;
 mod replication;
+mod sentinel;
 pub mod server;
 mod stream;
-mod string;
+pub mod string;
 mod transaction;
 mod zset;
 
 type Result = std::result::Result<Frame, Error>;
 
-pub type Args = VecDeque<String>;
+pub type Args = VecDeque<ByteString>;
@@ -1,39 +1,443 @@
-use super::server::Server;
+use super::server::{Server, command_args};
 use super::{Args, Result};
+use crate::Error;
+use crate::frame::Frame;
+use crate::rdb::decode::RDBParser;
+use crate::rdb::encode::RDBEncoder;
 use crate::server::errors::wrong_num_arguments;
+use crate::store::Store;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, mpsc};
 
 impl Server {
     pub async fn config(&mut self, mut args: Args) -> Result {
         let store = self.store.lock().await;
-        let get = args.pop_front().ok_or(wrong_num_arguments("config"))?;
-        assert_eq!(get.to_lowercase(), "get");
-        let mut res = vec![];
+        let command = args.pop_front().ok_or(wrong_num_arguments("config"))?;
+        if command.eq_ignore_ascii_case("set") {
+            drop(store);
+            return self.config_set(args).await;
+        }
+        assert_eq!(command.to_lowercase(), "get");
+        let mut res = HashMap::new();
         for key in args {
-            res.push(key.clone());
             let val = match key.to_lowercase().as_str() {
                 "dir" => store.info.dir.clone(),
                 "dbfilename" => store.info.db_filename.clone(),
+                "save" => store.info.save_params_string(),
+                "databases" => store.info.databases.to_string(),
+                "appendonly" => if store.info.appendonly { "yes" } else { "no" }.into(),
+                "appendfilename" => store.info.append_filename.clone(),
+                "appendfsync" => store.info.appendfsync.to_string(),
+                "repl-backlog-size" => store.info.repl_backlog_size.to_string(),
+                "replica-read-only" | "slave-read-only" => if store.info.replica_read_only {
+                    "yes"
+                } else {
+                    "no"
+                }
+                .into(),
+                "min-replicas-to-write" | "min-slaves-to-write" => {
+                    store.info.min_replicas_to_write.to_string()
+                }
+                "min-replicas-max-lag" | "min-slaves-max-lag" => {
+                    store.info.min_replicas_max_lag.to_string()
+                }
                 _ => unimplemented!(),
             };
-            res.push(val);
+            res.insert(key.to_string(), val.into());
         }
         Ok(res.into())
     }
 
+    /// Used in order to reconfigure the server at run time without the need to restart Redis.
+    /// ```
+    /// CONFIG SET parameter value [parameter value ...]
+    /// ```
+    pub async fn config_set(&mut self, mut args: Args) -> Result {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return Err(wrong_num_arguments("config|set").into());
+        }
+        let mut store = self.store.lock().await;
+        while let (Some(key), Some(value)) = (args.pop_front(), args.pop_front()) {
+            let invalid = || format!("ERR Invalid argument '{value}' for CONFIG SET '{key}'");
+            match key.to_lowercase().as_str() {
+                "replica-read-only" | "slave-read-only" => {
+                    store.info.replica_read_only = match value.to_lowercase().as_str() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(invalid().into()),
+                    }
+                }
+                "min-replicas-to-write" | "min-slaves-to-write" => {
+                    store.info.min_replicas_to_write = value.parse().map_err(|_| invalid())?;
+                }
+                "min-replicas-max-lag" | "min-slaves-max-lag" => {
+                    store.info.min_replicas_max_lag = value.parse().map_err(|_| invalid())?;
+                }
+                _ => {
+                    return Err(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{key}'"
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok("OK".into())
+    }
+
     /// Returns all keys matching pattern
     /// ```
     /// KEYS pattern
     /// ```
     pub async fn keys(&mut self, mut args: Args) -> Result {
         let pattern = args.pop_front().ok_or(wrong_num_arguments("keys"))?;
-        let pattern = pattern.replace("*", ".*");
+        let pattern = pattern.to_string().replace("*", ".*");
         let mut res = vec![];
-        let re = regex::Regex::new(&pattern)?;
-        for k in self.store.lock().await.kv.keys() {
-            if re.is_match_at(k, 0) {
+        // keys are not necessarily UTF-8, match them byte by byte
+        let re = regex::bytes::RegexBuilder::new(&pattern)
+            .unicode(false)
+            .build()?;
+        let store = self.store.lock().await;
+        let db = &store.databases[self.db];
+        for k in db.kv.keys() {
+            if re.is_match_at(k, 0) && !db.is_expired(k) {
                 res.push(k.clone());
             }
         }
         Ok(res.into())
     }
+
+    /// The SAVE commands performs a synchronous save of the dataset producing a point
+    /// in time snapshot of all the data inside the Redis instance, in the form of an RDB file.
+    ///
+    /// You almost never want to call SAVE in production environments where it will block
+    /// all the other clients. Instead usually BGSAVE is used.
+    /// ```
+    /// SAVE
+    /// ```
+    pub async fn save(&mut self, _: Args) -> Result {
+        let mut store = self.store.lock().await;
+        if store.info.bgsave_in_progress {
+            return Err("ERR Background save already in progress".into());
+        }
+        let data = RDBEncoder::encode(&store);
+        RDBEncoder::save_file(store.info.rdb_path(), &data)?;
+        store.info.dirty = 0;
+        store.info.last_save = unix_time();
+        Ok("OK".into())
+    }
+
+    /// Save the DB in background.
+    ///
+    /// Normally the OK code is immediately returned. The snapshot is taken while
+    /// holding the store and the file is written by a background task, the
+    /// other clients can keep running commands while the file is being written.
+    /// ```
+    /// BGSAVE [SCHEDULE]
+    /// ```
+    pub async fn bgsave(&mut self, _: Args) -> Result {
+        background_save(self.store.clone()).await?;
+        Ok("Background saving started".into())
+    }
+
+    /// Return the UNIX TIME of the last DB save executed with success.
+    /// ```
+    /// LASTSAVE
+    /// ```
+    pub async fn lastsave(&mut self, _: Args) -> Result {
+        Ok((self.store.lock().await.info.last_save as usize).into())
+    }
+
+    /// Instruct Redis to start an Append Only File rewrite process. The rewrite will create
+    /// a small optimized version of the current Append Only File.
+    ///
+    /// The rewritten file starts with an RDB snapshot of the dataset, the commands
+    /// received while it is being written are appended after it.
+    /// ```
+    /// BGREWRITEAOF
+    /// ```
+    pub async fn bgrewriteaof(&mut self, _: Args) -> Result {
+        background_rewrite_aof(self.store.clone()).await?;
+        Ok("Background append only file rewriting started".into())
+    }
+}
+
+/// Takes a snapshot of the store and writes it to
+/// the rdb file without holding the store lock
+pub async fn background_save(store: Arc<Mutex<Store>>) -> std::result::Result<(), Error> {
+    let mut guard = store.lock().await;
+    if guard.info.bgsave_in_progress {
+        return Err("ERR Background save already in progress".into());
+    }
+    let data = RDBEncoder::encode(&guard);
+    let path = guard.info.rdb_path();
+    let dirty = guard.info.dirty;
+    guard.info.bgsave_in_progress = true;
+    drop(guard);
+
+    tokio::spawn(async move {
+        let res = tokio::task::spawn_blocking(move || RDBEncoder::save_file(path, &data)).await;
+        let mut store = store.lock().await;
+        store.info.bgsave_in_progress = false;
+        match res {
+            Ok(Ok(())) => {
+                store.info.dirty -= dirty.min(store.info.dirty);
+                store.info.last_save = unix_time();
+                println!("Background saving terminated with success");
+            }
+            Ok(Err(e)) => println!("Background saving error: {e}"),
+            Err(e) => println!("Background saving error: {e}"),
+        }
+    });
+    Ok(())
+}
+
+/// Writes a snapshot of the store as the base of a new append only file
+/// and swaps it with the current one once it has been written
+pub async fn background_rewrite_aof(store: Arc<Mutex<Store>>) -> std::result::Result<(), Error> {
+    let mut guard = store.lock().await;
+    if guard.info.aof_rewrite_in_progress {
+        return Err("ERR Background append only file rewriting already in progress".into());
+    }
+    let data = RDBEncoder::encode(&guard);
+    let path = guard.info.aof_path();
+    let temp = path.with_file_name(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+    if let Some(aof) = guard.aof.as_mut() {
+        aof.start_rewrite();
+    }
+    guard.info.aof_rewrite_in_progress = true;
+    drop(guard);
+
+    tokio::spawn(async move {
+        let file = temp.clone();
+        let res = tokio::task::spawn_blocking(move || std::fs::write(file, &data)).await;
+        let mut store = store.lock().await;
+        store.info.aof_rewrite_in_progress = false;
+        let res = res.map_err(std::io::Error::other).and_then(|v| v);
+        let res = res.and_then(|_| match store.aof.as_mut() {
+            Some(aof) => aof.finish_rewrite(&temp),
+            None => std::fs::rename(&temp, &path),
+        });
+        match res {
+            Ok(()) => println!("Background AOF rewrite finished successfully"),
+            Err(e) => {
+                if let Some(aof) = store.aof.as_mut() {
+                    aof.abort_rewrite();
+                }
+                let _ = std::fs::remove_file(&temp);
+                println!("Background AOF rewrite error: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Replays the append only file into the store, the file can start with an RDB
+/// preamble. A command cut in half at the end of the file is dropped and the file
+/// is truncated to the last complete command, a transaction missing its EXEC is
+/// dropped from its MULTI on.
+pub async fn load_aof(store: Arc<Mutex<Store>>, path: PathBuf) -> std::result::Result<(), Error> {
+    let data = std::fs::read(&path)?;
+    let mut pos = 0;
+    if data.starts_with(b"REDIS") {
+        let (rdb_file, len) = RDBParser::parse(&data)?;
+        let mut store = store.lock().await;
+        for (idx, db) in rdb_file.databases {
+            let Some(database) = store.databases.get_mut(idx) else {
+                return Err(format!("DB index {idx} is out of range").into());
+            };
+            *database = db;
+        }
+        pos = len;
+    }
+
+    // nothing reads the replies of the replayed commands
+    let (tx, _) = mpsc::channel(1);
+    let mut server = Server::new(store, tx, 0);
+    server.loading = true;
+    // where the transaction being replayed starts in the file
+    let mut multi_start = 0;
+    let mut truncated = false;
+    while pos < data.len() {
+        let mut buf = Cursor::new(&data[..]);
+        buf.set_position(pos as u64);
+        let bad_format = |e| format!("Bad file format reading the append only file: {e}");
+        match Frame::check(&mut buf) {
+            Ok(()) => {}
+            Err(crate::frame::Error::Incomplete) => {
+                truncated = true;
+                break;
+            }
+            Err(e) => return Err(bad_format(e).into()),
+        }
+        let start = pos;
+        pos = buf.position() as usize;
+        // the command gets its own copy, a large value would keep the whole file alive
+        let command = Bytes::copy_from_slice(&data[start..pos]);
+        let command = Frame::parse(&mut Cursor::new(&command)).map_err(bad_format)?;
+
+        let args = command_args(command).ok_or("Bad file format reading the append only file")?;
+        let res = if server.in_transaction {
+            server.transaction(args).await
+        } else {
+            if args
+                .front()
+                .is_some_and(|v| v.eq_ignore_ascii_case("multi"))
+            {
+                multi_start = start;
+            }
+            server.execute(args).await
+        };
+        if let Err(e) = res {
+            println!("Error replaying the append only file: {e}");
+        }
+    }
+    if server.in_transaction {
+        println!("Revert incomplete MULTI/EXEC transaction in AOF file");
+        (truncated, pos) = (true, multi_start);
+    }
+    if truncated {
+        println!(
+            "!!! Warning: short read while loading the AOF file {}!!!",
+            path.display()
+        );
+        println!("AOF loaded anyway because aof-load-truncated is enabled");
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(pos as u64)?;
+    }
+    // replaying the file is not a change to the dataset
+    server.store.lock().await.info.dirty = 0;
+    Ok(())
+}
+
+/// Flushes the append only file once every second for the everysec policy
+pub async fn aof_cron(store: Arc<Mutex<Store>>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut store = store.lock().await;
+        if let Some(aof) = store.aof.as_mut()
+            && let Err(e) = aof.fsync()
+        {
+            println!("Error fsyncing the append only file: {e}");
+        }
+    }
+}
+
+/// Checks the `save <seconds> <changes>` rules once every second
+/// and starts a background save when any of them is satisfied
+pub async fn snapshot_cron(store: Arc<Mutex<Store>>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let (dirty, last_save, params) = {
+            let store = store.lock().await;
+            if store.info.bgsave_in_progress {
+                continue;
+            }
+            (
+                store.info.dirty,
+                store.info.last_save,
+                store.info.save_params.clone(),
+            )
+        };
+        let elapsed = unix_time().saturating_sub(last_save);
+        if let Some((seconds, changes)) = params
+            .into_iter()
+            .find(|&(seconds, changes)| dirty >= changes && elapsed >= seconds)
+        {
+            println!("{changes} changes in {seconds} seconds. Saving...");
+            if let Err(e) = background_save(store.clone()).await {
+                println!("{e}");
+            }
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Database;
+
+    fn command(args: &[&str]) -> String {
+        let mut res = format!("*{}\r\n", args.len());
+        for arg in args {
+            res.push_str(&format!("${}\r\n{arg}\r\n", arg.len()));
+        }
+        res
+    }
+
+    /// Loads `data` as the append only file of an empty store, returns the
+    /// store and what is left of the file
+    async fn load(name: &str, data: &str) -> (Store, String) {
+        let path = std::env::temp_dir().join(format!("{name}-{}.aof", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let store = Store {
+            databases: vec![Database::default(), Database::default()],
+            ..Store::default()
+        };
+        let store = Arc::new(Mutex::new(store));
+        load_aof(store.clone(), path.clone()).await.unwrap();
+        let file = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let store = Arc::try_unwrap(store).ok().unwrap().into_inner();
+        (store, file)
+    }
+
+    fn string(store: &Store, db: usize, key: &str) -> Option<String> {
+        let value = store.databases[db].get(key.as_bytes())?;
+        Some(value.string()?.to_string())
+    }
+
+    #[tokio::test]
+    async fn truncated_last_command_is_dropped() {
+        let complete = command(&["SET", "a", "1"]) + &command(&["SELECT", "1"]);
+        let set = command(&["SET", "b", "2"]);
+        let (store, file) = load("truncated", &(complete.clone() + &set[..set.len() - 3])).await;
+        assert_eq!(string(&store, 0, "a").as_deref(), Some("1"));
+        assert!(store.databases[1].kv.is_empty());
+        assert_eq!(file, complete);
+        assert_eq!(store.info.dirty, 0);
+
+        // a complete file is left as it is
+        let complete = complete + &set;
+        let (store, file) = load("complete", &complete).await;
+        assert_eq!(string(&store, 1, "b").as_deref(), Some("2"));
+        assert_eq!(file, complete);
+    }
+
+    #[tokio::test]
+    async fn multi_without_exec_is_reverted() {
+        let applied = command(&["SET", "a", "1"])
+            + &command(&["MULTI"])
+            + &command(&["SET", "b", "2"])
+            + &command(&["EXEC"]);
+        let pending = command(&["MULTI"]) + &command(&["SET", "c", "3"]) + &command(&["DEL", "a"]);
+        let (store, file) = load("multi", &(applied.clone() + &pending)).await;
+        assert_eq!(string(&store, 0, "a").as_deref(), Some("1"));
+        assert_eq!(string(&store, 0, "b").as_deref(), Some("2"));
+        assert!(string(&store, 0, "c").is_none());
+        assert_eq!(file, applied);
+
+        // the transaction is also reverted when its last command is cut short
+        let (store, file) = load(
+            "multi-truncated",
+            &(applied.clone() + &pending[..pending.len() - 1]),
+        )
+        .await;
+        assert!(string(&store, 0, "c").is_none());
+        assert_eq!(file, applied);
+    }
 }
@@ -1,5 +1,6 @@
 use super::{Args, server::Server};
 use crate::Error;
+use crate::bytestring::ByteString;
 use crate::frame::Frame;
 use crate::server::errors::wrong_num_arguments;
 use tokio::sync::{broadcast, oneshot};
@@ -27,12 +28,14 @@ impl Server {
         let (utx, mut urx) = oneshot::channel();
         self.unsubscribe.insert(key.clone(), utx);
         let output = self.output.clone();
+        // the messages are pushed in the protocol the client spoke when it subscribed
+        let protocol = self.protocol;
         tokio::spawn(async move {
             loop {
                 tokio::select! {
                     value = receiver.recv() => {
                         if let Ok(value) = value {
-                            if let Err(e) = output.send(value).await {
+                            if let Err(e) = output.send(value.into_protocol(protocol)).await {
                                 println!("stopping subscription channel due to {e}");
                                 break
                             }
@@ -49,7 +52,8 @@ impl Server {
 
         self.subscription_count += 1;
         let sub: Frame = "subscribe".to_string().into();
-        Ok(vec![sub, key.into(), self.subscription_count.into()].into())
+        let reply = vec![sub, key.into(), self.subscription_count.into()];
+        Ok(Frame::Push(reply))
     }
 
     /// Posts a message to the given channel.
@@ -64,7 +68,8 @@ impl Server {
         let key = args.pop_front().ok_or(wrong_num_arguments("subscribe"))?;
         let msg = args.pop_front().ok_or(wrong_num_arguments("subscribe"))?;
         if let Some(channel) = self.store.lock().await.channels.get(&key) {
-            channel.send(vec!["message".to_string(), key, msg].into())?;
+            let message = ["message".into(), key, msg].map(|v: ByteString| v.into());
+            channel.send(Frame::Push(message.into()))?;
             Ok(channel.receiver_count().into())
         } else {
             Ok(0usize.into())
@@ -84,6 +89,7 @@ impl Server {
         let _ = self.unsubscribe.remove(&key).unwrap().send(true);
         self.subscription_count -= 1;
         let sub: Frame = "unsubscribe".to_string().into();
-        Ok(vec![sub, key.into(), self.subscription_count.into()].into())
+        let reply = vec![sub, key.into(), self.subscription_count.into()];
+        Ok(Frame::Push(reply))
     }
 }
@@ -2,18 +2,19 @@ use super::Result;
 use super::errors::*;
 use super::server::{Server, SlaveConfig};
 use crate::Store;
-use crate::frame::encode::AsBytes;
 use crate::frame::{Frame, TypedNone};
+use crate::rdb::encode::RDBEncoder;
 use crate::server::Args;
-use bytes::{Bytes, BytesMut};
+use crate::slave;
+use crate::store::{FailoverState, ReplState, Role, Slave};
+use bytes::Bytes;
 use rand::random_range;
+use std::io::Cursor;
 use std::mem;
-use std::ops::AddAssign;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
-use tokio::sync::{broadcast, mpsc};
-
-const EMPTY_RDB: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
+use tokio::sync::broadcast::{Receiver, error::RecvError};
 
 impl Server {
     pub async fn replconf(&mut self, mut args: Args) -> Result {
@@ -24,13 +25,9 @@ impl Server {
         match key.as_str() {
             "ack" => {
                 let offset = args.pop_front().ok_or(wrong_num_arguments("replconf"))?;
-                // let offset = self.store.lock().await.info.send_offset;
+                let offset = offset.parse().map_err(|_| out_of_range())?;
                 let slave_id = self.slave_id;
-                self.store
-                    .lock()
-                    .await
-                    .slave_offsets
-                    .insert(slave_id, offset.parse().unwrap());
+                self.store.lock().await.acknowledge(slave_id, offset);
                 Ok("OK".into())
             }
             "getack" => {
@@ -61,167 +58,522 @@ impl Server {
                         capabilities: vec![],
                     })
                     .capabilities
-                    .push(v);
+                    .push(v.to_string());
                 Ok("OK".into())
             }
             _ => Ok(Frame::None(TypedNone::Nil)),
         }
     }
 
-    pub async fn psync(&mut self, _: Args) -> Result {
-        // tell that we are going to full resync
-        let repl_id = self.store.lock().await.info.master_id.clone();
-        let sync_status: Frame = format!("FULLRESYNC {repl_id} 0").as_str().into();
-        self.output.send(sync_status).await?;
+    /// Initiates a replication stream from the master. The offset is the one of the
+    /// first byte the replica still needs, one past what it processed. When the
+    /// replication id is ours, or the one of our previous master up to the offset we
+    /// followed it, and the offset is still inside of the backlog the replica continues
+    /// from there, otherwise it is sent a snapshot of the whole dataset.
+    ///
+    /// FAILOVER is sent by our master during a coordinated failover, we are promoted
+    /// and the old master carries on as our replica.
+    /// ```
+    /// PSYNC replicationid offset [FAILOVER]
+    /// ```
+    pub async fn psync(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("psync");
+        let repl_id = args.pop_front().ok_or(err())?.to_string();
+        let offset: isize = args
+            .pop_front()
+            .ok_or(err())?
+            .parse()
+            .map_err(|_| out_of_range())?;
+        let failover = match args.pop_front() {
+            Some(v) if v.eq_ignore_ascii_case("failover") => true,
+            Some(_) => return Err(syntax_error().into()),
+            None => false,
+        };
 
-        // send the empty rdb file
-        let res = hex::decode(EMPTY_RDB).unwrap();
-        self.output.send(Frame::RDB(Bytes::from(res))).await?;
+        // the snapshot and the subscription are taken under the same lock
+        // so that every write after the snapshot reaches the replica
+        let mut store = self.store.lock().await;
+        if failover {
+            if store.info.role != Role::Slave || store.info.master_id != repl_id {
+                return Err(
+                    make_io_error("ERR PSYNC FAILOVER replid must match my replid.").into(),
+                );
+            }
+            println!("Failover request received for replid {repl_id}.");
+            store.promote();
+        }
+        if store.info.role == Role::Slave && store.info.repl_state != ReplState::Connected {
+            return Err(make_io_error(
+                "NOMASTERLINK Can't SYNC while not connected with my master",
+            )
+            .into());
+        }
+        let mut reader = store
+            .broadcast
+            .clone()
+            .ok_or("invalid broadcast configuration")?
+            .subscribe();
+        // what the replica processed, the backlog has to hold whole frames from there
+        // on as nothing can be taken back once CONTINUE is sent
+        let processed = offset.checked_sub(1).and_then(|v| usize::try_from(v).ok());
+        let backlog = match (store.backlog.as_ref(), processed) {
+            (Some(backlog), Some(processed)) if store.same_history(&repl_id, processed) => {
+                let frames = backlog.since(processed).and_then(|v| parse_frames(&v));
+                frames.map(|v| (processed, v))
+            }
+            _ => None,
+        };
+        let master_id = store.info.master_id.clone();
+
+        let offset = if let Some((offset, frames)) = backlog {
+            drop(store);
+            let status: Frame = format!("CONTINUE {master_id}").as_str().into();
+            self.output.send(status).await?;
+            for frame in frames {
+                self.output.send(frame).await?;
+            }
+            offset
+        } else {
+            let rdb = RDBEncoder::encode(&store);
+            let offset = store.info.send_offset;
+            // make sure the new replica is told which database the next command is for,
+            // a replica can't change the stream of its master so the snapshot tells it
+            if store.info.role == Role::Master {
+                store.replication_db = None;
+            }
+            drop(store);
+
+            // tell that we are going to full resync
+            let status: Frame = format!("FULLRESYNC {master_id} {offset}").as_str().into();
+            self.output.send(status).await?;
+            self.output.send(Frame::Rdb(rdb)).await?;
+            offset
+        };
 
         let (mut tx, _rx) = tokio::sync::mpsc::channel(1);
         // basically only thing that we will be sending on this connection will be
         // the broadcast commands nothing more, not even the responses will be sent
         mem::swap(&mut self.output, &mut tx);
-        let mut reader = self
-            .store
-            .lock()
-            .await
-            .broadcast
-            .clone()
-            .ok_or("invalid broadcast configuration")?
-            .subscribe();
 
-        let px = tx.clone();
+        let store = self.store.clone();
         tokio::spawn(async move {
+            // the offset of the stream the replica was sent up to
+            let mut sent = offset;
             loop {
-                if let Ok(v) = reader.recv().await {
-                    if let Err(e) = tx.send(v).await {
+                let frames = match reader.recv().await {
+                    Ok((end, frame)) => {
+                        sent = end;
+                        vec![frame]
+                    }
+                    // The replica fell behind the channel, what it missed is sent
+                    // from the backlog for as long as the backlog holds it
+                    Err(RecvError::Lagged(_)) => match catch_up(&store, &mut reader, sent).await {
+                        Some((end, frames)) => {
+                            sent = end;
+                            frames
+                        }
+                        None => {
+                            println!("replica fell out of the backlog, disconnecting it");
+                            break;
+                        }
+                    },
+                    Err(RecvError::Closed) => break,
+                };
+                for frame in frames {
+                    if let Err(e) = tx.send(frame).await {
                         println!("stopping sending because {e}");
-                        break;
+                        return;
                     }
-                } else {
-                    break;
                 }
             }
         });
 
-        let ack_reader = self
-            .store
+        let slave_id = random_range(1..usize::MAX);
+        self.slave_id = slave_id;
+        let port = self.slave_config.as_ref().map_or(0, |v| v.port);
+        self.store
             .lock()
             .await
-            .get_ack_channel
-            .clone()
-            .ok_or("invalid get_ack configuration")?
-            .subscribe();
+            .slave_offsets
+            .insert(slave_id, Slave::new(self.addr.clone(), port, offset));
 
-        let slave_id = random_range(1..usize::MAX);
-        self.slave_id = slave_id;
-        self.store.lock().await.slave_offsets.insert(slave_id, 0);
+        Ok("OK".into())
+    }
 
-        let store = self.store.clone();
+    /// The REPLICAOF command can change the replication settings of a replica on the fly.
+    ///
+    /// If a Redis server is already acting as replica, the command REPLICAOF NO ONE will
+    /// turn off the replication, turning the Redis server into a MASTER. In the proper form
+    /// REPLICAOF hostname port will make the server a replica of another server listening
+    /// at the specified hostname and port.
+    /// ```
+    /// REPLICAOF host port | NO ONE
+    /// ```
+    pub async fn replicaof(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("replicaof");
+        let host = args.pop_front().ok_or(err())?.to_string();
+        let port = args.pop_front().ok_or(err())?;
+        if !args.is_empty() {
+            return Err(err().into());
+        }
 
-        tokio::spawn(async move { Server::get_ack(store, slave_id, ack_reader, px).await });
+        let mut store = self.store.lock().await;
+        // the replicas of a cluster are set up with CLUSTER REPLICATE
+        if store.cluster.is_some() {
+            return Err(make_io_error("ERR REPLICAOF not allowed in cluster mode.").into());
+        }
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            if store.info.role == Role::Slave {
+                store.promote();
+                println!("MASTER MODE enabled (user request from '{}')", self.user);
+            }
+            return Ok("OK".into());
+        }
 
+        let port: u16 = port
+            .parse()
+            .map_err(|_| make_io_error("ERR Invalid master port"))?;
+        if store.info.role == Role::Slave
+            && store.info.master_host.eq_ignore_ascii_case(&host)
+            && store.info.master_port == port
+        {
+            return Ok("OK Already connected to specified master".into());
+        }
+        store.demote(host.clone(), port);
+        store.master_link = Some(slave::start(self.store.clone(), host.clone(), port));
+        println!(
+            "REPLICAOF {host}:{port} enabled (user request from '{}')",
+            self.user
+        );
         Ok("OK".into())
     }
 
-    pub async fn get_ack(
-        store: Arc<Mutex<Store>>,
-        slave_id: usize,
-        mut ack: broadcast::Receiver<Frame>,
-        sender: mpsc::Sender<Frame>,
-    ) {
-        loop {
-            if let Ok(v) = ack.recv().await {
-                // println!("got instructions to send get ack");
-                let mut store = store.lock().await;
-                let asked_offset = *store.slave_asked_offsets.get(&slave_id).unwrap();
-                let send_offset = store.info.send_offset;
-                if send_offset > asked_offset {
-                    println!("asked for ack but don't know if she got the message :(");
-                    if let Err(e) = sender.send(v).await {
-                        println!("stopping sending because {e}");
-                        break;
+    /// Provide information on the role of a Redis instance in the context of replication,
+    /// by returning if the instance is currently a master or a replica. A master replies
+    /// with its offset and its replicas, a replica with the address of its master, the
+    /// state of the link and the offset it has processed.
+    /// ```
+    /// ROLE
+    /// ```
+    pub async fn role(&mut self, _: Args) -> Result {
+        let store = self.store.lock().await;
+        let info = &store.info;
+        let res: Vec<Frame> = match info.role {
+            Role::Master => {
+                let slaves: Vec<Frame> = store
+                    .slave_offsets
+                    .values()
+                    .map(|v| vec![v.ip.clone(), v.port.to_string(), v.offset.to_string()].into())
+                    .collect();
+                vec![
+                    "master".to_string().into(),
+                    info.send_offset.into(),
+                    slaves.into(),
+                ]
+            }
+            Role::Slave => {
+                let offset = match info.repl_state {
+                    ReplState::Connected => info.recv_offset as isize,
+                    _ => -1,
+                };
+                vec![
+                    "slave".to_string().into(),
+                    info.master_host.clone().into(),
+                    (info.master_port as usize).into(),
+                    info.repl_state.to_string().into(),
+                    offset.into(),
+                ]
+            }
+        };
+        Ok(res.into())
+    }
+
+    /// This command will start a coordinated failover between the currently-connected-to
+    /// master and one of its replicas. The writes of the clients are paused until the
+    /// replica has processed the whole replication stream, then the replica is asked to
+    /// take over and the master carries on as its replica.
+    ///
+    /// Without TO the first replica that catches up is picked. When the TIMEOUT passes
+    /// the failover is aborted, unless FORCE is given in which case the target is asked
+    /// to take over anyway. ABORT stops a failover that is going on.
+    /// ```
+    /// FAILOVER [TO host port [FORCE]] [ABORT] [TIMEOUT milliseconds]
+    /// ```
+    pub async fn failover(&mut self, mut args: Args) -> Result {
+        let (mut to, mut timeout, mut force, mut abort) = (None, None, false, false);
+        while let Some(option) = args.pop_front() {
+            match option.to_lowercase().as_str() {
+                "to" => {
+                    let host = args.pop_front().ok_or(syntax_error())?.to_string();
+                    let port: u16 = args
+                        .pop_front()
+                        .ok_or(syntax_error())?
+                        .parse()
+                        .map_err(|_| out_of_range())?;
+                    to = Some((host, port));
+                }
+                "timeout" => {
+                    let millis: u64 = args
+                        .pop_front()
+                        .ok_or(syntax_error())?
+                        .parse()
+                        .map_err(|_| out_of_range())?;
+                    if millis == 0 {
+                        return Err(
+                            make_io_error("ERR FAILOVER timeout must be greater than 0").into()
+                        );
                     }
-                } else {
-                    // println!("but send offset was {send_offset} and ask_offset was {asked_offset}");
+                    timeout = Some(Duration::from_millis(millis));
                 }
-                store
-                    .slave_asked_offsets
-                    .insert(slave_id, send_offset)
-                    .unwrap();
-            } else {
-                break;
+                "force" => force = true,
+                "abort" => abort = true,
+                _ => return Err(syntax_error().into()),
             }
         }
+
+        if abort {
+            if to.is_some() || timeout.is_some() || force {
+                return Err(syntax_error().into());
+            }
+            let mut store = self.store.lock().await;
+            if store.info.failover_state == FailoverState::NoFailover {
+                return Err(make_io_error("ERR FAILOVER is not in progress.").into());
+            }
+            store.abort_failover();
+            println!("FAILOVER aborted (user request from '{}')", self.user);
+            return Ok("OK".into());
+        }
+        if force && (to.is_none() || timeout.is_none()) {
+            return Err(make_io_error(
+                "ERR FAILOVER with force option requires both a timeout and target HOST and PORT.",
+            )
+            .into());
+        }
+
+        // the replicas are known by the address of their connection
+        let addresses = match to.as_ref() {
+            Some((host, port)) => tokio::net::lookup_host((host.as_str(), *port))
+                .await
+                .map(|v| v.map(|a| a.ip().to_string()).collect())
+                .unwrap_or_default(),
+            None => vec![],
+        };
+
+        let mut store = self.store.lock().await;
+        if store.info.role == Role::Slave {
+            return Err(
+                make_io_error("ERR FAILOVER is not valid when server is a replica.").into(),
+            );
+        }
+        if store.info.failover_state != FailoverState::NoFailover {
+            return Err(make_io_error("ERR FAILOVER already in progress.").into());
+        }
+        if store.slave_offsets.is_empty() {
+            return Err(make_io_error("ERR FAILOVER requires connected replicas.").into());
+        }
+        let target = match to {
+            Some((_, port)) => {
+                let target = store
+                    .slave_offsets
+                    .iter()
+                    .find(|(_, v)| v.port == port && addresses.contains(&v.ip))
+                    .map(|(&id, v)| (id, v.ip.clone(), v.port));
+                let target = target.ok_or(make_io_error(
+                    "ERR FAILOVER target HOST and PORT is not a replica.",
+                ))?;
+                Some(target)
+            }
+            None => None,
+        };
+
+        store.info.failover_state = FailoverState::WaitingForSync;
+        let deadline = timeout.map(|v| Instant::now() + v);
+        let task = tokio::spawn(run_failover(self.store.clone(), target, deadline, force));
+        store.failover = Some(task.abort_handle());
+        println!("FAILOVER requested (user request from '{}')", self.user);
+        Ok("OK".into())
+    }
+
+    /// A deprecated alias of REPLICAOF.
+    /// ```
+    /// SLAVEOF host port | NO ONE
+    /// ```
+    pub async fn slaveof(&mut self, args: Args) -> Result {
+        self.replicaof(args).await
     }
 
+    /// This command blocks the current client until all the previous write commands are
+    /// successfully transferred and acknowledged by at least the number of replicas specified
+    /// in the numreplicas argument. If the value you specify for the timeout argument (in
+    /// milliseconds) is reached, the command returns even if the specified number of replicas
+    /// were not yet reached. A timeout of 0 means to block forever.
+    /// ```
+    /// WAIT numreplicas timeout
+    /// ```
     pub async fn wait(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("wait");
         let count_replicas: usize = args
             .pop_front()
-            .ok_or(wrong_num_arguments("wait"))?
+            .ok_or(err())?
             .parse()
-            .map_err(|_| syntax_error())?;
-        let timeout: u128 = args
+            .map_err(|_| out_of_range())?;
+        let timeout: i64 = args
             .pop_front()
-            .ok_or(wrong_num_arguments("wait"))?
+            .ok_or(err())?
             .parse()
-            .map_err(|_| syntax_error())?;
+            .map_err(|_| make_io_error("ERR timeout is not an integer or out of range"))?;
+        if timeout < 0 {
+            return Err(make_io_error("ERR timeout is negative").into());
+        }
+        let deadline =
+            (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout as u64));
 
-        let message: Frame = vec!["REPLCONF".to_string(), "GETACK".into(), "*".into()].into();
+        // every write sent before the WAIT has to be acknowledged
+        let (target, acks) = {
+            let mut store = self.store.lock().await;
+            if store.info.role == Role::Slave {
+                return Err(
+                    make_io_error("ERR WAIT cannot be used with replica instances.").into(),
+                );
+            }
+            let target = store.info.send_offset;
+            let acknowledged = store.replicas_at(target);
+            if acknowledged >= count_replicas {
+                return Ok(acknowledged.into());
+            }
+            // the request for acks goes through the replication stream itself
+            let message: Frame = vec!["REPLCONF".to_string(), "GETACK".into(), "*".into()].into();
+            store.feed_replicas(message);
+            (target, store.slave_acks.clone())
+        };
 
-        let now = std::time::Instant::now();
+        loop {
+            // registered before checking so that an ack in between is not missed
+            let notified = acks.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
 
-        let get_ack_channel = self
-            .store
-            .lock()
-            .await
-            .get_ack_channel
-            .clone()
-            .ok_or("broadcast not setup please fix")?;
+            let acknowledged = self.store.lock().await.replicas_at(target);
+            if acknowledged >= count_replicas {
+                return Ok(acknowledged.into());
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline.into(), notified)
+                        .await
+                        .is_err()
+                    {
+                        return Ok(self.store.lock().await.replicas_at(target).into());
+                    }
+                }
+                None => notified.await,
+            }
+        }
+    }
+}
 
-        let kv: Vec<_> = self
-            .store
-            .lock()
-            .await
-            .slave_offsets
-            .clone()
-            .into_iter()
-            .collect();
+/// Waits for the target of the failover, or any replica when there is none, to process
+/// the whole replication stream and then makes this node its replica, asking it to take
+/// over. Writes are paused meanwhile so the replicas can catch up.
+async fn run_failover(
+    store: Arc<Mutex<Store>>,
+    target: Option<(usize, String, u16)>,
+    deadline: Option<Instant>,
+    force: bool,
+) {
+    let acks = store.lock().await.slave_acks.clone();
+    let caught_up = loop {
+        // registered before checking so that an ack in between is not missed
+        let notified = acks.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        {
+            let store = store.lock().await;
+            let offset = store.info.send_offset;
+            let replica = store
+                .slave_offsets
+                .iter()
+                .find(|&(id, v)| v.offset >= offset && target.as_ref().is_none_or(|t| t.0 == *id));
+            if let Some((_, v)) = replica {
+                break Some((v.ip.clone(), v.port));
+            }
+        }
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline.into(), notified)
+                    .await
+                    .is_err()
+                {
+                    break None;
+                }
+            }
+            None => notified.await,
+        }
+    };
 
-        for (slav, v) in kv {
-            self.store.lock().await.slave_asked_offsets.insert(slav, v);
+    let mut guard = store.lock().await;
+    let (host, port) = match (caught_up, target) {
+        (Some(replica), _) => replica,
+        (None, Some((_, host, port))) if force => (host, port),
+        _ => {
+            println!("FAILOVER timed out waiting for the replicas to catch up");
+            guard.end_failover();
+            return;
         }
+    };
+    println!("FAILOVER to {host}:{port} in progress");
+    guard.failover = None;
+    guard.info.failover_state = FailoverState::InProgress;
+    guard.demote(host.clone(), port);
+    guard.master_link = Some(slave::failover(store.clone(), host, port));
+}
 
-        let _ = get_ack_channel.send(message.clone());
+/// The part of the replication stream after `offset` read from the backlog, along
+/// with the offset it ends at. `reader` is subscribed again with the store locked,
+/// so it continues right after the backlog. `None` once the backlog doesn't go back
+/// to `offset` or the replicas were reset.
+async fn catch_up(
+    store: &Mutex<Store>,
+    reader: &mut Receiver<(usize, Frame)>,
+    offset: usize,
+) -> Option<(usize, Vec<Frame>)> {
+    let store = store.lock().await;
+    let subscriber = store.broadcast.as_ref()?.subscribe();
+    if !subscriber.same_channel(reader) {
+        return None;
+    }
+    let data = store.backlog.as_ref()?.since(offset)?;
+    *reader = subscriber;
+    let end = store.info.send_offset;
+    drop(store);
 
-        let mut b = BytesMut::new();
-        message.encode_bytes(&mut b);
+    Some((end, parse_frames(&data)?))
+}
 
-        loop {
-            let send_offset = self.store.lock().await.info.send_offset;
-            let ack_received = self
-                .store
-                .lock()
-                .await
-                .slave_offsets
-                .values_mut()
-                .filter(|&&mut x| x == send_offset)
-                .count();
+/// Splits a part of the replication stream into its frames, `None` when it doesn't
+/// start and end at the boundaries of frames
+fn parse_frames(data: &Bytes) -> Option<Vec<Frame>> {
+    let mut cursor = Cursor::new(data);
+    let mut frames = vec![];
+    while (cursor.position() as usize) < data.len() {
+        frames.push(Frame::parse(&mut cursor).ok()?);
+    }
+    Some(frames)
+}
 
-            if ack_received >= count_replicas || now.elapsed().as_millis() > timeout {
-                self.store
-                    .lock()
-                    .await
-                    .slave_offsets
-                    .values_mut()
-                    .filter(|&&mut x| x == send_offset)
-                    .for_each(|x| {
-                        x.add_assign(b.len());
-                    });
-                self.store.lock().await.info.send_offset += b.len();
-                return Ok(ack_received.into());
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_frames() {
+        let stream = Bytes::from_static(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*1\r\n$4\r\nPING\r\n");
+        assert_eq!(parse_frames(&stream).unwrap().len(), 2);
+        assert!(parse_frames(&stream.slice(23..)).is_some());
+        assert!(parse_frames(&Bytes::new()).unwrap().is_empty());
+        // one byte off either way of a frame boundary
+        assert!(parse_frames(&stream.slice(22..)).is_none());
+        assert!(parse_frames(&stream.slice(24..)).is_none());
+        assert!(parse_frames(&stream.slice(..stream.len() - 1)).is_none());
     }
 }
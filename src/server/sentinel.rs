@@ -0,0 +1,176 @@
+use super::errors::*;
+use super::server::Server;
+use super::{Args, Result};
+use crate::frame::{Frame, TypedNone};
+use crate::sentinel::{Master, Sentinel};
+use std::time::Instant;
+
+impl Server {
+    /// Inspects and drives the state of a node running in sentinel mode.
+    ///
+    /// GET-MASTER-ADDR-BY-NAME is what clients use to find the current master,
+    /// IS-MASTER-DOWN-BY-ADDR is used by the sentinels to agree on a master
+    /// being down and to elect the one that fails it over.
+    /// ```
+    /// SENTINEL GET-MASTER-ADDR-BY-NAME master-name
+    /// SENTINEL MASTERS
+    /// SENTINEL MASTER master-name
+    /// SENTINEL REPLICAS | SLAVES master-name
+    /// SENTINEL SENTINELS master-name
+    /// SENTINEL IS-MASTER-DOWN-BY-ADDR ip port current-epoch runid
+    /// SENTINEL MYID
+    /// ```
+    pub async fn sentinel(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("sentinel");
+        let mut store = self.store.lock().await;
+        let sentinel = store.sentinel.as_mut().ok_or(make_io_error(
+            "ERR SENTINEL is only available in sentinel mode",
+        ))?;
+        let subcommand = args.pop_front().ok_or(err())?.to_lowercase();
+        let mut name = || args.pop_front().map(|v| v.to_string()).ok_or(err());
+        let res: Frame = match subcommand.as_str() {
+            "get-master-addr-by-name" => match sentinel.masters.get(&name()?) {
+                Some(master) => vec![master.addr.0.clone(), master.addr.1.to_string()].into(),
+                None => Frame::None(TypedNone::Array),
+            },
+            "masters" => {
+                let masters: Vec<Frame> = sentinel.masters.values().map(describe).collect();
+                masters.into()
+            }
+            "master" => describe(master(sentinel, &name()?)?),
+            "replicas" | "slaves" => {
+                let master = master(sentinel, &name()?)?;
+                let replicas: Vec<Frame> = master
+                    .replicas
+                    .iter()
+                    .map(|((ip, port), replica)| {
+                        let down = replica
+                            .last_ok
+                            .is_none_or(|v| v.elapsed() > master.down_after);
+                        let link = if replica.link_up { "ok" } else { "err" };
+                        vec![
+                            "name".to_string(),
+                            format!("{ip}:{port}"),
+                            "ip".into(),
+                            ip.clone(),
+                            "port".into(),
+                            port.to_string(),
+                            "flags".into(),
+                            if down { "s_down,slave" } else { "slave" }.into(),
+                            "master-link-status".into(),
+                            link.into(),
+                            "slave-repl-offset".into(),
+                            replica.offset.to_string(),
+                        ]
+                        .into()
+                    })
+                    .collect();
+                replicas.into()
+            }
+            "sentinels" => {
+                let master = master(sentinel, &name()?)?;
+                let sentinels: Vec<Frame> = master
+                    .sentinels
+                    .iter()
+                    .map(|(runid, peer)| {
+                        vec![
+                            "name".to_string(),
+                            runid.clone(),
+                            "ip".into(),
+                            peer.addr.0.clone(),
+                            "port".into(),
+                            peer.addr.1.to_string(),
+                            "runid".into(),
+                            runid.clone(),
+                            "last-hello-message".into(),
+                            since(peer.last_hello),
+                        ]
+                        .into()
+                    })
+                    .collect();
+                sentinels.into()
+            }
+            "is-master-down-by-addr" => {
+                let ip = name()?;
+                let port = name()?.parse().map_err(|_| out_of_range())?;
+                let epoch = name()?.parse().map_err(|_| out_of_range())?;
+                let runid = name()?;
+                let addr = (ip, port);
+                let down = sentinel.master_by_addr(&addr).is_some_and(|v| v.s_down);
+                // "*" only asks for the state of the master, anything else asks for a vote
+                let (leader, leader_epoch) = match runid.as_str() {
+                    "*" => None,
+                    _ => sentinel.vote(&addr, epoch, &runid),
+                }
+                .unwrap_or(("*".into(), 0));
+                let reply: Vec<Frame> = vec![
+                    (down as usize).into(),
+                    leader.into(),
+                    (leader_epoch as usize).into(),
+                ];
+                reply.into()
+            }
+            "myid" => sentinel.myid.clone().into(),
+            _ => {
+                return Err(make_io_error(&format!(
+                    "ERR Unknown sentinel subcommand '{subcommand}'"
+                ))
+                .into());
+            }
+        };
+        Ok(res)
+    }
+}
+
+fn master<'a>(
+    sentinel: &'a Sentinel,
+    name: &str,
+) -> std::result::Result<&'a Master, std::io::Error> {
+    sentinel
+        .masters
+        .get(name)
+        .ok_or(make_io_error("ERR No such master with that name"))
+}
+
+/// Milliseconds elapsed since `time`
+fn since(time: Instant) -> String {
+    time.elapsed().as_millis().to_string()
+}
+
+fn describe(master: &Master) -> Frame {
+    let mut flags = vec!["master"];
+    if master.s_down {
+        flags.push("s_down");
+    }
+    if master.o_down {
+        flags.push("o_down");
+    }
+    if master.failover_start.is_some() {
+        flags.push("failover_in_progress");
+    }
+    vec![
+        "name".to_string(),
+        master.name.clone(),
+        "ip".into(),
+        master.addr.0.clone(),
+        "port".into(),
+        master.addr.1.to_string(),
+        "flags".into(),
+        flags.join(","),
+        "last-ok-ping-reply".into(),
+        since(master.last_ok),
+        "num-slaves".into(),
+        master.replicas.len().to_string(),
+        "num-other-sentinels".into(),
+        master.sentinels.len().to_string(),
+        "quorum".into(),
+        master.quorum.to_string(),
+        "config-epoch".into(),
+        master.config_epoch.to_string(),
+        "down-after-milliseconds".into(),
+        master.down_after.as_millis().to_string(),
+        "failover-timeout".into(),
+        master.failover_timeout.as_millis().to_string(),
+    ]
+    .into()
+}
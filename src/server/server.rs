@@ -1,30 +1,59 @@
+use super::command::{self, ADMIN, Command, MAY_REPLICATE, WRITE};
 use super::errors::*;
 use super::Args;
+use super::persistence::unix_time;
+use super::string::expire_if_needed;
+use crate::bytestring::ByteString;
 use crate::frame::{encode::AsBytes, Frame};
 use crate::parser::Parser;
-use crate::store::Store;
+use crate::store::{FailoverState, Role, Store};
 use crate::Error;
 use bytes::BytesMut;
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+
+/// The most bytes of replies collected before they are written to the connection
+const MAX_WRITE_BATCH: usize = 64 * 1024;
 
 pub struct Server {
+    /// unique id of the connection, as reported by HELLO and CLIENT ID
+    pub(crate) id: usize,
+    /// name given to the connection with CLIENT SETNAME
+    pub(crate) name: String,
+    /// the RESP version the client speaks, 2 until it switches with HELLO
+    pub(crate) protocol: u8,
     pub(crate) user: String,
     pub(crate) authenticated: bool,
     pub(crate) slave_id: usize,
+    /// whether this is the link with our master, the other replication
+    /// links are the connections of our own replicas
+    pub(crate) from_master: bool,
+    /// address of the client, empty for the pseudo client loading the append only file
+    pub(crate) addr: String,
+    /// whether this is the pseudo client loading the append only file, the commands
+    /// it replays are neither propagated nor written back to the file
+    pub(crate) loading: bool,
+    pub(crate) db: usize,
     pub(crate) subscription_count: usize,
     pub(crate) store: Arc<Mutex<Store>>,
     pub(crate) output: mpsc::Sender<Frame>,
     pub(crate) transaction: VecDeque<Args>,
-    pub(crate) unsubscribe: HashMap<String, oneshot::Sender<bool>>,
+    pub(crate) unsubscribe: HashMap<ByteString, oneshot::Sender<bool>>,
     pub(crate) slave_config: Option<SlaveConfig>,
     pub(crate) in_transaction: bool,
+    pub(crate) multi_propagation: Option<Vec<(usize, Frame, bool)>>,
+    /// set by ASKING for the next command only
+    pub(crate) asking: bool,
 }
 
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
+
 pub struct SlaveConfig {
     pub port: u16,
     pub capabilities: Vec<String>,
@@ -50,7 +79,14 @@ macro_rules! dispatch {
 impl Server {
     pub fn new(store: Arc<Mutex<Store>>, output: mpsc::Sender<Frame>, slave_id: usize) -> Self {
         Server {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            name: String::new(),
+            protocol: 2,
             slave_id,
+            addr: String::new(),
+            from_master: false,
+            loading: false,
+            db: 0,
             store,
             output,
             user: "default".into(),
@@ -59,7 +95,9 @@ impl Server {
             unsubscribe: HashMap::new(),
             subscription_count: 0,
             in_transaction: false,
+            multi_propagation: None,
             slave_config: None,
+            asking: false,
         }
     }
 
@@ -68,32 +106,56 @@ impl Server {
         stream: TcpStream,
         buffer: BytesMut,
         slave_id: usize,
-    ) {
+    ) -> JoinHandle<Result<(), Error>> {
+        tokio::spawn(Server::run(store, stream, buffer, slave_id))
+    }
+
+    /// Serves the connection until it is closed, dropping the future
+    /// closes the connection as well
+    pub async fn run(
+        store: Arc<Mutex<Store>>,
+        stream: TcpStream,
+        buffer: BytesMut,
+        slave_id: usize,
+    ) -> Result<(), Error> {
+        let addr = stream
+            .peer_addr()
+            .map(|v| v.ip().to_string())
+            .unwrap_or_default();
         let (reader, mut writer) = stream.into_split();
         let (tx, mut rx): (mpsc::Sender<Frame>, mpsc::Receiver<Frame>) = mpsc::channel(64);
 
         tokio::spawn(async move {
-            loop {
-                if let Some(v) = rx.recv().await {
-                    // println!("sending {v:?} to connection");
-                    let mut b = BytesMut::new();
+            let mut b = BytesMut::new();
+            while let Some(v) = rx.recv().await {
+                // the replies that are already waiting, like those of pipelined
+                // commands, go out with a single write
+                v.encode_bytes(&mut b);
+                while b.len() < MAX_WRITE_BATCH
+                    && let Ok(v) = rx.try_recv()
+                {
                     v.encode_bytes(&mut b);
-                    if writer.write_all(b.freeze().as_ref()).await.is_err() {
-                        break;
-                    }
-                } else {
+                }
+                if writer.write_all(&b).await.is_err() {
                     break;
                 }
+                b.clear();
             }
         });
 
         tokio::time::sleep(Duration::from_millis(1)).await;
 
-        tokio::spawn(async move {
-            let parser = Parser::new(Box::new(reader), buffer);
-            let mut server = Server::new(store, tx, slave_id);
-            server.execution_thread(parser).await
-        });
+        // only the link with our master starts out as a replication link
+        if slave_id != 0 {
+            tokio::spawn(send_acks(store.clone(), tx.downgrade()));
+        }
+
+        let mut parser = Parser::new(Box::new(reader), buffer);
+        parser.inline = true;
+        let mut server = Server::new(store, tx, slave_id);
+        server.addr = addr;
+        server.from_master = slave_id != 0;
+        server.execution_thread(parser).await
     }
 
     async fn execution_thread(&mut self, mut parser: Parser) -> Result<(), Error> {
@@ -104,11 +166,21 @@ impl Server {
                 self.authenticated = true;
             }
         }
+        if self.from_master {
+            // a partial resynchronization continues in the database the last link was using
+            self.db = self.store.lock().await.replication_db.unwrap_or(0);
+        }
 
         loop {
-            let command = match parser.read_frame().await? {
-                Some(v) => v,
-                None => break,
+            let command = match parser.read_frame().await {
+                Ok(Some(v)) => v,
+                Ok(None) => break,
+                Err(e) => {
+                    // the connection is closed after telling the client what was wrong
+                    let resp = Frame::SimpleError(format!("ERR {e}"));
+                    let _ = self.output.send(resp).await;
+                    return Err(e);
+                }
             };
 
             let method = command
@@ -120,67 +192,73 @@ impl Server {
                 .unwrap_or("ping".into())
                 .to_lowercase();
 
-            if method != "auth" && !self.authenticated {
+            // HELLO can authenticate as well
+            if method != "auth" && method != "hello" && !self.authenticated {
                 let resp = Frame::SimpleError("NOAUTH Authentication required.".into());
                 self.output.send(resp).await?;
                 continue;
             }
 
-            if self.subscription_count > 0 && !subscriber_mode_command(&method) {
+            // RESP3 clients tell the messages from the replies, they can run any command
+            if self.subscription_count > 0
+                && self.protocol == 2
+                && !subscriber_mode_command(&method)
+            {
                 let resp = Frame::SimpleError(format!("ERR Can't execute '{method}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context").into());
                 self.output.send(resp).await?;
                 continue;
             }
 
-            if self.slave_id == 0 {
-                if is_write_command(&method) {
-                    let _ = self
-                        .store
-                        .lock()
-                        .await
-                        .broadcast
-                        .clone()
-                        .expect("broadcast not set properly")
-                        .send(command.clone());
-
-                    println!("increasing send_offset in write command");
-                    self.store.lock().await.info.send_offset += parser.parsed_bytes;
-                }
-            }
-
-            let args: Option<VecDeque<String>> = command
-                .array()
-                .ok_or("invalid command format!")?
-                .into_iter()
-                .map(|v| v.string())
-                .collect();
-            let args = args.ok_or("invalid command format!")?;
+            let forward = self.from_master.then(|| command.clone());
+            let args = command_args(command).ok_or("invalid command format!")?;
 
             #[cfg(debug_assertions)]
             println!("command: {args:?}");
 
+            if self.slave_id == 0
+                && let Some(e) = self.cluster_redirect(&args).await
+            {
+                self.output.send(Frame::SimpleError(e)).await?;
+                continue;
+            }
+            if self.slave_id == 0 {
+                self.pause_writes(&args).await;
+            }
+            if self.slave_id == 0
+                && let Some(e) = self.refuse_write(&args).await
+            {
+                self.output.send(Frame::SimpleError(e.into())).await?;
+                continue;
+            }
+
             let mut response = if self.in_transaction {
                 self.transaction(args).await
             } else {
                 self.execute(args).await
             };
+            if method != "asking" {
+                self.asking = false;
+            }
 
-            self.store.lock().await.info.recv_offset += parser.parsed_bytes;
+            // only the stream coming from our master moves the replication offset,
+            // our own replicas are sent that stream just as we got it
+            if let Some(frame) = forward {
+                let mut store = self.store.lock().await;
+                store.info.recv_offset += parser.parsed_bytes;
+                store.info.master_last_io = unix_time();
+                store.feed_replicas(frame);
+            }
 
-            if self.subscription_count > 0 {
-                response = response.map(|r| {
-                    if r.is_array() {
-                        r
-                    } else {
-                        let pong: Frame = "pong".to_string().into();
-                        vec![pong, "".to_string().into()].into()
-                    }
+            if self.subscription_count > 0 && self.protocol == 2 && method == "ping" {
+                response = response.map(|_| {
+                    let pong: Frame = "pong".to_string().into();
+                    vec![pong, "".to_string().into()].into()
                 });
             }
 
             if self.slave_id == 0 {
                 let _ = match response {
-                    Ok(v) => self.output.send(v).await,
+                    Ok(v) => self.output.send(v.into_protocol(self.protocol)).await,
                     Err(e) => {
                         let resp = Frame::SimpleError(format!("{e}"));
                         self.output.send(resp).await
@@ -188,26 +266,51 @@ impl Server {
                 };
             }
         }
+
+        // a replica that went away no longer counts for WAIT and min-replicas-to-write
+        if self.slave_id != 0 {
+            self.store.lock().await.slave_offsets.remove(&self.slave_id);
+        }
         Ok(())
     }
 
     pub(crate) async fn execute(&mut self, mut args: Args) -> Result<Frame, Error> {
+        let command = command::lookup(&args);
+        if let Some(command) = command
+            && !self.loading
+        {
+            let keys = command.keys(&args);
+            if !keys.is_empty() {
+                expire_if_needed(&mut *self.store.lock().await, self.db, keys);
+            }
+        }
+        let propagate = command
+            .filter(|v| v.is(WRITE | MAY_REPLICATE))
+            .map(|v| (v, args.clone()));
         let method = args.pop_front().ok_or(syntax_error())?;
-        dispatch!(self, method, args, {
+        let response = dispatch!(self, method, args, {
             // Ping pong commands
-            ping, echo, info,
+            ping, echo, info, hello, client,
             // string operations
             set, get, incr,
             // list operations
             rpush, lpush, lpop, blpop, lrange, llen,
             // sream operations
-            xadd, xdel, xlen, xrange, xread,
+            xadd, xdel, xlen, xrange, xread, xinfo,
             // transaction operations
             multi,
             // replication operations
-            replconf, psync, wait,
+            replconf, psync, wait, replicaof, slaveof, role, failover,
+            // sentinel
+            sentinel,
+            // cluster
+            cluster, asking, dump, restore, migrate,
             // config
             config, keys,
+            // persistence
+            save, bgsave, lastsave, bgrewriteaof,
+            // keyspace
+            select, swapdb, flushdb, del, unlink,
             // pubsub
             subscribe, unsubscribe, publish,
             // zset
@@ -217,19 +320,123 @@ impl Server {
             // acl
             acl, auth;
             "type" => self.redis_type(args).await,
+            "move" => self.move_key(args).await,
+            "restore-asking" => self.restore(args).await,
             "exec" => Err(make_io_error("ERR EXEC without MULTI").into()),
             "discard" => Err(make_io_error("ERR DISCARD without MULTI").into()),
-        })
+        });
+
+        if let Some((command, args)) = propagate
+            && let Ok(response) = &response
+        {
+            self.propagate(command, args, response).await;
+        }
+        response
+    }
+
+    /// Writes are paused while a failover is going on, once it is over they
+    /// are usually refused as this node is a replica of the new master by then
+    async fn pause_writes(&self, args: &Args) {
+        if !command::lookup(args).is_some_and(|v| v.is(WRITE | MAY_REPLICATE) && !v.is(ADMIN)) {
+            return;
+        }
+        let done = self.store.lock().await.failover_done.clone();
+        loop {
+            let notified = done.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.store.lock().await.info.failover_state == FailoverState::NoFailover {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Writes from our own clients are refused by read only replicas, as the keyspace
+    /// may only be changed by the replication stream coming from the master, and by
+    /// a master that has fewer good replicas than `min-replicas-to-write`
+    async fn refuse_write(&self, args: &Args) -> Option<&'static str> {
+        if !command::lookup(args).is_some_and(|v| v.is(WRITE) && !v.is(ADMIN)) {
+            return None;
+        }
+        let store = self.store.lock().await;
+        match store.info.role {
+            Role::Slave if store.info.replica_read_only => {
+                Some("READONLY You can't write against a read only replica.")
+            }
+            Role::Master if store.good_replicas() < store.info.min_replicas_to_write => {
+                Some("NOREPLICAS Not enough good replicas to write.")
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends a write command that was executed successfully to the replicas and to
+    /// the append only file. Inside of EXEC the commands are held back so that the
+    /// whole transaction can be sent wrapped in MULTI and EXEC.
+    async fn propagate(&mut self, command: &Command, args: Args, response: &Frame) {
+        if self.loading {
+            return;
+        }
+        let mut store = self.store.lock().await;
+        let Some(args) = command::rewrite(&store.databases[self.db], args, response) else {
+            return;
+        };
+        let write = command.is(WRITE);
+        if write {
+            store.info.dirty += 1;
+        }
+        let frame: Frame = Vec::from(args).into();
+        match self.multi_propagation.as_mut() {
+            Some(commands) => commands.push((self.db, frame, write)),
+            None => self.feed(&mut store, self.db, frame, write),
+        }
+    }
+
+    /// Appends a command to the replication stream, if it came from one of our
+    /// clients, and to the append only file when it modifies the keyspace
+    pub(crate) fn feed(&self, store: &mut Store, db: usize, command: Frame, write: bool) {
+        if write
+            && let Some(aof) = store.aof.as_mut()
+            && let Err(e) = aof.feed(db, &command)
+        {
+            println!("Error writing to the append only file: {e}");
+        }
+        if self.slave_id == 0 && store.info.role == Role::Master {
+            store.propagate(db, command);
+        }
     }
 }
 
-fn is_write_command(cmd: &str) -> bool {
-    match cmd {
-        "set" | "del" => true,
-        _ => false,
+/// Acknowledges the processed offset to the master every second, until the link is closed
+async fn send_acks(store: Arc<Mutex<Store>>, output: mpsc::WeakSender<Frame>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let Some(output) = output.upgrade() else {
+            break;
+        };
+        let offset = store.lock().await.info.recv_offset;
+        let ack: Frame = vec!["REPLCONF".into(), "ACK".into(), offset.to_string()].into();
+        if output.send(ack).await.is_err() {
+            break;
+        }
     }
 }
 
+/// The arguments of a command, they are all binary safe strings
+pub(crate) fn command_args(command: Frame) -> Option<Args> {
+    command
+        .array()?
+        .into_iter()
+        .map(|v| match v {
+            Frame::BulkString(v) => Some(v.into()),
+            Frame::SimpleString(v) => Some(v.into()),
+            _ => None,
+        })
+        .collect()
+}
+
 fn subscriber_mode_command(cmd: &str) -> bool {
     match cmd {
         "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "ping" | "quit" => true,
@@ -2,6 +2,7 @@ use super::Result;
 use super::errors::*;
 use super::server::Server;
 use crate::HashMap;
+use crate::bytestring::ByteString;
 use crate::frame::Frame;
 use crate::frame::TypedNone;
 use crate::server::Args;
@@ -22,13 +23,11 @@ impl Server {
 
         let key = args.pop_front().ok_or(err())?;
         let mut store = self.store.lock().await;
-        let stream = store
-            .kv
-            .entry(key)
-            .or_insert(Value::Stream(vec![]))
+        let stream = store.databases[self.db]
+            .get_or_insert(key, Value::Stream(vec![]))
             .stream_mut()
             .ok_or(wrong_type())?;
-        let id = args.pop_front().ok_or(err())?;
+        let id = args.pop_front().ok_or(err())?.to_string();
         let mut data = HashMap::new();
         while args.len() > 0 {
             let key = args.pop_front().ok_or(err())?;
@@ -78,16 +77,14 @@ impl Server {
         let mut store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("xdel"))?;
 
-        let stream = store
-            .kv
-            .entry(key)
-            .or_insert(Value::Stream(vec![]))
+        let stream = store.databases[self.db]
+            .get_or_insert(key, Value::Stream(vec![]))
             .stream_mut()
             .ok_or(wrong_type())?;
 
         let mut res = 0usize;
         for id in args {
-            if let Some(idx) = stream.iter().position(|x| x.id.to_string() == id) {
+            if let Some(idx) = stream.iter().position(|x| id == *x.id.to_string()) {
                 stream.remove(idx);
                 res += 1;
             }
@@ -106,7 +103,7 @@ impl Server {
         let store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("xlen"))?;
 
-        let n = match store.kv.get(&key).and_then(|v| v.stream()) {
+        let n = match store.databases[self.db].get(&key).and_then(|v| v.stream()) {
             Some(l) => l.len(),
             None => 0,
         };
@@ -123,18 +120,14 @@ impl Server {
     pub async fn xrange(&mut self, mut args: Args) -> Result {
         let err = || wrong_num_arguments("xrange");
         let key = args.pop_front().ok_or(err())?;
-        let mut store = self.store.lock().await;
-        let stream = store
-            .kv
-            .entry(key)
-            .or_insert(Value::Stream(vec![]))
-            .stream_mut()
-            .ok_or(make_io_error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            ))?;
+        let store = self.store.lock().await;
+        let stream = match store.databases[self.db].get(&key) {
+            Some(v) => v.stream().ok_or(wrong_type())?.as_slice(),
+            None => &[],
+        };
 
-        let start = args.pop_front().ok_or(err())?;
-        let end = args.pop_front().ok_or(err())?;
+        let start = args.pop_front().ok_or(err())?.to_string();
+        let end = args.pop_front().ok_or(err())?.to_string();
 
         let start = if start == "-" {
             0
@@ -190,21 +183,17 @@ impl Server {
         let now = std::time::Instant::now();
 
         let stream_count = args.len() / 2;
-        let keys: Vec<String> = args.drain(0..stream_count).collect();
+        let keys: Vec<ByteString> = args.drain(0..stream_count).collect();
         let mut starts = vec![];
 
         for key in &keys {
-            let start = args.pop_front().ok_or(err())?;
+            let start = args.pop_front().ok_or(err())?.to_string();
             let start = if start == "$" {
-                let mut store = self.store.lock().await;
-                let stream = store
-                    .kv
-                    .entry(key.clone())
-                    .or_insert(Value::Stream(vec![]))
-                    .stream_mut()
-                    .ok_or(make_io_error(
-                        "WRONGTYPE Operation against a key holding the wrong kind of value",
-                    ))?;
+                let store = self.store.lock().await;
+                let stream = match store.databases[self.db].get(key) {
+                    Some(v) => v.stream().ok_or(wrong_type())?.as_slice(),
+                    None => &[],
+                };
                 stream
                     .last()
                     .map(|v| v.id)
@@ -218,18 +207,14 @@ impl Server {
         }
 
         while now.elapsed().as_millis() < time_out {
-            let mut store = self.store.lock().await;
+            let store = self.store.lock().await;
 
             let mut result: Vec<Frame> = vec![];
             for (key, start) in keys.iter().zip(starts.iter()) {
-                let stream = store
-                    .kv
-                    .entry(key.clone())
-                    .or_insert(Value::Stream(vec![]))
-                    .stream_mut()
-                    .ok_or(make_io_error(
-                        "WRONGTYPE Operation against a key holding the wrong kind of value",
-                    ))?;
+                let Some(value) = store.databases[self.db].get(key) else {
+                    continue;
+                };
+                let stream = value.stream().ok_or(wrong_type())?;
                 let start = stream.partition_point(|x| &x.id <= start);
                 if stream[start..].len() == 0 {
                     continue;
@@ -251,4 +236,41 @@ impl Server {
 
         Ok(Frame::None(TypedNone::Array))
     }
+
+    /// Returns information about the stream stored at key: its length, the ID of
+    /// its last entry and its first and last entries.
+    /// ```
+    /// XINFO STREAM key
+    /// ```
+    pub async fn xinfo(&mut self, mut args: Args) -> Result {
+        let err = || wrong_num_arguments("xinfo");
+        let subcommand = args.pop_front().ok_or(err())?.to_lowercase();
+        if subcommand != "stream" {
+            return Err(make_io_error(&format!(
+                "ERR unknown subcommand '{subcommand}'. Try XINFO HELP."
+            ))
+            .into());
+        }
+        let key = args.pop_front().ok_or(err())?;
+        let store = self.store.lock().await;
+        let stream = store.databases[self.db]
+            .get(&key)
+            .ok_or(make_io_error("ERR no such key"))?
+            .stream()
+            .ok_or(wrong_type())?;
+        let entry = |v: Option<&StreamEntry>| match v {
+            Some(v) => v.clone().into(),
+            None => Frame::None(TypedNone::String),
+        };
+        let id = |v: Option<&StreamEntry>| v.map_or("0-0".into(), |v| v.id.to_string());
+        let res = vec![
+            ("length".to_string(), stream.len().into()),
+            ("last-generated-id".into(), id(stream.last()).into()),
+            ("recorded-first-entry-id".into(), id(stream.first()).into()),
+            ("groups".into(), 0usize.into()),
+            ("first-entry".into(), entry(stream.first())),
+            ("last-entry".into(), entry(stream.last())),
+        ];
+        Ok(res.into())
+    }
 }
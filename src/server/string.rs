@@ -2,11 +2,12 @@ use super::Args;
 use super::Result;
 use super::errors::*;
 use super::server::Server;
+use crate::bytestring::ByteString;
 use crate::frame::{Frame, TypedNone};
-use crate::store::{Store, Value};
+use crate::store::{FailoverState, Role, Store, Value};
 use std::ops::Add;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::Mutex;
 
 impl Server {
@@ -22,7 +23,7 @@ impl Server {
 
         let key = args.pop_front().ok_or(wrong_num_arguments("get"))?;
 
-        let resp = if let Some(v) = store.kv.get(&key) {
+        let resp = if let Some(v) = store.databases[self.db].get(&key) {
             v.string().ok_or(wrong_type())?.clone().into()
         } else {
             Frame::None(TypedNone::String)
@@ -40,28 +41,38 @@ impl Server {
     /// ```
     pub async fn set(&mut self, mut args: Args) -> Result {
         let err = || wrong_num_arguments("set");
-        let mut store = self.store.lock().await;
         let key = args.pop_front().ok_or(err())?;
-        let value = args.pop_front().ok_or(wrong_num_arguments("set"))?;
-        store.kv.insert(key.clone(), value.into());
-
-        if args.len() > 0 {
-            let unit = args.pop_front().ok_or(err())?;
-            let mut time = args
-                .pop_front()
-                .ok_or(err())?
-                .parse()
-                .map_err(|_| syntax_error())?;
-            if unit.to_lowercase() == "ex" {
-                time *= 1000;
-            }
-            let expiry_time = std::time::SystemTime::now().add(Duration::from_millis(time));
-            store.expiry_queue.insert(expiry_time, key.clone());
-            store.expiry_time.insert(key, expiry_time);
-        } else {
-            if let Some(time) = store.expiry_time.remove(&key) {
-                store.expiry_queue.remove(&time);
-            }
+        let value = args.pop_front().ok_or(err())?;
+
+        let mut expiry = None;
+        let mut keep_ttl = false;
+        while let Some(option) = args.pop_front() {
+            let mut time = || -> std::result::Result<u64, std::io::Error> {
+                args.pop_front()
+                    .ok_or(syntax_error())?
+                    .parse()
+                    .map_err(|_| syntax_error())
+            };
+            expiry = match option.to_lowercase().as_str() {
+                "ex" => Some(SystemTime::now().add(Duration::from_secs(time()?))),
+                "px" => Some(SystemTime::now().add(Duration::from_millis(time()?))),
+                "exat" => Some(UNIX_EPOCH.add(Duration::from_secs(time()?))),
+                "pxat" => Some(UNIX_EPOCH.add(Duration::from_millis(time()?))),
+                "keepttl" => {
+                    keep_ttl = true;
+                    continue;
+                }
+                _ => return Err(syntax_error().into()),
+            };
+        }
+
+        let mut store = self.store.lock().await;
+        let db = &mut store.databases[self.db];
+        db.insert(key.clone(), value.into());
+        if let Some(expiry_time) = expiry {
+            db.set_expiry(key, expiry_time);
+        } else if !keep_ttl {
+            db.persist(&key);
         }
 
         Ok("OK".into())
@@ -77,34 +88,113 @@ impl Server {
     pub async fn incr(&mut self, mut args: Args) -> Result {
         let key = args.pop_front().ok_or(wrong_num_arguments("incr"))?;
         let mut store = self.store.lock().await;
-        let val = store
-            .kv
-            .entry(key)
-            .or_insert(Value::String("0".into()))
+        let val = store.databases[self.db]
+            .get_or_insert(key, Value::String("0".into()))
             .string_mut()
             .ok_or(out_of_range())?;
         let mut result_value: isize = val.parse().ok().ok_or(out_of_range())?;
         result_value += 1;
 
-        *val = result_value.to_string();
+        *val = result_value.to_string().into();
         Ok(result_value.into())
     }
 }
 
-/// Removes expired keys from the kv store
-/// keys are stored in heap wrt their expiration time
+/// Removes expired keys from every database of the kv store. Only the master expires
+/// keys, each of them is sent to the replicas and the append only file as a DEL so
+/// that they drop the key at the same point of the stream no matter their clock.
 pub async fn remove_expired(store: Arc<Mutex<Store>>) {
     let mut store = store.lock().await;
-    while !store.expiry_queue.is_empty() {
-        let (t, key) = match store.expiry_queue.pop_first() {
-            Some(v) => v,
-            None => break,
-        };
-        if t > std::time::SystemTime::now() {
-            store.expiry_queue.insert(t, key);
-            break;
+    if !expires_keys(&store) {
+        return;
+    }
+    for idx in 0..store.databases.len() {
+        while let Some(key) = store.databases[idx].pop_expired() {
+            propagate_expired(&mut store, idx, key);
         }
-        store.expiry_time.remove(&key);
-        store.kv.remove(&key);
+    }
+}
+
+/// Removes the keys a command is about to use when their time to live has passed,
+/// the cron may not have got to them yet. Replicas keep hiding such keys until
+/// the DEL of their master arrives.
+pub fn expire_if_needed<'a>(
+    store: &mut Store,
+    idx: usize,
+    keys: impl IntoIterator<Item = &'a ByteString>,
+) {
+    if !expires_keys(store) {
+        return;
+    }
+    for key in keys {
+        if store.databases[idx].is_expired(key) {
+            store.databases[idx].remove(key);
+            propagate_expired(store, idx, key.clone());
+        }
+    }
+}
+
+/// Whether this node may remove expired keys, the keyspace must not change while
+/// the target of a failover catches up
+fn expires_keys(store: &Store) -> bool {
+    store.info.role == Role::Master && store.info.failover_state == FailoverState::NoFailover
+}
+
+fn propagate_expired(store: &mut Store, idx: usize, key: ByteString) {
+    let del: Frame = vec!["DEL".into(), key].into();
+    if let Some(aof) = store.aof.as_mut()
+        && let Err(e) = aof.feed(idx, &del)
+    {
+        println!("Error writing to the append only file: {e}");
+    }
+    store.info.dirty += 1;
+    store.propagate(idx, del);
+}
+
+/// Actively expires keys that are never accessed again
+pub async fn expire_cron(store: Arc<Mutex<Store>>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+        remove_expired(store.clone()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Database;
+    use std::collections::HashSet;
+
+    fn store(role: Role) -> Store {
+        let mut store = Store::default();
+        store.info.role = role;
+        store.databases = vec![Database::default(), Database::default()];
+        let db = &mut store.databases[1];
+        db.insert("s".into(), Value::Set(HashSet::from(["a".into()])));
+        db.set_expiry("s".into(), SystemTime::now() - Duration::from_millis(1));
+        store
+    }
+
+    #[test]
+    fn master_deletes_expired_set_before_use() {
+        let mut store = store(Role::Master);
+        expire_if_needed(&mut store, 1, [&"s".into(), &"missing".into()]);
+        assert!(!store.databases[1].kv.contains_key(&b"s"[..]));
+        assert!(store.databases[1].expiry_time.is_empty());
+        // a SELECT and the DEL went to the replicas
+        assert_eq!(store.replication_db, Some(1));
+        let stream = "*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n*2\r\n$3\r\nDEL\r\n$1\r\ns\r\n";
+        assert_eq!(store.info.send_offset, stream.len());
+        assert_eq!(store.info.dirty, 1);
+    }
+
+    #[test]
+    fn replica_hides_expired_set() {
+        let mut store = store(Role::Slave);
+        expire_if_needed(&mut store, 1, [&"s".into()]);
+        assert!(store.databases[1].kv.contains_key(&b"s"[..]));
+        assert!(store.databases[1].get(b"s").is_none());
+        assert_eq!(store.info.send_offset, 0);
     }
 }
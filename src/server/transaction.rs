@@ -25,6 +25,7 @@ impl Server {
                 println!("running queued commands: \n{:?}", commands);
 
                 let mut res = vec![];
+                self.multi_propagation = Some(vec![]);
                 for v in commands {
                     match self.execute(v).await {
                         Ok(r) => res.push(r),
@@ -32,6 +33,19 @@ impl Server {
                     }
                 }
 
+                // the writes of the transaction are propagated together as a transaction
+                let propagated = self.multi_propagation.take().unwrap_or_default();
+                if let Some(&(mut db, _, _)) = propagated.first() {
+                    let mut store = self.store.lock().await;
+                    let write = propagated.iter().any(|&(_, _, write)| write);
+                    self.feed(&mut store, db, vec!["MULTI".to_string()].into(), write);
+                    for (command_db, command, write) in propagated {
+                        db = command_db;
+                        self.feed(&mut store, db, command, write);
+                    }
+                    self.feed(&mut store, db, vec!["EXEC".to_string()].into(), write);
+                }
+
                 Ok(res.into())
             }
             "discard" => {
@@ -1,10 +1,10 @@
 use super::server::Server;
 use super::{Args, Result};
+use crate::bytestring::ByteString;
 use crate::frame::{Frame, TypedNone};
 use crate::server::errors::{syntax_error, wrong_num_arguments, wrong_type};
 use crate::store::{Value, ZSet};
 use ordered_float::OrderedFloat;
-use std::str::FromStr;
 
 const S: &str = "\u{10FFFF}";
 
@@ -26,16 +26,14 @@ impl Server {
     pub async fn zadd(&mut self, mut args: Args) -> Result {
         let mut store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("zadd"))?;
-        let set = store
-            .kv
-            .entry(key)
-            .or_insert(Value::ZSet(ZSet::default()))
+        let set = store.databases[self.db]
+            .get_or_insert(key, Value::ZSet(ZSet::default()))
             .zset_mut()
             .ok_or(wrong_type())?;
         let mut res = 0usize;
         while !args.is_empty() {
             let k = args.pop_front().ok_or(syntax_error())?;
-            let key = OrderedFloat::from_str(&k).map_err(|_| syntax_error())?;
+            let key: OrderedFloat<f64> = k.parse().map_err(|_| syntax_error())?;
             let value = args.pop_front().ok_or(syntax_error())?;
             if let Some(prev_score) = set.scores.remove(&value) {
                 set.ordered.remove(&(prev_score, value.clone()));
@@ -55,8 +53,7 @@ impl Server {
     pub async fn zcard(&mut self, mut args: Args) -> Result {
         let store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("zadd"))?;
-        let card = store
-            .kv
+        let card = store.databases[self.db]
             .get(&key)
             .and_then(|v| v.zset())
             .map(|v| v.scores.len())
@@ -73,10 +70,15 @@ impl Server {
     pub async fn zcount(&mut self, mut args: Args) -> Result {
         let store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("zadd"))?;
-        let min = OrderedFloat::from_str(&args.pop_front().ok_or(wrong_num_arguments("zadd"))?)?;
-        let max = OrderedFloat::from_str(&args.pop_front().ok_or(wrong_num_arguments("zadd"))?)?;
-        let count = store
-            .kv
+        let min: OrderedFloat<f64> = args
+            .pop_front()
+            .ok_or(wrong_num_arguments("zadd"))?
+            .parse()?;
+        let max: OrderedFloat<f64> = args
+            .pop_front()
+            .ok_or(wrong_num_arguments("zadd"))?
+            .parse()?;
+        let count = store.databases[self.db]
             .get(&key)
             .and_then(|v| v.zset())
             .map(|v| v.ordered.range(&(min, "".into())..&(max, S.into())).count())
@@ -94,7 +96,7 @@ impl Server {
         let store = self.store.lock().await;
         let key = args.pop_front().ok_or(wrong_num_arguments("zadd"))?;
         let member = args.pop_front().ok_or(wrong_num_arguments("zadd"))?;
-        if let Some(set) = store.kv.get(&key).and_then(|v| v.zset())
+        if let Some(set) = store.databases[self.db].get(&key).and_then(|v| v.zset())
             && let Some(&k) = set.scores.get(&member)
         {
             println!("{:?}", set.ordered);
@@ -120,7 +122,7 @@ impl Server {
 
         let store = self.store.lock().await;
         let key = args.pop_front().ok_or(err())?;
-        let set = if let Some(v) = store.kv.get(&key) {
+        let set = if let Some(v) = store.databases[self.db].get(&key) {
             v.zset().ok_or(wrong_type())?
         } else {
             &ZSet::default()
@@ -146,7 +148,7 @@ impl Server {
             .skip(start)
             .take(end - start)
             .map(|(_, v)| v.clone())
-            .collect::<Vec<String>>()
+            .collect::<Vec<ByteString>>()
             .into())
     }
 
@@ -161,7 +163,7 @@ impl Server {
         let mut store = self.store.lock().await;
         let key = args.pop_front().ok_or(err())?;
         let member = args.pop_front().ok_or(err())?;
-        let res = if let Some(v) = store.kv.get_mut(&key)
+        let res = if let Some(v) = store.databases[self.db].get_mut(&key)
             && let Some(score) = v.zset_mut().ok_or(wrong_type())?.scores.remove(&member)
         {
             v.zset_mut()
@@ -188,10 +190,10 @@ impl Server {
         let key = args.pop_front().ok_or(err())?;
         let member = args.pop_front().ok_or(err())?;
 
-        if let Some(v) = store.kv.get(&key)
+        if let Some(v) = store.databases[self.db].get(&key)
             && let Some(score) = v.zset().ok_or(wrong_type())?.scores.get(&member)
         {
-            Ok(score.0.to_string().into())
+            Ok(Frame::Double(score.0))
         } else {
             Ok(Frame::None(TypedNone::String))
         }
@@ -1,135 +1,300 @@
 use crate::Error;
 use crate::frame::Frame;
 use crate::frame::encode::AsBytes;
+use crate::rdb::decode::RDBParser;
+use crate::server::persistence::{background_rewrite_aof, unix_time};
 use crate::server::server::Server;
-use crate::store::Store;
+use crate::store::{Backlog, ReplState, Store};
 use bytes::{Buf, BytesMut};
-use rand;
-use std::io::Cursor;
-use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
+use tokio::task::AbortHandle;
 
-pub async fn handle(addr: Ipv4Addr, port: u16, store: Arc<Mutex<Store>>) -> Result<(), Error> {
-    let mut tcp = TcpStream::connect(SocketAddrV4::new(addr, port)).await?;
-    ping(&mut tcp).await?;
-    replconf(store.clone(), &mut tcp).await?;
-    let buffer = psync(store.clone(), &mut tcp).await?;
-    Server::handle(store, tcp, buffer, rand::random_range(1..usize::MAX)).await;
-    Ok(())
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Starts replicating from the master at `host:port`, the returned handle
+/// stops the replication link along with the connection to the master
+pub fn start(store: Arc<Mutex<Store>>, host: String, port: u16) -> AbortHandle {
+    tokio::spawn(replicate(store, host, port, false)).abort_handle()
 }
 
-pub async fn ping(tcp: &mut TcpStream) -> Result<(), Error> {
-    let mut b = BytesMut::new();
-    let ping: Frame = vec!["PING".to_string()].into();
-    ping.encode_bytes(&mut b);
-    tcp.write_all(b.as_ref()).await?;
-    b.clear();
-    tcp.read_buf(&mut b).await?;
-    assert_eq!(b.as_ref().to_ascii_lowercase(), b"+pong\r\n");
-    Ok(())
+/// Starts replicating from our replica at `host:port` asking it to take over,
+/// if it refuses we are promoted back to a master
+pub fn failover(store: Arc<Mutex<Store>>, host: String, port: u16) -> AbortHandle {
+    tokio::spawn(replicate(store, host, port, true)).abort_handle()
 }
 
-pub async fn replconf(store: Arc<Mutex<Store>>, tcp: &mut TcpStream) -> Result<(), Error> {
-    let mut b = BytesMut::new();
-    // send first message
-    let first_message: Frame = vec![
-        "REPLCONF".into(),
-        "listening-port".into(),
-        store.lock().await.info.listening_port.to_string(),
-    ]
-    .into();
-    first_message.encode_bytes(&mut b);
-    tcp.write_all(b.as_ref()).await?;
-    b.clear();
-    // check ok response
-    tcp.read_buf(&mut b).await?;
-    assert_eq!(&b.to_ascii_lowercase(), b"+ok\r\n");
-    b.clear();
-    // send second message
-    let second_message: Frame = vec!["REPLCONF", "capa", "psync2"].into();
-    second_message.encode_bytes(&mut b);
-    tcp.write_all(b.as_ref()).await?;
-    b.clear();
-    tcp.read_buf(&mut b).await?;
-    assert_eq!(&b.to_ascii_lowercase(), b"+ok\r\n");
-    Ok(())
+/// Keeps the link with the master alive, every time the connection is lost
+/// or the handshake fails it is retried after an exponential backoff
+async fn replicate(store: Arc<Mutex<Store>>, host: String, port: u16, mut failover: bool) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        store.lock().await.info.repl_state = ReplState::Connecting;
+        println!("Connecting to MASTER {host}:{port}");
+        let result = connect(store.clone(), &host, port, failover).await;
+        if failover {
+            let mut guard = store.lock().await;
+            if let Err(e) = &result {
+                println!("FAILOVER to {host}:{port} failed: {e}");
+                // the link is this very task, it must not be aborted by promote
+                guard.master_link = None;
+                guard.promote();
+            }
+            guard.end_failover();
+            if result.is_err() {
+                return;
+            }
+            failover = false;
+        }
+        match result {
+            Ok((tcp, buffer)) => {
+                backoff = MIN_BACKOFF;
+                let mut guard = store.lock().await;
+                guard.info.repl_state = ReplState::Connected;
+                guard.info.master_last_io = unix_time();
+                drop(guard);
+                println!("MASTER <-> REPLICA sync: Finished with success");
+                let slave_id = rand::random_range(1..usize::MAX);
+                match Server::run(store.clone(), tcp, buffer, slave_id).await {
+                    Ok(()) => println!("Connection with master lost."),
+                    Err(e) => println!("Connection with master lost: {e}"),
+                }
+            }
+            Err(e) => println!("Error condition on socket for SYNC: {e}"),
+        }
+
+        let mut guard = store.lock().await;
+        guard.info.repl_state = ReplState::Connect;
+        guard.info.master_link_down_since = unix_time();
+        drop(guard);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
 }
 
-pub async fn psync(store: Arc<Mutex<Store>>, tcp: &mut TcpStream) -> Result<BytesMut, Error> {
+/// Performs the handshake with the master and synchronizes with it, returns
+/// the connection along with whatever was read past the end of the snapshot
+async fn connect(
+    store: Arc<Mutex<Store>>,
+    host: &str,
+    port: u16,
+    failover: bool,
+) -> Result<(TcpStream, BytesMut), Error> {
+    let mut tcp = TcpStream::connect((host, port)).await?;
     let mut b = BytesMut::new();
-    let repl_id = store.lock().await.info.master_id.clone();
-    let offset = match store.lock().await.info.recv_offset {
-        0 => -1,
-        v => v as isize,
-    };
+    store.lock().await.info.repl_state = ReplState::Handshake;
+    ping(&mut tcp, &mut b).await?;
+    replconf(store.clone(), &mut tcp, &mut b).await?;
+    psync(store, &mut tcp, &mut b, failover).await?;
+    Ok((tcp, b))
+}
 
-    let message: Frame = vec!["PSYNC".into(), repl_id, offset.to_string()].into();
-    message.encode_bytes(&mut b);
-    tcp.write_all(b.as_ref()).await?;
-    b.clear();
+async fn ping(tcp: &mut TcpStream, b: &mut BytesMut) -> Result<(), Error> {
+    send(tcp, vec!["PING".to_string()].into()).await?;
+    expect(tcp, b, "+pong").await
+}
 
-    while b.len() == 0 {
-        tcp.read_buf(&mut b).await?;
+async fn replconf(
+    store: Arc<Mutex<Store>>,
+    tcp: &mut TcpStream,
+    b: &mut BytesMut,
+) -> Result<(), Error> {
+    let port = store.lock().await.info.listening_port.to_string();
+    let listening_port: Frame = vec!["REPLCONF".into(), "listening-port".into(), port].into();
+    send(tcp, listening_port).await?;
+    expect(tcp, b, "+ok").await?;
+    send(tcp, vec!["REPLCONF", "capa", "psync2"].into()).await?;
+    expect(tcp, b, "+ok").await
+}
+
+/// Asks the master to continue from our offset if it still can,
+/// otherwise loads the snapshot of the dataset it sends
+async fn psync(
+    store: Arc<Mutex<Store>>,
+    tcp: &mut TcpStream,
+    b: &mut BytesMut,
+    failover: bool,
+) -> Result<(), Error> {
+    let (repl_id, offset) = {
+        let store = store.lock().await;
+        match store.info.master_id.as_str() {
+            "?" => ("?".to_string(), -1),
+            // the offset of the first byte we still need
+            id => (id.to_string(), store.info.recv_offset as isize + 1),
+        }
+    };
+    let mut command = vec!["PSYNC".into(), repl_id, offset.to_string()];
+    if failover {
+        command.push("FAILOVER".into());
     }
-    let mut cursor = Cursor::new(b.as_ref());
+    send(tcp, command.into()).await?;
 
-    loop {
-        assert_eq!(cursor.get_u8(), b'+');
-        match crate::frame::decode::get_line(&mut cursor) {
-            Ok(_v) => {
-                #[cfg(debug_assertions)]
-                println!("psync-response: {}", String::from_utf8_lossy(_v));
-                break;
-            }
-            Err(crate::frame::Error::Incomplete) => {
-                tcp.read_buf(&mut b).await?;
-                cursor = Cursor::new(b.as_ref());
+    let line = read_line(tcp, b).await?;
+    let mut parts = line.split_whitespace();
+    match parts.next().map(|v| v.to_lowercase()).as_deref() {
+        Some("+fullresync") => {
+            let repl_id = parts.next().ok_or("invalid psync response")?.to_string();
+            let offset: usize = parts.next().ok_or("invalid psync response")?.parse()?;
+
+            store.lock().await.info.repl_state = ReplState::Transfer;
+            let rdb = read_payload(tcp, b).await?;
+            load_rdb(store, &rdb, repl_id, offset).await
+        }
+        Some("+continue") => {
+            println!("Successful partial resynchronization with master.");
+            // the master changed its replication id, the history is still the same
+            if let Some(repl_id) = parts.next() {
+                let mut guard = store.lock().await;
+                if guard.info.master_id != repl_id {
+                    guard.shift_replid(repl_id.to_string());
+                    guard.reset_replicas();
+                }
             }
-            Err(e) => panic!("{e}"),
+            Ok(())
         }
+        _ => Err(format!("unexpected reply to PSYNC from master: {line}").into()),
     }
+}
 
-    let parsed = cursor.position() as usize;
-    b.copy_within(parsed.., 0);
-    b.truncate(b.len() - parsed);
-    while b.len() == 0 {
-        tcp.read_buf(&mut b).await?;
+/// Replaces the dataset of the replica with the snapshot sent by the master
+async fn load_rdb(
+    store: Arc<Mutex<Store>>,
+    data: &[u8],
+    repl_id: String,
+    offset: usize,
+) -> Result<(), Error> {
+    let (rdb_file, _) = RDBParser::parse(data)?;
+    let mut guard = store.lock().await;
+    guard.databases.iter_mut().for_each(|db| db.clear());
+    for (idx, db) in rdb_file.databases {
+        let database = guard
+            .databases
+            .get_mut(idx)
+            .ok_or("master sent a DB index that is out of range")?;
+        *database = db;
     }
-    let mut cursor = Cursor::new(b.as_ref());
-
-    let size = loop {
-        assert_eq!(cursor.get_u8(), b'$');
-        match crate::frame::decode::get_decimal(&mut cursor) {
-            Ok(v) => break v,
-            Err(crate::frame::Error::Incomplete) => {
-                cursor = Cursor::new(b.as_ref());
-            }
-            Err(e) => panic!("{e}"),
-        };
-    } as usize;
+    guard.info.master_id = repl_id;
+    guard.clear_replid2();
+    guard.info.recv_offset = offset;
+    guard.replication_db = rdb_file
+        .metadata
+        .get("repl-stream-db")
+        .and_then(|v| v.parse().ok());
+    // our own replicas have to load the new dataset as well
+    guard.info.send_offset = offset;
+    guard.backlog = Some(Backlog::new(guard.info.repl_backlog_size, offset));
+    guard.reset_replicas();
+    let appendonly = guard.aof.is_some();
+    drop(guard);
 
-    #[cfg(debug_assertions)]
-    println!("size = {size}");
+    // the append only file has to start over from the new dataset
+    if appendonly {
+        background_rewrite_aof(store).await?;
+    }
+    Ok(())
+}
 
-    let parsed = cursor.position() as usize;
-    b.copy_within(parsed.., 0);
-    b.truncate(b.len() - parsed);
+async fn send(tcp: &mut TcpStream, frame: Frame) -> Result<(), Error> {
+    let mut b = BytesMut::new();
+    frame.encode_bytes(&mut b);
+    tcp.write_all(b.as_ref()).await?;
+    Ok(())
+}
 
+/// Reads the RDB payload of a full resynchronization, sent as `$<len>\r\n` followed
+/// by the file without a trailing CRLF
+async fn read_payload<R>(tcp: &mut R, b: &mut BytesMut) -> Result<BytesMut, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let size: usize = read_line(tcp, b)
+        .await?
+        .strip_prefix('$')
+        .ok_or("expected the RDB payload from master")?
+        .parse()?;
     while b.len() < size {
-        tcp.read_buf(&mut b).await?;
+        if tcp.read_buf(b).await? == 0 {
+            return Err("connection lost while receiving the RDB payload".into());
+        }
     }
+    Ok(b.split_to(size))
+}
 
-    #[cfg(debug_assertions)]
-    println!(
-        "RDB file: {b:?}, \n hex: {}",
-        hex::encode(&b.as_ref()[..size])
-    );
+/// Reads a single line of the reply. Empty lines are sent by the master as keep
+/// alives and are skipped, so are the bare newlines it sends while it creates the
+/// snapshot for a full resynchronization.
+async fn read_line<R>(tcp: &mut R, b: &mut BytesMut) -> Result<String, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let newlines = b.iter().take_while(|&&v| v == b'\n').count();
+        b.advance(newlines);
+        if let Some(pos) = b.windows(2).position(|w| w == b"\r\n") {
+            let line = b.split_to(pos + 2);
+            if pos == 0 {
+                continue;
+            }
+            return Ok(String::from_utf8_lossy(&line[..pos]).to_string());
+        }
+        if tcp.read_buf(b).await? == 0 {
+            return Err("connection closed by master".into());
+        }
+    }
+}
 
-    b.copy_within(size.., 0);
-    b.truncate(b.len() - size);
+async fn expect(tcp: &mut TcpStream, b: &mut BytesMut, expected: &str) -> Result<(), Error> {
+    let line = read_line(tcp, b).await?;
+    if !line.eq_ignore_ascii_case(expected) {
+        return Err(format!("unexpected reply from master: {line}").into());
+    }
+    Ok(())
+}
 
-    Ok(b)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keep_alives_before_the_payload() {
+        let mut input = &b"\n\n$5\r\nREDIS+FULLRESYNC"[..];
+        let mut b = BytesMut::new();
+        assert_eq!(
+            &read_payload(&mut input, &mut b).await.unwrap()[..],
+            b"REDIS"
+        );
+        assert_eq!(&b[..], b"+FULLRESYNC");
+    }
+
+    #[tokio::test]
+    async fn keep_alives_between_lines() {
+        let mut input = &b"\r\n+FULLRESYNC abc 0\r\n\n\n\r\n\n$0\r\n"[..];
+        let mut b = BytesMut::new();
+        assert_eq!(
+            read_line(&mut input, &mut b).await.unwrap(),
+            "+FULLRESYNC abc 0"
+        );
+        assert!(read_payload(&mut input, &mut b).await.unwrap().is_empty());
+        assert!(read_line(&mut input, &mut b).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_payload() {
+        let mut input = &b"\n$10\r\nREDIS"[..];
+        assert!(
+            read_payload(&mut input, &mut BytesMut::new())
+                .await
+                .is_err()
+        );
+        let mut input = &b"+OK\r\n"[..];
+        assert!(
+            read_payload(&mut input, &mut BytesMut::new())
+                .await
+                .is_err()
+        );
+    }
 }
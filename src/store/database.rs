@@ -0,0 +1,213 @@
+use super::{Database, Value};
+use crate::bytestring::ByteString;
+use crate::cluster::key_slot;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+impl Database {
+    /// Sets the value of the key, its expiry time is left as it is
+    pub fn insert(&mut self, key: ByteString, value: Value) -> Option<Value> {
+        if let Some(slots) = self.slots.as_mut() {
+            slots.entry(key_slot(&key)).or_default().insert(key.clone());
+        }
+        self.kv.insert(key, value)
+    }
+
+    /// The value of the key, which is set to `default` when it doesn't exist or
+    /// is logically expired
+    pub fn get_or_insert(&mut self, key: ByteString, default: Value) -> &mut Value {
+        if self.is_expired(&key) {
+            self.remove(&key);
+        }
+        match self.kv.entry(key) {
+            Entry::Occupied(v) => v.into_mut(),
+            Entry::Vacant(v) => {
+                if let Some(slots) = self.slots.as_mut() {
+                    slots
+                        .entry(key_slot(v.key()))
+                        .or_default()
+                        .insert(v.key().clone());
+                }
+                v.insert(default)
+            }
+        }
+    }
+
+    /// The keys that hash to the slot, the ones that are logically expired are left out.
+    /// The index of the slots is built by the first call and kept up to date from then on
+    pub fn keys_in_slot(&mut self, slot: u16) -> impl Iterator<Item = &ByteString> {
+        let kv = &self.kv;
+        let slots = self.slots.get_or_insert_with(|| {
+            let mut slots: HashMap<u16, HashSet<ByteString>> = HashMap::new();
+            for key in kv.keys() {
+                slots.entry(key_slot(key)).or_default().insert(key.clone());
+            }
+            slots
+        });
+        let now = SystemTime::now();
+        let expiry_time = &self.expiry_time;
+        slots
+            .get(&slot)
+            .into_iter()
+            .flatten()
+            .filter(move |v| expiry_time.get(*v).is_none_or(|&time| time > now))
+    }
+
+    fn remove_from_slot(&mut self, key: &[u8]) {
+        let Some(slots) = self.slots.as_mut() else {
+            return;
+        };
+        let slot = key_slot(key);
+        if let Some(keys) = slots.get_mut(&slot) {
+            keys.remove(key);
+            if keys.is_empty() {
+                slots.remove(&slot);
+            }
+        }
+    }
+
+    /// Sets the time at which the key will be removed
+    pub fn set_expiry(&mut self, key: ByteString, time: SystemTime) {
+        if let Some(prev) = self.expiry_time.insert(key.clone(), time) {
+            self.expiry_queue.remove(&(prev, key.clone()));
+        }
+        self.expiry_queue.insert((time, key));
+    }
+
+    /// Removes the expiry time of the key, it is kept until deleted
+    pub fn persist(&mut self, key: &[u8]) {
+        if let Some((key, time)) = self.expiry_time.remove_entry(key) {
+            self.expiry_queue.remove(&(time, key));
+        }
+    }
+
+    /// Removes the key along with its expiry time
+    pub fn remove(&mut self, key: &[u8]) -> Option<(Value, Option<SystemTime>)> {
+        let value = self.kv.remove(key)?;
+        self.remove_from_slot(key);
+        let expiry = self.expiry_time.remove_entry(key).map(|(key, time)| {
+            self.expiry_queue.remove(&(time, key));
+            time
+        });
+        Some((value, expiry))
+    }
+
+    /// Whether the time to live of the key has passed, the key may still be
+    /// stored as only the master removes expired keys from the keyspace
+    pub fn is_expired(&self, key: &[u8]) -> bool {
+        self.expiry_time
+            .get(key)
+            .is_some_and(|&time| time <= SystemTime::now())
+    }
+
+    /// Gets the value of the key, keys that are logically expired are hidden
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.kv.get(key).filter(|_| !self.is_expired(key))
+    }
+
+    /// Gets the value of the key to change it, keys that are logically expired are hidden
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut Value> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.kv.get_mut(key)
+    }
+
+    /// Removes the key that expires the earliest if its time has passed
+    pub fn pop_expired(&mut self) -> Option<ByteString> {
+        let (time, _) = self.expiry_queue.first()?;
+        if *time > SystemTime::now() {
+            return None;
+        }
+        let (_, key) = self.expiry_queue.pop_first()?;
+        self.expiry_time.remove(&key);
+        self.kv.remove(&key);
+        self.remove_from_slot(&key);
+        Some(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.kv.clear();
+        self.slots = None;
+        self.expiry_queue.clear();
+        self.expiry_time.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    fn list(items: &[&'static str]) -> Value {
+        Value::List(items.iter().map(|&v| Frame::BulkString(v.into())).collect())
+    }
+
+    #[test]
+    fn expired_list_is_absent() {
+        let mut db = Database::default();
+        db.insert("l".into(), list(&["a", "b"]));
+        db.set_expiry("l".into(), SystemTime::now() - Duration::from_secs(1));
+
+        // still stored, as a replica waits for the DEL of its master
+        assert!(db.kv.contains_key(&b"l"[..]));
+        assert!(db.get(b"l").is_none());
+        assert!(db.get_mut(b"l").is_none());
+        assert_eq!(db.keys_in_slot(key_slot(b"l")).count(), 0);
+
+        // a write starts over from an empty list without the old time to live
+        let list = db.get_or_insert("l".into(), Value::List(VecDeque::new()));
+        assert!(list.list().unwrap().is_empty());
+        assert!(db.expiry_time.is_empty() && db.expiry_queue.is_empty());
+        assert!(db.get(b"l").is_some());
+    }
+
+    #[test]
+    fn live_list_is_kept() {
+        let mut db = Database::default();
+        db.insert("l".into(), list(&["a"]));
+        db.set_expiry("l".into(), SystemTime::now() + Duration::from_secs(100));
+        let value = db.get_or_insert("l".into(), Value::List(VecDeque::new()));
+        assert_eq!(value.list().unwrap().len(), 1);
+        assert!(db.get_mut(b"l").is_some());
+        assert!(db.pop_expired().is_none());
+    }
+
+    #[test]
+    fn keys_sharing_an_expiry_time() {
+        let mut db = Database::default();
+        let time = SystemTime::now() - Duration::from_secs(1);
+        for key in ["a", "b", "c"] {
+            db.insert(key.into(), list(&["x"]));
+            db.set_expiry(key.into(), time);
+        }
+        db.persist(b"b");
+        let mut expired: Vec<_> = std::iter::from_fn(|| db.pop_expired()).collect();
+        expired.sort();
+        assert_eq!(expired, [ByteString::from("a"), ByteString::from("c")]);
+        assert!(db.get(b"b").is_some());
+    }
+
+    #[test]
+    fn slot_index_is_built_on_first_use() {
+        let mut db = Database::default();
+        db.insert("{a}1".into(), list(&["x"]));
+        db.insert("{a}2".into(), list(&["x"]));
+        assert!(db.slots.is_none());
+
+        let slot = key_slot(b"a");
+        assert_eq!(db.keys_in_slot(slot).count(), 2);
+        assert!(db.slots.is_some());
+
+        // maintained from then on
+        db.insert("{a}3".into(), list(&["x"]));
+        db.get_or_insert("{a}4".into(), list(&["x"]));
+        db.remove(b"{a}1");
+        let mut keys: Vec<_> = db.keys_in_slot(slot).cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["{a}2", "{a}3", "{a}4"]);
+    }
+}
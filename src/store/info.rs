@@ -1,7 +1,73 @@
-use super::{Info, Role};
-use std::fmt::{Display, Formatter};
+use super::{FailoverState, Info, ReplState, Role, Store};
+use crate::server::persistence::unix_time;
+use std::fmt::{Display, Formatter, Write};
+use std::path::PathBuf;
 
 impl Info {
+    /// Path of the RDB file, falls back to `dump.rdb`
+    /// in the working directory just like redis
+    pub fn rdb_path(&self) -> PathBuf {
+        let mut path = PathBuf::from(if self.dir.is_empty() { "." } else { &self.dir });
+        if self.db_filename.is_empty() {
+            path.push("dump.rdb");
+        } else {
+            path.push(&self.db_filename);
+        }
+        path
+    }
+
+    /// Path of the append only file, falls back to
+    /// `appendonly.aof` in the working directory
+    pub fn aof_path(&self) -> PathBuf {
+        let mut path = PathBuf::from(if self.dir.is_empty() { "." } else { &self.dir });
+        if self.append_filename.is_empty() {
+            path.push("appendonly.aof");
+        } else {
+            path.push(&self.append_filename);
+        }
+        path
+    }
+
+    /// Parses the `save` config of the form `<seconds> <changes> [<seconds> <changes> ...]`
+    pub fn parse_save_params(value: &str) -> Option<Vec<(u64, usize)>> {
+        let parts: Vec<_> = value.split_whitespace().collect();
+        if parts.len() % 2 != 0 {
+            return None;
+        }
+        parts
+            .chunks(2)
+            .map(|v| Some((v[0].parse().ok()?, v[1].parse().ok()?)))
+            .collect()
+    }
+
+    /// Parses a size in bytes that can have a `k`, `kb`, `m`, `mb`, `g` or `gb` suffix
+    pub fn parse_memory(value: &str) -> Option<usize> {
+        let value = value.to_lowercase();
+        let split = value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (num, unit) = value.split_at(split);
+        let unit = match unit {
+            "" | "b" => 1,
+            "k" => 1000,
+            "kb" => 1024,
+            "m" => 1000 * 1000,
+            "mb" => 1024 * 1024,
+            "g" => 1000 * 1000 * 1000,
+            "gb" => 1024 * 1024 * 1024,
+            _ => return None,
+        };
+        num.parse::<usize>().ok()?.checked_mul(unit)
+    }
+
+    pub fn save_params_string(&self) -> String {
+        self.save_params
+            .iter()
+            .map(|(seconds, changes)| format!("{seconds} {changes}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
     // pub fn from_role(listening_port: u16, role: Role, master_id: String, offset: usize) -> Self {
     //     let mut res = Info::default();
     //     res.listening_port = listening_port;
@@ -19,20 +85,133 @@ impl Info {
     //     res.recv_offset = 0;
     //     res
     // }
-}
 
-impl Display for Info {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    /// The offset of the replication stream, sent as a master and received as a replica
+    pub fn repl_offset(&self) -> usize {
+        match self.role {
+            Role::Master => self.send_offset,
+            Role::Slave => self.recv_offset,
+        }
+    }
+
+    fn clients(&self, f: &mut String) -> std::fmt::Result {
+        separate(f)?;
         writeln!(f, "# Clients")?;
-        writeln!(f, "connected_clients:{}", self.connected_client)?;
+        writeln!(f, "connected_clients:{}", self.connected_client)
+    }
+
+    fn persistence(&self, f: &mut String) -> std::fmt::Result {
+        separate(f)?;
+        writeln!(f, "# Persistence")?;
+        writeln!(f, "rdb_changes_since_last_save:{}", self.dirty)?;
+        writeln!(
+            f,
+            "rdb_bgsave_in_progress:{}",
+            self.bgsave_in_progress as u8
+        )?;
+        writeln!(f, "rdb_last_save_time:{}", self.last_save)?;
+        writeln!(f, "aof_enabled:{}", self.appendonly as u8)?;
+        writeln!(
+            f,
+            "aof_rewrite_in_progress:{}",
+            self.aof_rewrite_in_progress as u8
+        )
+    }
+}
+
+impl Store {
+    /// Renders the INFO sections asked for, every section when none is given
+    pub fn info(&self, sections: &[String]) -> Result<String, std::fmt::Error> {
+        let wanted = |name: &str| {
+            sections.is_empty()
+                || sections.iter().any(|v| {
+                    let v = v.to_lowercase();
+                    v == name || v == "all" || v == "default" || v == "everything"
+                })
+        };
+        let mut res = String::new();
+        let f = &mut res;
+        if wanted("clients") {
+            self.info.clients(f)?;
+        }
+        if wanted("persistence") {
+            self.info.persistence(f)?;
+        }
+        if wanted("replication") {
+            self.replication(f)?;
+        }
+        if wanted("cluster") {
+            separate(f)?;
+            writeln!(f, "# Cluster")?;
+            writeln!(f, "cluster_enabled:{}", self.cluster.is_some() as u8)?;
+        }
+        Ok(res)
+    }
+
+    fn replication(&self, f: &mut String) -> std::fmt::Result {
+        let info = &self.info;
+        separate(f)?;
         writeln!(f, "# Replication")?;
-        writeln!(f, "role:{}", self.role)?;
-        writeln!(f, "master_replid:{}", self.master_id)?;
-        writeln!(f, "master_repl_offset:{}", self.recv_offset)?;
+        writeln!(f, "role:{}", info.role)?;
+        if info.role == Role::Slave {
+            writeln!(f, "master_host:{}", info.master_host)?;
+            writeln!(f, "master_port:{}", info.master_port)?;
+            let up = info.repl_state == ReplState::Connected;
+            writeln!(f, "master_link_status:{}", if up { "up" } else { "down" })?;
+            let last_io = if up {
+                unix_time().saturating_sub(info.master_last_io) as i64
+            } else {
+                -1
+            };
+            writeln!(f, "master_last_io_seconds_ago:{last_io}")?;
+            let syncing = info.repl_state == ReplState::Transfer;
+            writeln!(f, "master_sync_in_progress:{}", syncing as u8)?;
+            writeln!(f, "slave_read_repl_offset:{}", info.recv_offset)?;
+            writeln!(f, "slave_repl_offset:{}", info.recv_offset)?;
+            if !up {
+                let down = unix_time().saturating_sub(info.master_link_down_since);
+                writeln!(f, "master_link_down_since_seconds:{down}")?;
+            }
+            writeln!(f, "slave_read_only:{}", info.replica_read_only as u8)?;
+        }
+        writeln!(f, "connected_slaves:{}", self.slave_offsets.len())?;
+        if info.min_replicas_to_write > 0 {
+            writeln!(f, "min_slaves_good_slaves:{}", self.good_replicas())?;
+        }
+        for (idx, slave) in self.slave_offsets.values().enumerate() {
+            writeln!(
+                f,
+                "slave{idx}:ip={},port={},state=online,offset={},lag={}",
+                slave.ip,
+                slave.port,
+                slave.offset,
+                slave.ack_time.elapsed().as_secs()
+            )?;
+        }
+        writeln!(f, "master_failover_state:{}", info.failover_state)?;
+        writeln!(f, "master_replid:{}", info.master_id)?;
+        writeln!(f, "master_replid2:{}", info.master_replid2)?;
+        writeln!(f, "master_repl_offset:{}", info.repl_offset())?;
+        writeln!(f, "second_repl_offset:{}", info.second_repl_offset)?;
+        let backlog = self.backlog.as_ref();
+        writeln!(f, "repl_backlog_active:{}", backlog.is_some() as u8)?;
+        writeln!(f, "repl_backlog_size:{}", info.repl_backlog_size)?;
+        let first_byte = backlog.map_or(0, |v| v.start_offset() + 1);
+        writeln!(f, "repl_backlog_first_byte_offset:{first_byte}")?;
+        let histlen = backlog.map_or(0, |v| v.histlen());
+        writeln!(f, "repl_backlog_histlen:{histlen}")?;
         Ok(())
     }
 }
 
+/// Sections are separated by an empty line
+fn separate(f: &mut String) -> std::fmt::Result {
+    if !f.is_empty() {
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
 impl Display for Role {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -41,3 +220,25 @@ impl Display for Role {
         }
     }
 }
+
+impl Display for FailoverState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailoverState::NoFailover => write!(f, "no-failover"),
+            FailoverState::WaitingForSync => write!(f, "waiting-for-sync"),
+            FailoverState::InProgress => write!(f, "failover-in-progress"),
+        }
+    }
+}
+
+impl Display for ReplState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplState::Connect => write!(f, "connect"),
+            ReplState::Connecting => write!(f, "connecting"),
+            ReplState::Handshake => write!(f, "handshake"),
+            ReplState::Transfer => write!(f, "sync"),
+            ReplState::Connected => write!(f, "connected"),
+        }
+    }
+}
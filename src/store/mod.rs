@@ -1,45 +1,79 @@
+mod database;
 mod info;
+mod replication;
 mod stream;
 mod value;
 
+use crate::aof::{Aof, FsyncPolicy};
+use crate::bytestring::ByteString;
+use crate::cluster::Cluster;
 use crate::frame::Frame;
+use crate::sentinel::Sentinel;
 use ordered_float::OrderedFloat;
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
-use tokio::sync::broadcast;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::{Notify, broadcast};
+use tokio::task::AbortHandle;
 
+pub use replication::{Backlog, Slave};
+
+#[derive(Default)]
 pub struct Store {
     pub info: Info,
-    pub broadcast: Option<broadcast::Sender<Frame>>,
-    pub get_ack_channel: Option<broadcast::Sender<Frame>>,
-    pub kv: HashMap<String, Value>,
+    /// the replication stream sent to the replicas, each frame along with the
+    /// offset right after it
+    pub broadcast: Option<broadcast::Sender<(usize, Frame)>>,
+    pub databases: Vec<Database>,
     pub users: HashMap<String, HashMap<String, Vec<String>>>,
-    pub channels: HashMap<String, broadcast::Sender<Frame>>,
-    pub slave_offsets: HashMap<usize, usize>,
-    pub slave_asked_offsets: HashMap<usize, usize>,
-    pub expiry_queue: BTreeMap<std::time::SystemTime, String>,
-    pub expiry_time: HashMap<String, std::time::SystemTime>,
+    pub channels: HashMap<ByteString, broadcast::Sender<Frame>>,
+    pub slave_offsets: HashMap<usize, Slave>,
+    /// notified every time one of the replicas acknowledges its offset
+    pub slave_acks: Arc<Notify>,
+    pub failover: Option<AbortHandle>,
+    /// notified when a failover ends, writes are paused until then
+    pub failover_done: Arc<Notify>,
+    pub replication_db: Option<usize>,
+    pub backlog: Option<Backlog>,
+    pub master_link: Option<AbortHandle>,
+    pub aof: Option<Aof>,
+    /// present when running in sentinel mode
+    pub sentinel: Option<Sentinel>,
+    /// present when running in cluster mode
+    pub cluster: Option<Cluster>,
+}
+
+/// A logical database selected with SELECT
+#[derive(Default)]
+pub struct Database {
+    pub kv: HashMap<ByteString, Value>,
+    /// the keys with an expiry time ordered by it, keys often share the same time
+    pub expiry_queue: BTreeSet<(std::time::SystemTime, ByteString)>,
+    pub expiry_time: HashMap<ByteString, std::time::SystemTime>,
+    /// the keys by the cluster hash slot they belong to, only built once a
+    /// command asks for the keys of a slot
+    pub slots: Option<HashMap<u16, HashSet<ByteString>>>,
 }
 
 pub enum Value {
-    String(String),
+    String(ByteString),
     List(VecDeque<Frame>),
-    Set(HashSet<Frame>),
+    Set(HashSet<ByteString>),
     ZSet(ZSet),
-    Hash,
+    Hash(HashMap<ByteString, ByteString>),
     Stream(Vec<StreamEntry>),
     VectorSet,
 }
 
 #[derive(Default)]
 pub struct ZSet {
-    pub scores: HashMap<String, OrderedFloat<f64>>,
-    pub ordered: BTreeSet<(OrderedFloat<f64>, String)>,
+    pub scores: HashMap<ByteString, OrderedFloat<f64>>,
+    pub ordered: BTreeSet<(OrderedFloat<f64>, ByteString)>,
 }
 
 #[derive(Clone)]
 pub struct StreamEntry {
     pub id: StreamEntryID,
-    pub data: HashMap<String, String>,
+    pub data: HashMap<ByteString, ByteString>,
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -52,12 +86,35 @@ pub struct StreamEntryID {
 pub struct Info {
     pub role: Role,
     pub master_id: String,
+    /// the replication id we had before the last change of master, valid up
+    /// to `second_repl_offset` so that replicas of the old master can continue
+    pub master_replid2: String,
+    pub second_repl_offset: isize,
     pub send_offset: usize,
     pub recv_offset: usize,
     pub connected_client: usize,
     pub listening_port: u16,
     pub dir: String,
     pub db_filename: String,
+    pub databases: usize,
+    pub save_params: Vec<(u64, usize)>,
+    pub dirty: usize,
+    pub last_save: u64,
+    pub bgsave_in_progress: bool,
+    pub appendonly: bool,
+    pub append_filename: String,
+    pub appendfsync: FsyncPolicy,
+    pub aof_rewrite_in_progress: bool,
+    pub repl_backlog_size: usize,
+    pub master_host: String,
+    pub master_port: u16,
+    pub repl_state: ReplState,
+    pub master_link_down_since: u64,
+    pub master_last_io: u64,
+    pub failover_state: FailoverState,
+    pub replica_read_only: bool,
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: u64,
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -66,6 +123,33 @@ pub enum Role {
     Slave,
 }
 
+/// State of a coordinated failover started with FAILOVER on a master
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+pub enum FailoverState {
+    #[default]
+    NoFailover,
+    /// writes are paused until the target replica catches up
+    WaitingForSync,
+    /// the target replica is being asked to take over
+    InProgress,
+}
+
+/// State of the link with the master on a replica
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+pub enum ReplState {
+    /// must connect to the master
+    #[default]
+    Connect,
+    /// connecting to the master
+    Connecting,
+    /// exchanging PING, REPLCONF and PSYNC
+    Handshake,
+    /// receiving the snapshot of the dataset
+    Transfer,
+    /// receiving the stream of commands
+    Connected,
+}
+
 impl Default for Role {
     fn default() -> Self {
         Role::Master
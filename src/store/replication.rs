@@ -0,0 +1,281 @@
+use super::{FailoverState, ReplState, Role, Store};
+use crate::frame::{Frame, encode::AsBytes};
+use bytes::{Buf, Bytes, BytesMut};
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use std::mem;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// A buffer holding the tail of the replication stream, replicas that reconnect
+/// with an offset that is still inside of it can continue from there without
+/// having to load the whole dataset again.
+pub struct Backlog {
+    buffer: BytesMut,
+    size: usize,
+    /// replication offset right after the last byte in the buffer
+    end_offset: usize,
+}
+
+impl Backlog {
+    pub fn new(size: usize, end_offset: usize) -> Self {
+        Backlog {
+            buffer: BytesMut::with_capacity(size),
+            size,
+            end_offset,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+        self.end_offset += data.len();
+        let overflow = self.buffer.len().saturating_sub(self.size);
+        self.buffer.advance(overflow);
+    }
+
+    pub fn start_offset(&self) -> usize {
+        self.end_offset - self.buffer.len()
+    }
+
+    /// Number of bytes of the stream currently held
+    pub fn histlen(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Everything written to the stream after `offset`, if the backlog still holds it
+    pub fn since(&self, offset: usize) -> Option<Bytes> {
+        let skip = offset.checked_sub(self.start_offset())?;
+        let data = self.buffer.get(skip..)?;
+        Some(Bytes::copy_from_slice(data))
+    }
+}
+
+/// One of our replicas along with the last offset it acknowledged
+pub struct Slave {
+    pub ip: String,
+    pub port: u16,
+    pub offset: usize,
+    pub ack_time: Instant,
+}
+
+impl Slave {
+    pub fn new(ip: String, port: u16, offset: usize) -> Self {
+        Slave {
+            ip,
+            port,
+            offset,
+            ack_time: Instant::now(),
+        }
+    }
+}
+
+impl Store {
+    /// Records the offset acknowledged by a replica and wakes up the clients in WAIT
+    pub fn acknowledge(&mut self, slave_id: usize, offset: usize) {
+        if let Some(slave) = self.slave_offsets.get_mut(&slave_id) {
+            slave.offset = offset;
+            slave.ack_time = Instant::now();
+        }
+        self.slave_acks.notify_waiters();
+    }
+
+    /// Number of replicas that have acknowledged every byte up to `offset`
+    pub fn replicas_at(&self, offset: usize) -> usize {
+        self.slave_offsets
+            .values()
+            .filter(|v| v.offset >= offset)
+            .count()
+    }
+
+    /// Number of replicas whose last acknowledgement is
+    /// no older than `min-replicas-max-lag` seconds
+    pub fn good_replicas(&self) -> usize {
+        let max_lag = Duration::from_secs(self.info.min_replicas_max_lag);
+        self.slave_offsets
+            .values()
+            .filter(|v| v.ack_time.elapsed() <= max_lag)
+            .count()
+    }
+
+    /// Turns this node into a master with a new replication id,
+    /// the offset carries on from where the replication stream was
+    pub fn promote(&mut self) {
+        if let Some(link) = self.master_link.take() {
+            link.abort();
+        }
+        if self.info.role == Role::Slave {
+            self.info.send_offset = self.info.recv_offset;
+        }
+        if self.info.role == Role::Slave && self.info.master_id != "?" {
+            // the replicas of our old master can continue with us
+            self.shift_replid(new_replid());
+        } else {
+            self.info.master_id = new_replid();
+        }
+        self.info.role = Role::Master;
+        self.info.master_host.clear();
+        self.info.master_port = 0;
+        let (size, offset) = (self.info.repl_backlog_size, self.info.send_offset);
+        self.backlog
+            .get_or_insert_with(|| Backlog::new(size, offset));
+        self.replication_db = None;
+        // our replicas have to reconnect to learn the new replication id
+        self.reset_replicas();
+    }
+
+    /// Turns this node into a replica of `host:port`, the link itself has to be
+    /// started by the caller. Our own replicas are disconnected, they reconnect
+    /// and follow the stream of the new master through us.
+    pub fn demote(&mut self, host: String, port: u16) {
+        if let Some(link) = self.master_link.take() {
+            link.abort();
+        }
+        if self.info.role == Role::Master {
+            // the new master may be able to continue from our own history
+            self.info.recv_offset = self.info.send_offset;
+        }
+        self.info.role = Role::Slave;
+        self.info.master_host = host;
+        self.info.master_port = port;
+        self.info.repl_state = ReplState::Connect;
+        let (size, offset) = (self.info.repl_backlog_size, self.info.recv_offset);
+        self.backlog
+            .get_or_insert_with(|| Backlog::new(size, offset));
+        self.reset_replicas();
+    }
+
+    /// Switches to a new replication id, the old one stays valid up to the current
+    /// offset so that the replicas that followed it can still continue from there
+    pub fn shift_replid(&mut self, replid: String) {
+        self.info.master_replid2 = mem::replace(&mut self.info.master_id, replid);
+        self.info.second_repl_offset = self.info.repl_offset() as isize + 1;
+    }
+
+    /// Forgets the previous replication id, the history before it is gone
+    pub fn clear_replid2(&mut self) {
+        self.info.master_replid2 = "0".repeat(40);
+        self.info.second_repl_offset = -1;
+    }
+
+    /// Whether a replica that processed the stream of `replid` up
+    /// to `offset` shares the history of the stream we send
+    pub fn same_history(&self, replid: &str, offset: usize) -> bool {
+        replid == self.info.master_id
+            || (replid == self.info.master_replid2
+                && (offset as isize) < self.info.second_repl_offset)
+    }
+
+    /// Ends the failover going on, the clients waiting to write are resumed
+    pub fn end_failover(&mut self) {
+        self.failover = None;
+        self.info.failover_state = FailoverState::NoFailover;
+        self.failover_done.notify_waiters();
+    }
+
+    /// Stops the failover going on, if the target was already
+    /// being asked to take over we become the master again
+    pub fn abort_failover(&mut self) {
+        if let Some(failover) = self.failover.take() {
+            failover.abort();
+        }
+        if self.info.failover_state == FailoverState::InProgress {
+            self.promote();
+        }
+        self.end_failover();
+    }
+
+    /// Disconnects every replica, dropping the broadcast channel closes their connections
+    pub fn reset_replicas(&mut self) {
+        let (tx, _rx) = broadcast::channel(64);
+        self.broadcast = Some(tx);
+        self.slave_offsets.clear();
+    }
+
+    /// Sends a command executed against the database `db` to the replicas,
+    /// a SELECT is sent first whenever the database differs from the last command
+    pub fn propagate(&mut self, db: usize, command: Frame) {
+        if self.replication_db != Some(db) {
+            let select: Frame = vec!["SELECT".to_string(), db.to_string()].into();
+            self.feed_replicas(select);
+            self.replication_db = Some(db);
+        }
+        self.feed_replicas(command);
+    }
+
+    /// Appends a frame to the replication stream, advancing the master offset
+    pub fn feed_replicas(&mut self, frame: Frame) {
+        let mut b = BytesMut::new();
+        frame.encode_bytes(&mut b);
+        if let Some(backlog) = self.backlog.as_mut() {
+            backlog.feed(&b);
+        }
+        self.info.send_offset += b.len();
+        if let Some(broadcast) = self.broadcast.as_ref() {
+            let _ = broadcast.send((self.info.send_offset, frame));
+        }
+    }
+}
+
+/// Generates a random 40 characters long replication id
+fn new_replid() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect::<String>()
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn since_boundaries() {
+        let mut backlog = Backlog::new(10, 100);
+        assert_eq!(backlog.since(100).unwrap(), "");
+        assert!(backlog.since(99).is_none());
+        assert!(backlog.since(101).is_none());
+
+        backlog.feed(b"abcdef");
+        assert_eq!(backlog.start_offset(), 100);
+        // exact start, inside, at the end and past it
+        assert_eq!(backlog.since(100).unwrap(), "abcdef");
+        assert_eq!(backlog.since(103).unwrap(), "def");
+        assert_eq!(backlog.since(106).unwrap(), "");
+        assert!(backlog.since(107).is_none());
+        // before the start
+        assert!(backlog.since(99).is_none());
+        assert!(backlog.since(0).is_none());
+    }
+
+    #[test]
+    fn since_after_overflow() {
+        let mut backlog = Backlog::new(4, 0);
+        backlog.feed(b"abc");
+        backlog.feed(b"def");
+        assert_eq!((backlog.start_offset(), backlog.histlen()), (2, 4));
+        assert!(backlog.since(1).is_none());
+        assert_eq!(backlog.since(2).unwrap(), "cdef");
+        for _ in 0..100 {
+            backlog.feed(b"0123456789");
+        }
+        assert_eq!(backlog.histlen(), 4);
+        assert_eq!(backlog.since(1002).unwrap(), "6789");
+        assert!(backlog.since(1001).is_none());
+        assert!(backlog.since(usize::MAX).is_none());
+    }
+
+    #[test]
+    fn history_of_the_previous_master() {
+        let mut store = Store::default();
+        store.info.master_id = "a".repeat(40);
+        store.info.send_offset = 100;
+        store.shift_replid("b".repeat(40));
+        assert!(store.same_history(&"b".repeat(40), 500));
+        // the replicas of the old master may have processed up to where we left it
+        assert!(store.same_history(&"a".repeat(40), 100));
+        assert!(!store.same_history(&"a".repeat(40), 101));
+        assert!(!store.same_history(&"c".repeat(40), 0));
+    }
+}
@@ -1,6 +1,7 @@
 use super::{StreamEntry, Value, ZSet};
+use crate::bytestring::ByteString;
 use crate::frame::Frame;
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 macro_rules! impl_getter {
     ($variant:ident, $tp:ty, $name:ident, $name_mut:ident) => {
@@ -29,16 +30,17 @@ impl Value {
             Value::List(_) => "list",
             Value::Set(_) => "set",
             Value::ZSet(_) => "zset",
-            Value::Hash => "hash",
+            Value::Hash(_) => "hash",
             Value::Stream(_) => "stream",
             Value::VectorSet => "vectorset",
         }
         .into()
     }
 
-    impl_getter!(String, String, string, string_mut);
+    impl_getter!(String, ByteString, string, string_mut);
     impl_getter!(List, VecDeque<Frame>, list, list_mut);
-    impl_getter!(Set, HashSet<Frame>, set, set_mut);
+    impl_getter!(Set, HashSet<ByteString>, set, set_mut);
+    impl_getter!(Hash, HashMap<ByteString, ByteString>, hash, hash_mut);
     impl_getter!(ZSet, ZSet, zset, zset_mut);
     impl_getter!(Stream, Vec<StreamEntry>, stream, stream_mut);
 }
@@ -53,8 +55,9 @@ macro_rules! impl_into_value {
     };
 }
 
-impl_into_value!(String => String);
+impl_into_value!(ByteString => String);
 impl_into_value!(VecDeque<Frame> => List);
-impl_into_value!(HashSet<Frame> => Set);
+impl_into_value!(HashSet<ByteString> => Set);
+impl_into_value!(HashMap<ByteString, ByteString> => Hash);
 impl_into_value!(ZSet => ZSet);
 impl_into_value!(Vec<StreamEntry> => Stream);